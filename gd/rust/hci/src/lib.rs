@@ -20,7 +20,7 @@ use bt_packets::hci::EventChild::{
 };
 use bt_packets::hci::{
     CommandExpectations, CommandPacket, ErrorCode, EventCode, EventPacket, LeMetaEventPacket,
-    ResetBuilder, SubeventCode,
+    OpCode, ResetBuilder, SubeventCode,
 };
 use error::Result;
 use gddi::{module, part_out, provides, Stoppable};
@@ -56,6 +56,7 @@ struct Hci {
 #[provides]
 async fn provide_hci(control: ControlHal, rt: Arc<Runtime>) -> Hci {
     let (cmd_tx, cmd_rx) = channel::<QueuedCommand>(10);
+    let (unacked_tx, unacked_rx) = channel::<CommandPacket>(10);
     let evt_handlers = Arc::new(Mutex::new(HashMap::new()));
     let le_evt_handlers = Arc::new(Mutex::new(HashMap::new()));
 
@@ -65,9 +66,10 @@ async fn provide_hci(control: ControlHal, rt: Arc<Runtime>) -> Hci {
         control.rx,
         control.tx,
         cmd_rx,
+        unacked_rx,
     ));
 
-    let raw_commands = RawCommandSender { cmd_tx };
+    let raw_commands = RawCommandSender { cmd_tx, unacked_tx };
     let mut commands = CommandSender { raw: raw_commands.clone() };
 
     assert!(
@@ -88,6 +90,7 @@ struct QueuedCommand {
 #[derive(Clone, Stoppable)]
 pub struct RawCommandSender {
     cmd_tx: Sender<QueuedCommand>,
+    unacked_tx: Sender<CommandPacket>,
 }
 
 impl RawCommandSender {
@@ -116,6 +119,14 @@ impl CommandSender {
     ) -> T::ResponseType {
         T::_to_response_type(self.raw.send(cmd.into()).await.unwrap())
     }
+
+    /// Send a command the controller does not respond to, like Host Number Of Completed
+    /// Packets. It goes out right away, even while another command waits on its response. The
+    /// controller only answers Host Number Of Completed Packets if it finds it invalid, and
+    /// that answer is logged and dropped.
+    pub async fn send_unacknowledged<T: Into<CommandPacket>>(&mut self, cmd: T) {
+        self.raw.unacked_tx.send(cmd.into()).await.unwrap();
+    }
 }
 
 /// Provides ability to register and unregister for HCI events
@@ -171,6 +182,7 @@ async fn dispatch(
     evt_rx: Arc<Mutex<Receiver<EventPacket>>>,
     cmd_tx: Sender<CommandPacket>,
     mut cmd_rx: Receiver<QueuedCommand>,
+    mut unacked_rx: Receiver<CommandPacket>,
 ) {
     let mut pending: Option<QueuedCommand> = None;
     let mut hci_timeout = Alarm::new();
@@ -191,6 +203,10 @@ async fn dispatch(
                             None => panic!("Unexpected status event with opcode {:?}", this_opcode),
                         }
                     },
+                    // Only sent when the report was invalid, and not for the command waiting
+                    CommandComplete(evt) if evt.get_command_op_code() == OpCode::HostNumCompletedPackets => {
+                        error!("controller refused host number of completed packets");
+                    },
                     CommandComplete(evt) => {
                         hci_timeout.cancel();
                         let this_opcode = evt.get_command_op_code();
@@ -239,6 +255,11 @@ async fn dispatch(
                 hci_timeout.reset(Duration::from_secs(2));
                 pending = Some(queued);
             },
+            Some(cmd) = unacked_rx.recv() => {
+                if let Err(e) = cmd_tx.send(cmd).await {
+                    error!("command queue closed: {:?}", e);
+                }
+            },
             _ = hci_timeout.expired() => panic!("Timed out waiting for {:?}", pending.unwrap().cmd.get_op_code()),
             else => break,
         }
@@ -0,0 +1,46 @@
+//! Accounting of the bandwidth promised to guaranteed service QoS streams, so no more are
+//! accepted than the radio can schedule
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Token rates reserved per connection, in octets per second, checked against a limit shared
+/// by all connections
+#[derive(Clone)]
+pub(super) struct BandwidthBudget {
+    limit: Option<u32>,
+    reserved: Arc<Mutex<HashMap<u16, u32>>>,
+}
+
+impl BandwidthBudget {
+    /// A budget of `limit` octets per second, or an unlimited one that only keeps count
+    pub fn new(limit: Option<u32>) -> Self {
+        Self { limit, reserved: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Reserve `rate` for `handle`, replacing what it held. If that would oversubscribe the
+    /// radio, the previous reservation is kept, and the most that could be reserved instead
+    /// is returned so the caller can downgrade the request.
+    pub fn reserve(&self, handle: u16, rate: u32) -> Result<(), u32> {
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some(limit) = self.limit {
+            let others: u32 = reserved.iter().filter(|(h, _)| **h != handle).map(|(_, r)| r).sum();
+            let available = limit.saturating_sub(others);
+            if rate > available {
+                return Err(available);
+            }
+        }
+        reserved.insert(handle, rate);
+        Ok(())
+    }
+
+    /// Give back whatever `handle` reserved, once its stream ends or the link goes down
+    pub fn release(&self, handle: u16) {
+        self.reserved.lock().unwrap().remove(&handle);
+    }
+
+    /// Total reserved across all connections
+    pub fn reserved(&self) -> u32 {
+        self.reserved.lock().unwrap().values().sum()
+    }
+}
@@ -0,0 +1,136 @@
+//! Rate limiting of state-like connection events, for consumers that can't keep up with them
+
+use super::metrics::{send_tracked, Counters};
+use super::{ConnectionEvent, ConnectionEventMask, ParkedChannels};
+use bt_common::time::Alarm;
+use log::debug;
+use std::collections::HashMap;
+use std::mem::{discriminant, Discriminant};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+
+type Kind = Discriminant<ConnectionEvent>;
+
+/// Sends a connection's events, delivering at most one coalescable event of each kind per
+/// interval. Events of a kind that arrive within the interval are held, each replacing the
+/// last, and the one left is delivered once the interval is up. Delivered events also go to
+/// the subscribers that selected their kind. While the connection's channels are parked,
+/// events that don't fit are dropped rather than waited on.
+pub(super) struct EventSender {
+    tx: Sender<ConnectionEvent>,
+    parked: ParkedChannels,
+    subscribers: Vec<(ConnectionEventMask, Sender<ConnectionEvent>)>,
+    interval: Option<Duration>,
+    metrics: Arc<Counters>,
+    sent: HashMap<Kind, Instant>,
+    held: Vec<ConnectionEvent>,
+    flush: Alarm,
+}
+
+impl EventSender {
+    pub fn new(
+        tx: Sender<ConnectionEvent>,
+        parked: ParkedChannels,
+        interval: Option<Duration>,
+        metrics: Arc<Counters>,
+    ) -> Self {
+        Self {
+            tx,
+            parked,
+            subscribers: Vec::new(),
+            interval,
+            metrics,
+            sent: HashMap::new(),
+            held: Vec::new(),
+            flush: Alarm::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, mask: ConnectionEventMask, tx: Sender<ConnectionEvent>) {
+        // Gone before it was subscribed
+        if !tx.is_closed() {
+            self.subscribers.push((mask, tx));
+        }
+    }
+
+    pub async fn send(&mut self, event: ConnectionEvent) {
+        let interval = match self.interval {
+            Some(interval) if event.coalesces() => interval,
+            _ => {
+                // Held events go first, so consumers still see events in the order they happened
+                let held: Vec<_> = self.held.drain(..).collect();
+                for event in held {
+                    self.deliver(event).await;
+                }
+                self.flush.cancel();
+                return self.forward(event).await;
+            }
+        };
+        let kind = discriminant(&event);
+        match self.sent.get(&kind) {
+            Some(&at) if at.elapsed() < interval => {
+                self.held.retain(|e| discriminant(e) != kind);
+                self.held.push(event);
+                self.arm();
+            }
+            _ => self.deliver(event).await,
+        }
+    }
+
+    /// Completes when held events are due, to be delivered with `flush_due`
+    pub async fn due(&mut self) {
+        self.flush.expired().await;
+    }
+
+    pub async fn flush_due(&mut self) {
+        let interval = self.interval.unwrap_or_default();
+        let sent = &self.sent;
+        let (due, held) = self.held.drain(..).partition(|e| {
+            sent.get(&discriminant(e)).map(|at| at.elapsed() >= interval).unwrap_or(true)
+        });
+        self.held = held;
+        for event in due {
+            self.deliver(event).await;
+        }
+        self.arm();
+    }
+
+    async fn deliver(&mut self, event: ConnectionEvent) {
+        self.sent.insert(discriminant(&event), Instant::now());
+        self.forward(event).await;
+    }
+
+    async fn forward(&mut self, event: ConnectionEvent) {
+        let kind = ConnectionEventMask::of(&event);
+        self.subscribers.retain(|(mask, tx)| {
+            if mask.contains(kind) {
+                // One with no room misses the event, but stays subscribed
+                !matches!(tx.try_send(event.clone()), Err(TrySendError::Closed(_)))
+            } else {
+                !tx.is_closed()
+            }
+        });
+        tokio::select! {
+            biased;
+            _ = send_tracked(&self.tx, event, &self.metrics.connection_events) => {}
+            _ = self.parked.parked() => debug!("connection event dropped, nobody holds the connection"),
+        }
+    }
+
+    fn arm(&mut self) {
+        let interval = self.interval.unwrap_or_default();
+        let next = self
+            .held
+            .iter()
+            .filter_map(|e| self.sent.get(&discriminant(e)))
+            .map(|at| interval.saturating_sub(at.elapsed()))
+            .min();
+        match next {
+            // A zero duration would disarm the alarm instead
+            Some(wait) => self.flush.reset(wait.max(Duration::from_millis(1))),
+            None => self.flush.cancel(),
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Errors reported by the classic ACL manager
+
+use bt_hci::Address;
+use bt_packets::hci::ErrorCode;
+use bytes::Bytes;
+use thiserror::Error;
+
+/// Classic ACL manager errors
+#[derive(Error, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AclError {
+    /// The controller refused a command with the specified status
+    #[error("HCI command failed: {0:?}")]
+    Hci(ErrorCode),
+    /// The connection went down before the request completed
+    #[error("Disconnected")]
+    Disconnected,
+    /// The manager stopped before the request completed
+    #[error("Channel closed")]
+    ChannelClosed,
+    /// There is no connection to the address
+    #[error("Not connected")]
+    NotConnected,
+    /// The address is of a type BR/EDR can't connect to, like an LE random address
+    #[error("Invalid address type")]
+    InvalidAddressType,
+    /// The request didn't complete within the time it was given
+    #[error("Timed out")]
+    TimedOut,
+}
+
+/// Why `AclManager::connect_any` didn't connect to any of its addresses
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum ConnectAnyError {
+    /// The addresses tried, in order, each with what its connect failed with. Trying stops
+    /// early if the manager does.
+    #[error("No address connected: {0:?}")]
+    Failed(Vec<(Address, AclError)>),
+    /// The connect being paged was cancelled, so the addresses after it weren't tried
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+/// Why `Connection::try_send` couldn't queue data, which is handed back
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum SendError {
+    /// The connection's queue for the traffic class is full
+    #[error("Send queue full")]
+    Full(Bytes),
+    /// The connection went down
+    #[error("Disconnected")]
+    Disconnected(Bytes),
+}
@@ -0,0 +1,57 @@
+//! Event codes registered the first time a feature needs them, rather than at startup
+
+use bt_hci::EventRegistry;
+use bt_packets::hci::{EventCode, EventPacket};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Mutex;
+
+/// Registers event codes on first use, all delivered to the same channel. Only suits events
+/// the controller sends in response to a command, since an event arriving unregistered is
+/// fatal to the HCI layer.
+#[derive(Clone)]
+pub(super) struct LazyEvents {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    events: EventRegistry,
+    evt_tx: Sender<EventPacket>,
+    registered: HashSet<EventCode>,
+    closed: bool,
+}
+
+impl LazyEvents {
+    pub fn new(events: EventRegistry, evt_tx: Sender<EventPacket>) -> Self {
+        let inner = Inner { events, evt_tx, registered: HashSet::new(), closed: false };
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Register `code`, unless it already is or the manager is shutting down
+    pub async fn ensure(&self, code: EventCode) {
+        let mut inner = self.inner.lock().await;
+        if !inner.closed && inner.registered.insert(code) {
+            let evt_tx = inner.evt_tx.clone();
+            inner.events.register(code, evt_tx).await;
+        }
+    }
+
+    /// Stop registering new codes, for shutdown
+    pub async fn close(&self) {
+        self.inner.lock().await.closed = true;
+    }
+
+    /// Once closed, unregister everything registered so far. `idle` says no connection is left
+    /// that could still be waiting on one of the events.
+    pub async fn release(&self, idle: bool) {
+        let mut inner = self.inner.lock().await;
+        if !inner.closed || !idle {
+            return;
+        }
+        let registered: Vec<EventCode> = inner.registered.drain().collect();
+        for code in registered {
+            inner.events.unregister(code).await;
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Backpressure accounting for the classic ACL manager's internal channels
+
+use log::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::Sender;
+
+/// Backpressure seen on one kind of channel
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChannelMetrics {
+    /// Number of sends that found the channel full
+    pub full: u64,
+    /// Total time sends spent waiting for room
+    pub blocked: Duration,
+}
+
+/// Snapshot of the classic ACL manager's metrics
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AclMetrics {
+    /// Requests sent to the manager by `AclManager` handles
+    pub requests: ChannelMetrics,
+    /// Events sent to the `AclManager` event receiver
+    pub manager_events: ChannelMetrics,
+    /// Events sent to individual connections
+    pub connection_events: ChannelMetrics,
+    /// HCI events handed to connection tasks
+    pub hci_events: ChannelMetrics,
+}
+
+#[derive(Default)]
+pub(super) struct ChannelCounters {
+    full: AtomicU64,
+    blocked_us: AtomicU64,
+}
+
+impl ChannelCounters {
+    fn snapshot(&self) -> ChannelMetrics {
+        ChannelMetrics {
+            full: self.full.load(Ordering::Relaxed),
+            blocked: Duration::from_micros(self.blocked_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct Counters {
+    pub requests: ChannelCounters,
+    pub manager_events: ChannelCounters,
+    pub connection_events: ChannelCounters,
+    pub hci_events: ChannelCounters,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> AclMetrics {
+        AclMetrics {
+            requests: self.requests.snapshot(),
+            manager_events: self.manager_events.snapshot(),
+            connection_events: self.connection_events.snapshot(),
+            hci_events: self.hci_events.snapshot(),
+        }
+    }
+}
+
+/// Data that went over a connection since it came up, from `Connection::stats`. Packets are
+/// whole L2CAP PDUs, however many ACL packets they were split into.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TrafficStats {
+    /// Bytes handed to the controller
+    pub tx_bytes: u64,
+    /// Bytes received
+    pub rx_bytes: u64,
+    /// PDUs handed to the controller
+    pub tx_packets: u64,
+    /// PDUs received
+    pub rx_packets: u64,
+    /// PDUs received but dropped, because the reader fell behind under
+    /// `InboundOverflow::DropNewest`. Also counted in `rx_bytes` and `rx_packets`.
+    pub rx_dropped: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct TrafficCounters {
+    tx_bytes: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    rx_packets: AtomicU64,
+    rx_dropped: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub fn sent(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, len: usize) {
+        self.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Send on `tx`, recording in `counters` when the channel is full and how long it takes to
+/// make room. A receiver that is gone only means nobody wants the value any more, so it is
+/// dropped.
+pub(super) async fn send_tracked<T>(tx: &Sender<T>, value: T, counters: &ChannelCounters) {
+    if send_counted(tx, value, counters).await.is_err() {
+        debug!("receiver was dropped before the value was sent");
+    }
+}
+
+/// Like `send_tracked`, but hands the value back if the receiver is gone
+pub(super) async fn send_counted<T>(
+    tx: &Sender<T>,
+    value: T,
+    counters: &ChannelCounters,
+) -> Result<(), SendError<T>> {
+    match tx.try_send(value) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(value)) => {
+            counters.full.fetch_add(1, Ordering::Relaxed);
+            let start = Instant::now();
+            tx.send(value).await?;
+            let blocked = start.elapsed().as_micros() as u64;
+            counters.blocked_us.fetch_add(blocked, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(TrySendError::Closed(value)) => Err(SendError(value)),
+    }
+}
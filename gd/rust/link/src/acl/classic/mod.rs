@@ -1,296 +1,5432 @@
 //! Classic ACL manager
 
+mod bandwidth;
+mod coalesce;
+mod error;
+mod lazy_events;
+mod metrics;
+mod retry;
+mod state;
+pub mod status;
+
+pub use error::{AclError, ConnectAnyError, SendError};
+pub use metrics::{AclMetrics, ChannelMetrics, TrafficStats};
+#[cfg(feature = "fuzz")]
+pub use state::{handle_event, AclState, Completion, EventOutcome};
+#[cfg(not(feature = "fuzz"))]
+use state::{handle_event, AclState, Completion, EventOutcome};
+pub use status::{DisconnectCause, FailureClass};
+
+use crate::acl::classic::bandwidth::BandwidthBudget;
+use crate::acl::classic::coalesce::EventSender;
+use crate::acl::classic::lazy_events::LazyEvents;
+use crate::acl::classic::metrics::{
+    send_counted, send_tracked, ChannelCounters, Counters, TrafficCounters,
+};
+use crate::acl::classic::retry::Retries;
+use crate::acl::classic::state::PendingConnect;
 use crate::acl::core;
+use bt_common::time::{interval, Alarm};
 use bt_common::Bluetooth;
+use bt_hci::controller::null_terminated_to_string;
 use bt_hci::{Address, CommandSender, EventRegistry};
 use bt_packets::hci::EventChild::{
-    AuthenticationComplete, ConnectionComplete, DisconnectionComplete,
+    AuthenticationComplete, ConnectionComplete, DisconnectionComplete, EncryptionChange,
+    HardwareError, IoCapabilityRequest, LinkKeyNotification, LinkKeyRequest, ModeChange,
+    QosSetupComplete, ReadClockOffsetComplete, ReadRemoteExtendedFeaturesComplete,
+    ReadRemoteSupportedFeaturesComplete, ReadRemoteVersionInformationComplete,
+    RemoteNameRequestComplete, RoleChange, SynchronousConnectionComplete, UserConfirmationRequest,
+    UserPasskeyRequest,
 };
 use bt_packets::hci::{
-    AcceptConnectionRequestBuilder, AcceptConnectionRequestRole, ClockOffsetValid,
-    CreateConnectionBuilder, CreateConnectionCancelBuilder, CreateConnectionRoleSwitch,
-    DisconnectBuilder, DisconnectReason, ErrorCode, EventChild, EventCode, EventPacket,
-    PageScanRepetitionMode, RejectConnectionReason, RejectConnectionRequestBuilder, Role,
+    AcceptConnectionRequestBuilder, AcceptConnectionRequestRole, AddressType,
+    AuthenticationRequestedBuilder, AuthenticationRequirements, ClockOffsetValid,
+    CommandExpectations, CommandPacket, CreateConnectionBuilder, CreateConnectionCancelBuilder,
+    CreateConnectionRoleSwitch, DisconnectBuilder, DisconnectReason, DisconnectionCompleteBuilder,
+    Enable, EncryptionEnabled, ErrorCode, EventChild, EventCode, EventPacket, ExitSniffModeBuilder,
+    FlushBuilder, HoldModeBuilder, IoCapability, IoCapabilityRequestReplyBuilder, KeyType,
+    LinkKeyRequestNegativeReplyBuilder, LinkKeyRequestReplyBuilder, LinkType, Mode, OobDataPresent,
+    OpCode, OpCodeIndex, PageScanRepetitionMode, PageScanType, QosSetupBuilder,
+    ReadAutomaticFlushTimeoutBuilder, ReadClockBuilder, ReadClockOffsetBuilder,
+    ReadConnectionAcceptTimeoutBuilder, ReadEncryptionKeySizeBuilder,
+    ReadFailedContactCounterBuilder, ReadLinkPolicySettingsBuilder, ReadLinkQualityBuilder,
+    ReadLinkSupervisionTimeoutBuilder, ReadLocalNameBuilder, ReadLocalSupportedCodecsV1Builder,
+    ReadLocalSupportedCommandsBuilder, ReadLocalSupportedFeaturesBuilder, ReadPageScanTypeBuilder,
+    ReadRemoteExtendedFeaturesBuilder, ReadRemoteSupportedFeaturesBuilder,
+    ReadRemoteVersionInformationBuilder, ReadRssiBuilder, ReadScanEnableBuilder,
+    ReadTransmitPowerLevelBuilder, RejectConnectionReason, RejectConnectionRequestBuilder,
+    RemoteNameRequestBuilder, ResetFailedContactCounterBuilder, Role, RoleDiscoveryBuilder,
+    ScanEnable, ScoAirMode, ScoLinkType, ServiceType, SetAfhHostChannelClassificationBuilder,
+    SetConnectionEncryptionBuilder, SetupSynchronousConnectionBuilder, SniffModeBuilder,
+    SwitchRoleBuilder, TransmitPowerLevelType, UserConfirmationRequestNegativeReplyBuilder,
+    UserConfirmationRequestReplyBuilder, UserPasskeyRequestNegativeReplyBuilder,
+    UserPasskeyRequestReplyBuilder, WhichClock, WriteAutomaticFlushTimeoutBuilder,
+    WriteConnectionAcceptTimeoutBuilder, WriteDefaultLinkPolicySettingsBuilder,
+    WriteLinkPolicySettingsBuilder, WriteLinkSupervisionTimeoutBuilder, WriteLocalNameBuilder,
+    WritePageScanTypeBuilder, WritePageTimeoutBuilder, WriteScanEnableBuilder,
 };
 use bytes::Bytes;
 use gddi::{module, provides, Stoppable};
-use log::warn;
-use std::collections::HashMap;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::BitOr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{oneshot, Mutex, Notify};
+use tokio::time::timeout;
+use tracing::{info_span, Instrument, Span};
 
 module! {
     classic_acl_module,
     providers {
         AclManager => provide_acl_manager,
+        AclConfig => provide_acl_config,
     },
 }
 
-/// Classic ACL manager
+/// Decides how to respond to an incoming connection request from the given address. It has
+/// until the controller's connection accept timeout, see
+/// `AclManager::write_connection_accept_timeout`; slow decisions are better deferred.
+pub type AcceptPolicy = Arc<dyn Fn(Address) -> AcceptDecision + Send + Sync>;
+
+/// Decides whether an outgoing connection to the given address may go ahead, or the reason it
+/// is refused. Consulted when the connect is issued, and again by `AclManager::revalidate_queue`.
+pub type ConnectPolicy = Arc<dyn Fn(Address) -> Result<(), ErrorCode> + Send + Sync>;
+
+/// Outcome of the accept policy for an incoming connection request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AcceptDecision {
+    /// Accept the connection, asking to become central
+    AcceptAsCentral,
+    /// Accept the connection, staying peripheral
+    AcceptAsPeripheral,
+    /// Accept the connection as peripheral, then switch to central if the remote features show
+    /// it supports role switch. The settled role is reported with `ConnectionEvent::RoleChanged`.
+    AcceptThenSwitchIfSupported,
+    /// Reject the connection with the specified reason
+    Reject(RejectConnectionReason),
+    /// Hold the request until `AclManager::resolve_incoming` is called, or the accept timeout
+    /// elapses, whichever comes first
+    Defer,
+}
+
+/// What to do with a connection request from a peer there already is a connection to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DuplicateIncoming {
+    /// Reject the request with the specified reason, keeping the existing link
+    Reject(RejectConnectionReason),
+    /// Take the existing link for dead, with its disconnect missed, so disconnect it and
+    /// handle the request like any other. The accept policy can still turn it away.
+    ReplaceExisting,
+}
+
+/// Which connects `Event::QueueActive` and `Event::QueueIdle` track
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QueueEvents {
+    /// Neither event is sent
+    Off,
+    /// Outgoing connects being paged, queued or waiting to retry
+    Outgoing,
+    /// Outgoing connects, and incoming connections being decided on or set up
+    OutgoingAndIncoming,
+}
+
+/// Security an accepted incoming connection must reach before it is kept
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SecurityLevel {
+    /// No requirement
+    None,
+    /// The link must be encrypted
+    Encrypted,
+    /// The link must be authenticated on this connection, and encrypted
+    Authenticated,
+}
+
+/// Decides the security level an accepted incoming connection from the given address must
+/// reach. Links that can't reach it are disconnected.
+pub type SecurityPolicy = Arc<dyn Fn(Address) -> SecurityLevel + Send + Sync>;
+
+/// How many items each of the manager's internal channels holds before senders wait on it.
+/// How often they had to is reported by `AclManager::metrics`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChannelCapacities {
+    /// Requests from `AclManager` handles to the manager
+    pub requests: usize,
+    /// Events to the `AclManager` event receiver
+    pub manager_events: usize,
+    /// HCI events the HCI layer hands the manager. While it is full, the HCI event dispatcher
+    /// waits, holding up events for every other module too. Those waits are made on the HCI
+    /// side, so they aren't counted.
+    pub hci_events: usize,
+    /// Requests from each `Connection` to its task
+    pub connection_requests: usize,
+    /// Events to each `Connection`'s receiver
+    pub connection_events: usize,
+}
+
+impl Default for ChannelCapacities {
+    fn default() -> Self {
+        Self {
+            requests: 10,
+            manager_events: 10,
+            hci_events: 3,
+            connection_requests: 10,
+            connection_events: 10,
+        }
+    }
+}
+
+/// Classic ACL manager config. Inject into the registry to override the defaults.
 #[derive(Clone, Stoppable)]
+pub struct AclConfig {
+    accept_policy: AcceptPolicy,
+    incoming_security_policy: SecurityPolicy,
+    next_connection_strategy: Arc<dyn NextConnectionStrategy>,
+    stats_sink: Arc<dyn ConnectionStatsSink>,
+    connect_policy: ConnectPolicy,
+    incoming_accept_timeout: Duration,
+    duplicate_incoming: DuplicateIncoming,
+    skip_role_switch_feature_check: bool,
+    event_coalescing: Option<Duration>,
+    link_risk: Option<LinkRiskThresholds>,
+    link_degradation: Option<LinkDegradationThresholds>,
+    recent_hci_events: usize,
+    require_encryption_after_auth: bool,
+    encryption_after_auth_timeout: Duration,
+    auto_secure_incoming: bool,
+    max_guaranteed_bandwidth: Option<u32>,
+    connect_timeout: Option<Duration>,
+    connection_limit: Option<ConnectionLimit>,
+    clock_offset_lifetime: Option<Duration>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    idle_timeout: Option<Duration>,
+    disconnect_timeout: Duration,
+    allow_no_supervision_timeout: bool,
+    allow_no_page_timeout: bool,
+    default_link_policy: Option<LinkPolicy>,
+    queue_events: QueueEvents,
+    channel_capacities: ChannelCapacities,
+    pairing_delegate: Arc<dyn PairingDelegate>,
+    key_store: Arc<dyn KeyStore>,
+}
+
+impl AclConfig {
+    /// Constructs a new config, accepting all incoming connections as central and allowing all
+    /// outgoing ones
+    pub fn new() -> Self {
+        Self {
+            accept_policy: Arc::new(|_| AcceptDecision::AcceptAsCentral),
+            incoming_security_policy: Arc::new(|_| SecurityLevel::None),
+            next_connection_strategy: Arc::new(Fifo),
+            stats_sink: Arc::new(NoStats),
+            connect_policy: Arc::new(|_| Ok(())),
+            // Below the controller's default connection accept timeout of 5 seconds
+            incoming_accept_timeout: Duration::from_secs(4),
+            duplicate_incoming: DuplicateIncoming::Reject(
+                RejectConnectionReason::UnacceptableBdAddr,
+            ),
+            skip_role_switch_feature_check: false,
+            event_coalescing: None,
+            link_risk: None,
+            link_degradation: None,
+            recent_hci_events: 0,
+            require_encryption_after_auth: false,
+            encryption_after_auth_timeout: Duration::from_secs(5),
+            auto_secure_incoming: false,
+            max_guaranteed_bandwidth: None,
+            connect_timeout: None,
+            connection_limit: None,
+            clock_offset_lifetime: None,
+            reconnect_policy: None,
+            idle_timeout: None,
+            // Past the default supervision timeout of 20 seconds, which a disconnect from a peer
+            // that stopped answering waits out
+            disconnect_timeout: Duration::from_secs(30),
+            allow_no_supervision_timeout: false,
+            allow_no_page_timeout: false,
+            default_link_policy: None,
+            queue_events: QueueEvents::Off,
+            channel_capacities: ChannelCapacities::default(),
+            pairing_delegate: Arc::new(JustWorks),
+            key_store: Arc::new(NoKeys),
+        }
+    }
+
+    /// Overwrites the policy applied to incoming connection requests. To accept every
+    /// connection in the peripheral role, return `AcceptDecision::AcceptAsPeripheral` for all.
+    pub fn set_accept_policy(&mut self, value: AcceptPolicy) {
+        self.accept_policy = value;
+    }
+
+    /// Overwrites the security level required of accepted incoming connections
+    pub fn set_incoming_security_policy(&mut self, value: SecurityPolicy) {
+        self.incoming_security_policy = value;
+    }
+
+    /// Overwrites how the next queued connect to page is chosen
+    pub fn set_next_connection_strategy(&mut self, value: Arc<dyn NextConnectionStrategy>) {
+        self.next_connection_strategy = value;
+    }
+
+    /// Overwrites where connections that came up, and the stats of those that went down, are
+    /// reported
+    pub fn set_stats_sink(&mut self, value: Arc<dyn ConnectionStatsSink>) {
+        self.stats_sink = value;
+    }
+
+    /// Overwrites what answers the controller's questions during secure simple pairing
+    pub fn set_pairing_delegate(&mut self, value: Arc<dyn PairingDelegate>) {
+        self.pairing_delegate = value;
+    }
+
+    /// Overwrites where link keys of bonded devices are looked up and new ones are kept
+    pub fn set_key_store(&mut self, value: Arc<dyn KeyStore>) {
+        self.key_store = value;
+    }
+
+    /// Overwrites the policy applied to outgoing connections
+    pub fn set_connect_policy(&mut self, value: ConnectPolicy) {
+        self.connect_policy = value;
+    }
+
+    /// Overwrites how long a deferred incoming connection waits to be resolved before it is
+    /// rejected
+    pub fn set_incoming_accept_timeout(&mut self, value: Duration) {
+        self.incoming_accept_timeout = value;
+    }
+
+    /// Overwrites what happens to a connection request from a peer that is already connected.
+    /// By default it is rejected with `UnacceptableBdAddr`.
+    pub fn set_duplicate_incoming(&mut self, value: DuplicateIncoming) {
+        self.duplicate_incoming = value;
+    }
+
+    /// Overwrites whether role switches are issued without first checking that the remote
+    /// supports them. Useful for peers known to misreport their features.
+    pub fn set_skip_role_switch_feature_check(&mut self, value: bool) {
+        self.skip_role_switch_feature_check = value;
+    }
+
+    /// Overwrites the shortest interval between two connection events of a kind that only
+    /// reports the latest state, such as `RoleChanged`. Events in between are dropped, the last
+    /// one is delivered when the interval is up. `None` delivers every event.
+    pub fn set_event_coalescing(&mut self, value: Option<Duration>) {
+        self.event_coalescing = value;
+    }
+
+    /// Overwrites when connections report `LinkAtRisk`. `None`, the default, doesn't watch link
+    /// quality at all.
+    pub fn set_link_risk(&mut self, value: Option<LinkRiskThresholds>) {
+        self.link_risk = value;
+    }
+
+    /// Overwrites when connections report `LinkDegraded`. `None`, the default, doesn't sample
+    /// link quality at all.
+    pub fn set_link_degradation(&mut self, value: Option<LinkDegradationThresholds>) {
+        self.link_degradation = value;
+    }
+
+    /// Overwrites how many of the latest HCI events each connection keeps for
+    /// `Connection::recent_hci_events`, for debugging. 0, the default, keeps none.
+    pub fn set_recent_hci_events(&mut self, value: usize) {
+        self.recent_hci_events = value;
+    }
+
+    /// Overwrites whether links are encrypted as soon as they authenticate, and disconnected if
+    /// that fails, rather than left authenticated but unencrypted
+    pub fn set_require_encryption_after_auth(&mut self, value: bool) {
+        self.require_encryption_after_auth = value;
+    }
+
+    /// Overwrites whether accepted incoming connections are authenticated and encrypted before
+    /// `Event::ConnectSuccess` is sent for them, whatever the incoming security policy asks.
+    /// One that fails either is disconnected and reported with `Event::ConnectFail`.
+    pub fn set_auto_secure_incoming(&mut self, value: bool) {
+        self.auto_secure_incoming = value;
+    }
+
+    /// Overwrites how long a link that authenticated has to become encrypted, when
+    /// `set_require_encryption_after_auth` is on
+    pub fn set_encryption_after_auth_timeout(&mut self, value: Duration) {
+        self.encryption_after_auth_timeout = value;
+    }
+
+    /// Overwrites how many octets per second guaranteed service QoS may reserve across all
+    /// connections. Requests that would go over it are turned down. `None`, the default, only
+    /// keeps count.
+    pub fn set_max_guaranteed_bandwidth(&mut self, value: Option<u32>) {
+        self.max_guaranteed_bandwidth = value;
+    }
+
+    /// Overwrites how long an outgoing connect may page before it is cancelled and fails with
+    /// `PageTimeout`. `None`, the default, leaves it to the controller's page timeout.
+    pub fn set_connect_timeout(&mut self, value: Option<Duration>) {
+        self.connect_timeout = value;
+    }
+
+    /// Overwrites how many connections can be open at once. Incoming connections over it are
+    /// rejected with `LimitedResources`. `None`, the default, leaves it to the controller.
+    pub fn set_connection_limit(&mut self, value: Option<ConnectionLimit>) {
+        self.connection_limit = value;
+    }
+
+    /// Overwrites how long the clock offset of a peer that disconnected is reused to page it
+    /// again. When set, each link reads its peer's clock offset once it is up. The peer's
+    /// clock drifts, so it shouldn't be kept long. `None`, the default, reads and keeps none.
+    pub fn set_clock_offset_lifetime(&mut self, value: Option<Duration>) {
+        self.clock_offset_lifetime = value;
+    }
+
+    /// Overwrites how outgoing connects that fail with a `Transient` class are retried, unless
+    /// their `ConnectParams` say otherwise. `None`, the default, fails them on the first try.
+    pub fn set_reconnect_policy(&mut self, value: Option<ReconnectPolicy>) {
+        self.reconnect_policy = value;
+    }
+
+    /// Overwrites how long a link may go without ACL data either way before it is
+    /// disconnected. HCI events on the link don't count as traffic. `None`, the default, keeps
+    /// idle links up.
+    pub fn set_idle_timeout(&mut self, value: Option<Duration>) {
+        self.idle_timeout = value;
+    }
+
+    /// Overwrites how long a link may take to go down once a disconnect was sent on it. Past
+    /// it, the link is dropped without the controller's Disconnection Complete and reported
+    /// down with `ConnectionTimeout`.
+    pub fn set_disconnect_timeout(&mut self, value: Duration) {
+        self.disconnect_timeout = value;
+    }
+
+    /// Overwrites whether `Connection::write_link_supervision_timeout` may turn the timeout off
+    /// with 0, so a link that stops answering is never dropped. Off by default.
+    pub fn set_allow_no_supervision_timeout(&mut self, value: bool) {
+        self.allow_no_supervision_timeout = value;
+    }
+
+    /// Overwrites whether `AclManager::write_page_timeout` may write 0, which leaves the
+    /// controller unable to page at all. Off by default.
+    pub fn set_allow_no_page_timeout(&mut self, value: bool) {
+        self.allow_no_page_timeout = value;
+    }
+
+    /// Overwrites the link policy the controller gives new connections, written when the
+    /// manager starts. Modes the local controller doesn't support are left out. `None`, the
+    /// default, keeps the controller's own.
+    pub fn set_default_link_policy(&mut self, value: Option<LinkPolicy>) {
+        self.default_link_policy = value;
+    }
+
+    /// Overwrites which connects the manager reports starting and stopping having outstanding
+    /// with `Event::QueueActive` and `Event::QueueIdle`. Off by default.
+    pub fn set_queue_events(&mut self, value: QueueEvents) {
+        self.queue_events = value;
+    }
+
+    /// Overwrites how many items the manager's internal channels hold. A channel can't hold
+    /// none, so 0 is taken as 1.
+    pub fn set_channel_capacities(&mut self, value: ChannelCapacities) {
+        self.channel_capacities = ChannelCapacities {
+            requests: value.requests.max(1),
+            manager_events: value.manager_events.max(1),
+            hci_events: value.hci_events.max(1),
+            connection_requests: value.connection_requests.max(1),
+            connection_events: value.connection_events.max(1),
+        };
+    }
+}
+
+/// How a failed outgoing connect is paged again. Only the terminal failure is reported, once
+/// the attempts are used up or a failure comes that trying again won't help with.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    /// Retries after the first attempt
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each after it
+    pub base_delay: Duration,
+    /// Longest wait between two attempts
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Wait before the specified retry, counting from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(31);
+        self.base_delay.checked_mul(factor).unwrap_or(self.max_delay).min(self.max_delay)
+    }
+}
+
+/// The most connections that can be open at once, and what becomes of connects made past it
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimit {
+    /// Most open connections
+    pub max: usize,
+    /// What becomes of connects made while `max` connections are open
+    pub when_full: WhenFull,
+}
+
+/// What becomes of a connect made while the most connections allowed are open
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WhenFull {
+    /// Queue it until a connection goes down
+    Queue,
+    /// Fail it with `ConnectionLimitExceeded`. Connects queued before the limit was reached
+    /// still wait for a connection to go down.
+    Fail,
+}
+
+/// When a link counts as degrading towards a supervision timeout: its failed contact counter
+/// rose by at least `failed_contacts` since the last check, while its RSSI is below `rssi`
+#[derive(Clone, Copy, Debug)]
+pub struct LinkRiskThresholds {
+    /// How often each link is checked
+    pub interval: Duration,
+    /// Rise of the failed contact counter between two checks
+    pub failed_contacts: u16,
+    /// RSSI as Read RSSI reports it, which on BR/EDR is relative to the golden receive power
+    /// range rather than absolute
+    pub rssi: i8,
+}
+
+/// When a link counts as degraded: the link quality the controller reports stayed below
+/// `quality` for `samples` checks in a row
+#[derive(Clone, Copy, Debug)]
+pub struct LinkDegradationThresholds {
+    /// How often each link is checked
+    pub interval: Duration,
+    /// Link quality as Read Link Quality reports it, from 0 to 255 with higher being better
+    pub quality: u8,
+    /// Checks in a row the quality has to stay below the threshold for
+    pub samples: u32,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[provides]
+async fn provide_acl_config() -> AclConfig {
+    AclConfig::default()
+}
+
+/// Options for an outgoing connection
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectParams {
+    /// Type of the address to connect to. Only public addresses can be paged.
+    pub addr_type: AddressType,
+    /// If set, written to the new link before it is reported as connected, in 0.625ms slots
+    pub link_supervision_timeout: Option<u16>,
+    /// Weight of the connect for `ByPriority`, higher goes first
+    pub priority: u8,
+    /// ACL packet types to ask for, in the bit layout of HCI Create Connection. They are
+    /// narrowed to what the controller supports. `None` asks for every DM and DH type.
+    pub packet_types: Option<u16>,
+    /// Page scan repetition mode the peer was last seen with
+    pub page_scan_repetition_mode: PageScanRepetitionMode,
+    /// Clock offset of the peer, if known. Takes the place of one remembered from standby.
+    pub clock_offset: Option<u16>,
+    /// Whether the peer may switch roles while the connection is set up. Overruled by
+    /// `require_role`, if set.
+    pub allow_role_switch: bool,
+    /// If set, the local role the link has to end up in. Should it come up in the other one, a
+    /// switch is asked for, and the link is only reported as connected once it is in the role.
+    /// If the switch fails, the link is disconnected and the connect fails.
+    pub require_role: Option<Role>,
+    /// How the connect is retried if it fails. `None` follows `AclConfig::set_reconnect_policy`.
+    pub reconnect_policy: Option<ReconnectPolicy>,
+    /// Whether the peer is paged again, with these parameters and its latest clock offset, if
+    /// the link is lost to a supervision timeout. The lost `Connection` still ends with
+    /// `ConnectionEvent::Reconnecting` and then `Disconnected`. The link paged again is a new
+    /// `Connection`, handed out with `Event::ConnectSuccess` like any other connect, or failed
+    /// with `Event::ConnectFail` once `reconnect_policy` runs out of attempts.
+    pub auto_reconnect_on_link_loss: bool,
+}
+
+impl Default for ConnectParams {
+    fn default() -> Self {
+        Self {
+            addr_type: AddressType::PublicDeviceAddress,
+            link_supervision_timeout: None,
+            priority: 0,
+            packet_types: None,
+            page_scan_repetition_mode: PageScanRepetitionMode::R1,
+            clock_offset: None,
+            allow_role_switch: true,
+            require_role: None,
+            reconnect_policy: None,
+            auto_reconnect_on_link_loss: false,
+        }
+    }
+}
+
+/// A connect waiting in the queue, as offered to a `NextConnectionStrategy`
+#[derive(Clone, Copy, Debug)]
+pub struct QueuedConnect {
+    /// Address to connect to
+    pub addr: Address,
+    /// Options the connect was issued with
+    pub params: ConnectParams,
+    /// Whether a clock offset is known for the peer, which makes paging it faster
+    pub clock_offset_known: bool,
+}
+
+/// Chooses which queued connect is paged next, whenever the controller is free to page
+pub trait NextConnectionStrategy: Send + Sync {
+    /// Index of the connect to page next. `candidates` is never empty, and is in the order the
+    /// connects were issued. An index past the end is logged and the first is paged instead.
+    fn pick(&self, candidates: &[QueuedConnect]) -> usize;
+}
+
+/// Pages queued connects in the order they were issued
+pub struct Fifo;
+
+impl NextConnectionStrategy for Fifo {
+    fn pick(&self, _candidates: &[QueuedConnect]) -> usize {
+        0
+    }
+}
+
+/// Pages the queued connect with the highest `ConnectParams::priority`, oldest first among
+/// equals
+pub struct ByPriority;
+
+impl NextConnectionStrategy for ByPriority {
+    fn pick(&self, candidates: &[QueuedConnect]) -> usize {
+        let mut picked = 0;
+        for (i, c) in candidates.iter().enumerate() {
+            if c.params.priority > candidates[picked].params.priority {
+                picked = i;
+            }
+        }
+        picked
+    }
+}
+
+/// What a connection saw over its lifetime, reported once it goes down
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionStats {
+    /// How long the connection was up
+    pub duration: Duration,
+    /// Why the connection went down
+    pub reason: ErrorCode,
+    /// Local role when the connection went down
+    pub role: Role,
+    /// Whether the link was encrypted when it went down
+    pub encrypted: bool,
+    /// Number of completed role switches
+    pub role_switches: u32,
+    /// Outbound ACL data handed to the controller, in bytes
+    pub bytes_sent: u64,
+}
+
+/// What the controller reported of a link as it came up
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionEstablished {
+    /// Address of the peer
+    pub addr: Address,
+    /// Handle of the connection
+    pub handle: u16,
+    /// Local role the link came up in
+    pub role: Role,
+    /// Type of the link
+    pub link_type: LinkType,
+    /// Whether the link came up encrypted
+    pub encryption_enabled: bool,
+}
+
+/// Receives the stats of each connection as it goes down, for products that keep long term
+/// per-device reliability stats
+pub trait ConnectionStatsSink: Send + Sync {
+    /// Called once per connection, when it has gone down
+    fn record(&self, addr: Address, stats: ConnectionStats);
+
+    /// Called once per connection, when it came up and before it is handed out. Only carries
+    /// what the controller's connection complete did, so nothing is sent to the controller
+    /// for it.
+    fn established(&self, _established: ConnectionEstablished) {}
+}
+
+/// Drops connection stats
+pub struct NoStats;
+
+impl ConnectionStatsSink for NoStats {
+    fn record(&self, _addr: Address, _stats: ConnectionStats) {}
+}
+
+/// Answers the controller's questions while a link goes through secure simple pairing. Called
+/// from the manager's task, so answers should come without waiting on anything slow.
+pub trait PairingDelegate: Send + Sync {
+    /// IO capabilities to pair with the peer with
+    fn io_capability_request(&self, addr: Address) -> IoCapability;
+    /// Whether the value shown for numeric comparison matches the peer's, or whether to pair
+    /// at all when there is nothing to compare
+    fn confirm_passkey(&self, addr: Address, passkey: u32) -> bool;
+    /// The passkey entered for the peer, or `None` to refuse pairing
+    fn passkey_request(&self, addr: Address) -> Option<u32>;
+}
+
+/// Pairs as a device with a display and a yes/no button that confirms every numeric
+/// comparison, so pairing goes ahead without anyone asked. Passkey entry is refused.
+pub struct JustWorks;
+
+impl PairingDelegate for JustWorks {
+    fn io_capability_request(&self, _addr: Address) -> IoCapability {
+        IoCapability::DisplayYesNo
+    }
+
+    fn confirm_passkey(&self, _addr: Address, _passkey: u32) -> bool {
+        true
+    }
+
+    fn passkey_request(&self, _addr: Address) -> Option<u32> {
+        None
+    }
+}
+
+/// A BR/EDR link key
+pub type LinkKey = [u8; 16];
+
+/// A new or changed link key: the peer, the key, and its type
+pub type LinkKeyEvent = (Address, LinkKey, KeyType);
+
+/// Holds the link keys of bonded devices, so reconnecting to one authenticates with the key
+/// from last time instead of pairing again. Called from the manager's task, like
+/// `PairingDelegate`.
+pub trait KeyStore: Send + Sync {
+    /// The key bonded with the peer, if there is one
+    fn get(&self, addr: Address) -> Option<LinkKey>;
+    /// Keep a key the controller created or changed for the peer
+    fn store(&self, addr: Address, key: LinkKey, key_type: KeyType);
+}
+
+/// Knows no keys and keeps none, so every authentication pairs afresh
+pub struct NoKeys;
+
+impl KeyStore for NoKeys {
+    fn get(&self, _addr: Address) -> Option<LinkKey> {
+        None
+    }
+
+    fn store(&self, _addr: Address, _key: LinkKey, _key_type: KeyType) {}
+}
+
+/// Classic ACL manager
+#[derive(Clone)]
 pub struct AclManager {
     req_tx: Sender<Request>,
     /// High level events from AclManager
     pub evt_rx: Arc<Mutex<Receiver<Event>>>,
+    link_key_rx: Arc<Mutex<Option<Receiver<LinkKeyEvent>>>>,
+    event_info: broadcast::Sender<EventInfo>,
+    metrics: Arc<Counters>,
+    local_name: Arc<std::sync::Mutex<String>>,
+    codecs: Arc<Result<LocalCodecs, ErrorCode>>,
+    bandwidth: BandwidthBudget,
+    local_commands: [u8; 64],
+    lmp_features: u64,
+}
+
+impl Stoppable for AclManager {
+    /// Start winding the manager down. Open links are disconnected, queued connects are failed,
+    /// and links that still complete afterwards are disconnected as soon as they come up.
+    fn stop(&self) {
+        // Can't wait for room here, and a full request queue means the loop is wedged anyway
+        let reason = DisconnectReason::RemoteDeviceTerminatedConnectionPowerOff;
+        if self.req_tx.try_send(Request::Shutdown { reason, fut: None }).is_err() {
+            warn!("could not request ACL manager shutdown");
+        }
+    }
 }
 
 /// Events generated by AclManager
 #[derive(Debug)]
 pub enum Event {
-    /// Connection was successful - provides the newly created connection
+    /// Connection was successful - provides the newly created connection. It is always an ACL
+    /// link, and already knows the role and encryption the Connection Complete reported.
     ConnectSuccess(Connection),
-    /// Locally initialted connection was not successful - indicates address & reason
+    /// Connection was not successful - indicates address & reason
     ConnectFail {
         /// Address of the failed connection
         addr: Address,
         /// Reason of the failed connection
         reason: ErrorCode,
+        /// What the reason means for trying again
+        class: FailureClass,
+    },
+    /// An incoming connection was accepted, and is followed by `ConnectSuccess` or
+    /// `ConnectFail` for the address once it completes
+    IncomingConnecting {
+        /// Address of the peer
+        addr: Address,
+    },
+    /// An incoming connection was turned down, by the accept policy or because the manager
+    /// could not take it
+    IncomingRejected {
+        /// Address of the peer
+        addr: Address,
+        /// Reason given to the peer
+        reason: RejectConnectionReason,
+    },
+    /// The controller stopped acknowledging commands, and needs to be reset. Pending and
+    /// queued connections are failed with `HardwareFailure` before this is sent.
+    HardwareError,
+    /// The controller reported a hardware error, so none of its links are left. Each connection
+    /// was sent `Disconnected` with `HardwareFailure`, and pending and queued connects were
+    /// failed with it, before this is sent.
+    ControllerReset,
+    /// The manager went from no outstanding connects to some, as `AclConfig::set_queue_events`
+    /// selects them
+    QueueActive,
+    /// The last outstanding connect was answered
+    QueueIdle,
+}
+
+/// A summary of an `Event`, handed to every `AclManager::subscribe` receiver. The
+/// `Connection` itself still only goes to `evt_rx`, or to the caller waiting on the connect.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventInfo {
+    /// A connection came up
+    Connected {
+        /// Address of the peer
+        addr: Address,
+        /// Handle of the connection
+        handle: u16,
+    },
+    /// A locally initiated connection failed
+    ConnectFailed {
+        /// Address of the failed connection
+        addr: Address,
+        /// Reason of the failed connection
+        reason: ErrorCode,
+        /// What the reason means for trying again
+        class: FailureClass,
+    },
+    /// An incoming connection was accepted, and is being set up
+    IncomingConnecting {
+        /// Address of the peer
+        addr: Address,
+    },
+    /// An incoming connection was turned down
+    IncomingRejected {
+        /// Address of the peer
+        addr: Address,
+        /// Reason given to the peer
+        reason: RejectConnectionReason,
     },
+    /// The controller stopped acknowledging commands
+    HardwareError,
+    /// The controller lost every link to a hardware error
+    ControllerReset,
+    /// Connects became outstanding
+    QueueActive,
+    /// No connect is outstanding anymore
+    QueueIdle,
+}
+
+impl From<&Event> for EventInfo {
+    fn from(event: &Event) -> Self {
+        match event {
+            Event::ConnectSuccess(connection) => {
+                EventInfo::Connected { addr: connection.addr, handle: connection.handle }
+            }
+            Event::ConnectFail { addr, reason, class } => {
+                EventInfo::ConnectFailed { addr: *addr, reason: *reason, class: *class }
+            }
+            Event::IncomingConnecting { addr } => EventInfo::IncomingConnecting { addr: *addr },
+            Event::IncomingRejected { addr, reason } => {
+                EventInfo::IncomingRejected { addr: *addr, reason: *reason }
+            }
+            Event::HardwareError => EventInfo::HardwareError,
+            Event::ControllerReset => EventInfo::ControllerReset,
+            Event::QueueActive => EventInfo::QueueActive,
+            Event::QueueIdle => EventInfo::QueueIdle,
+        }
+    }
+}
+
+/// Where the manager's events go: the whole event to `evt_rx`, and its summary to subscribers
+struct EventSinks {
+    tx: Sender<Event>,
+    info: broadcast::Sender<EventInfo>,
+    // Spans of connects not answered yet, which their result is traced under
+    connects: std::sync::Mutex<HashMap<Address, Span>>,
+}
+
+impl EventSinks {
+    /// The span a connect to `addr` is traced under, from when it is issued or accepted until
+    /// it is answered
+    fn connect_span(&self, addr: Address) -> Span {
+        let mut connects = self.connects.lock().unwrap();
+        connects.entry(addr).or_insert_with(|| info_span!("acl_connect", addr = %addr)).clone()
+    }
+
+    fn end_connect_span(&self, addr: Address) -> Span {
+        let span = self.connects.lock().unwrap().remove(&addr);
+        span.unwrap_or_else(|| info_span!("acl_connect", addr = %addr))
+    }
+
+    async fn send(&self, event: Event, counters: &ChannelCounters) {
+        self.publish(&event);
+        send_tracked(&self.tx, event, counters).await;
+    }
+
+    // Having no subscribers is fine
+    fn publish(&self, event: &Event) {
+        let _ = self.info.send(event.into());
+    }
+}
+
+// How many summaries a subscriber can fall behind by before it misses the oldest
+const EVENT_INFO_CAPACITY: usize = 32;
+
+// The caller may have stopped waiting, which is no reason to stop the loop answering it
+fn reply<T>(fut: oneshot::Sender<T>, value: T) {
+    if fut.send(value).is_err() {
+        debug!("request was dropped before it was answered");
+    }
+}
+
+fn connect_fail(addr: Address, reason: ErrorCode) -> Event {
+    Event::ConnectFail { addr, reason, class: FailureClass::of(reason) }
+}
+
+/// A standard codec, by its assigned coding format
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Codec {
+    /// Assigned coding format number
+    pub id: u8,
+}
+
+/// A vendor specific codec
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VendorCodec {
+    /// Company that defines the codec
+    pub company_id: u16,
+    /// Codec number, as defined by the company
+    pub codec_id: u16,
+}
+
+#[derive(Debug)]
+struct LocalCodecs {
+    standard: Vec<Codec>,
+    vendor: Vec<VendorCodec>,
 }
 
 /// A classic ACL connection
 #[derive(Debug)]
 pub struct Connection {
     addr: Address,
-    rx: Receiver<Bytes>,
-    tx: Sender<Bytes>,
+    handle: u16,
+    encrypted: Arc<AtomicBool>,
+    channels: Option<ConnectionChannels>,
+    tx: OutboundSender,
     shared: Arc<Mutex<ConnectionShared>>,
     requests: Sender<ConnectionRequest>,
-    evt_rx: Receiver<ConnectionEvent>,
+    tx_limit: core::TxPduLimit,
+    parked: ParkedChannels,
+    traffic: Arc<TrafficCounters>,
 }
 
-/// Events generated by Connection
-#[derive(Debug)]
-pub enum ConnectionEvent {
-    /// Connection was disconnected with the specified code.
-    Disconnected(ErrorCode),
-    /// Connection authentication was completed
-    AuthenticationComplete,
+/// Read-only view of a connection's state. Cheap to clone and hand to other tasks; it keeps
+/// reading the last known state after the connection goes down.
+#[derive(Clone, Debug)]
+pub struct ConnectionView {
+    addr: Address,
+    handle: u16,
+    encrypted: Arc<AtomicBool>,
+    shared: Arc<Mutex<ConnectionShared>>,
 }
 
-impl Connection {
-    /// Disconnect the connection with the specified reason.
-    pub async fn disconnect(&mut self, reason: DisconnectReason) {
-        let (tx, rx) = oneshot::channel();
-        self.requests.send(ConnectionRequest::Disconnect { reason, fut: tx }).await.unwrap();
-        rx.await.unwrap()
+impl ConnectionView {
+    /// Address of the peer
+    pub fn addr(&self) -> Address {
+        self.addr
+    }
+
+    /// Handle of the connection
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    /// Local role on the connection
+    pub async fn role(&self) -> Role {
+        self.shared.lock().await.role
+    }
+
+    /// Whether the link is currently encrypted. Doesn't lock, so it is cheap enough to check
+    /// before every packet.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.load(Ordering::Relaxed)
+    }
+
+    /// The remote's LMP features, once they have been read
+    pub async fn remote_features(&self) -> Option<u64> {
+        self.shared.lock().await.remote_features
     }
 }
 
+/// The receiving ends of a connection, which can only have one owner at a time
 #[derive(Debug)]
-enum ConnectionRequest {
-    Disconnect { reason: DisconnectReason, fut: oneshot::Sender<()> },
+struct ConnectionChannels {
+    rx: Receiver<Bytes>,
+    evt_rx: Receiver<ConnectionEvent>,
 }
 
-struct ConnectionInternal {
-    addr: Address,
-    #[allow(dead_code)]
-    shared: Arc<Mutex<ConnectionShared>>,
-    hci_evt_tx: Sender<EventPacket>,
+/// Where a dropped `Connection` leaves its channels, for `AclManager::take_connection`
+#[derive(Debug, Default)]
+struct Parked {
+    channels: std::sync::Mutex<Option<ConnectionChannels>>,
+    // Wakes sends waiting for room in the event receiver, which nobody reads once it is parked
+    notify: Notify,
 }
 
-#[derive(Debug)]
-struct ConnectionShared {
-    role: Role,
-}
+type ParkedChannels = Arc<Parked>;
 
-impl AclManager {
-    /// Connect to the specified address, or queue it if a connection is already pending
-    pub async fn connect(&mut self, addr: Address) {
-        self.req_tx.send(Request::Connect { addr }).await.unwrap();
+impl Parked {
+    fn park(&self, channels: ConnectionChannels) {
+        *self.channels.lock().unwrap() = Some(channels);
+        self.notify.notify_waiters();
     }
 
-    /// Cancel the connection to the specified address, if it is pending
-    pub async fn cancel_connect(&mut self, addr: Address) {
-        let (tx, rx) = oneshot::channel();
-        self.req_tx.send(Request::CancelConnect { addr, fut: tx }).await.unwrap();
-        rx.await.unwrap();
+    fn take(&self) -> Option<ConnectionChannels> {
+        self.channels.lock().unwrap().take()
+    }
+
+    /// Completes once the channels are parked
+    async fn parked(&self) {
+        loop {
+            // Created before checking, so a park in between still wakes it
+            let notified = self.notify.notified();
+            if self.channels.lock().unwrap().is_some() {
+                return;
+            }
+            notified.await;
+        }
     }
 }
 
-#[derive(Debug)]
-enum Request {
-    Connect { addr: Address },
-    CancelConnect { addr: Address, fut: oneshot::Sender<()> },
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(channels) = self.channels.take() {
+            self.parked.park(channels);
+        }
+    }
 }
 
-#[derive(Eq, PartialEq)]
-enum PendingConnect {
-    Outgoing(Address),
-    Incoming(Address),
-    None,
+/// How hard the controller tries to deliver ACL data on a connection before flushing it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReliabilityMode {
+    /// Retransmit until delivered, for signalling and other data that must not be lost
+    Reliable,
+    /// Give up on data that is late enough to be useless, so streaming audio doesn't stall
+    /// behind it
+    RealTime,
 }
 
-impl PendingConnect {
-    fn take(&mut self) -> Self {
-        std::mem::replace(self, PendingConnect::None)
+impl ReliabilityMode {
+    // In baseband slots of 0.625ms, with 0 meaning never flush
+    fn flush_timeout(self) -> u16 {
+        match self {
+            ReliabilityMode::Reliable => 0,
+            // 100ms, a few A2DP frames
+            ReliabilityMode::RealTime => 160,
+        }
     }
 }
 
-#[provides]
-async fn provide_acl_manager(
+/// What a connection does with inbound data once its reader falls behind
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InboundOverflow {
+    /// Stop taking data from the controller until the reader catches up. The default.
+    Wait,
+    /// Drop what doesn't fit and count it in `TrafficStats::rx_dropped`, for audio and other
+    /// traffic that would rather lose data than stall the link
+    DropNewest,
+}
+
+/// Priority class of outbound ACL data. When the controller has room, data is sent from the
+/// highest priority class that has data waiting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrafficClass {
+    /// Signalling & profile control traffic, sent ahead of everything else
+    Control,
+    /// Streaming media
+    Audio,
+    /// Everything else
+    BestEffort,
+}
+
+#[derive(Clone, Debug)]
+struct OutboundSender {
+    control: Sender<Bytes>,
+    audio: Sender<Bytes>,
+    best_effort: Sender<Bytes>,
+}
+
+impl OutboundSender {
+    fn get(&self, class: TrafficClass) -> &Sender<Bytes> {
+        match class {
+            TrafficClass::Control => &self.control,
+            TrafficClass::Audio => &self.audio,
+            TrafficClass::BestEffort => &self.best_effort,
+        }
+    }
+
+    fn pending(&self) -> usize {
+        [&self.control, &self.audio, &self.best_effort]
+            .iter()
+            .map(|tx| OUTBOUND_QUEUE_LEN - tx.capacity())
+            .sum()
+    }
+}
+
+struct OutboundQueues {
+    control: Receiver<Bytes>,
+    audio: Receiver<Bytes>,
+    best_effort: Receiver<Bytes>,
+}
+
+impl OutboundQueues {
+    async fn recv(&mut self) -> Option<Bytes> {
+        select! {
+            biased;
+            Some(data) = self.control.recv() => Some(data),
+            Some(data) = self.audio.recv() => Some(data),
+            Some(data) = self.best_effort.recv() => Some(data),
+            else => None,
+        }
+    }
+}
+
+// PDUs each traffic class can have queued on a connection
+const OUTBOUND_QUEUE_LEN: usize = 10;
+
+fn outbound_queues() -> (OutboundSender, OutboundQueues) {
+    let (control_tx, control_rx) = channel(OUTBOUND_QUEUE_LEN);
+    let (audio_tx, audio_rx) = channel(OUTBOUND_QUEUE_LEN);
+    let (best_effort_tx, best_effort_rx) = channel(OUTBOUND_QUEUE_LEN);
+    (
+        OutboundSender { control: control_tx, audio: audio_tx, best_effort: best_effort_tx },
+        OutboundQueues { control: control_rx, audio: audio_rx, best_effort: best_effort_rx },
+    )
+}
+
+/// Events generated by Connection
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// Connection was disconnected with the specified code.
+    Disconnected {
+        /// Reason the controller gave
+        reason: ErrorCode,
+        /// Whether this side ended the link, rather than the peer or the link failing
+        local: bool,
+        /// What ended the link, derived from the reason
+        cause: DisconnectCause,
+    },
+    /// Connection authentication was completed, with the status the controller reported
+    AuthenticationComplete(ErrorCode),
+    /// A synchronous connection to the same peer was established
+    ScoConnected(ScoConnection),
+    /// The local role changed, whichever side switched. Also reports the role an incoming
+    /// connection accepted with `AcceptThenSwitchIfSupported` settled on, even if unchanged.
+    RoleChanged(Role),
+    /// The link entered or left a power saving mode, by either side's request
+    ModeChanged {
+        /// Mode the link is now in
+        mode: Mode,
+        /// Sniff or hold interval in baseband slots, 0 when active
+        interval: u16,
+    },
+    /// Encryption was turned on or off, by either side
+    EncryptionChanged {
+        /// Whether the link is now encrypted
+        enabled: bool,
+    },
+    /// An incoming connection reached the security level its policy requires
+    SecurityLevelReached(SecurityLevel),
+    /// Link quality crossed the configured `LinkRiskThresholds`, so a supervision timeout may
+    /// follow. Reported once until the link recovers.
+    LinkAtRisk {
+        /// Failed contact counter at the check
+        failed_contacts: u16,
+        /// RSSI at the check
+        rssi: i8,
+    },
+    /// Link quality stayed below the configured `LinkDegradationThresholds`, a chance to react
+    /// before the link is lost. Reported once until the quality recovers.
+    LinkDegraded {
+        /// Link quality at the last check
+        quality: u8,
+    },
+    /// The link was lost to a supervision timeout and, as `auto_reconnect_on_link_loss` asks,
+    /// the peer is being paged again. Followed by `Disconnected`, as this connection is over.
+    Reconnecting,
+}
+
+impl ConnectionEvent {
+    /// Whether the event only reports the latest state, so earlier ones can be dropped when
+    /// they come in faster than configured
+    fn coalesces(&self) -> bool {
+        matches!(
+            self,
+            ConnectionEvent::RoleChanged(_)
+                | ConnectionEvent::EncryptionChanged { .. }
+                | ConnectionEvent::ModeChanged { .. }
+                | ConnectionEvent::LinkAtRisk { .. }
+                | ConnectionEvent::LinkDegraded { .. }
+        )
+    }
+}
+
+/// Kinds of `ConnectionEvent` a `Connection::subscribe` receiver gets. Combine them with `|`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionEventMask(u16);
+
+impl ConnectionEventMask {
+    /// `ConnectionEvent::Disconnected`
+    pub const DISCONNECTED: Self = Self(0x01);
+    /// `ConnectionEvent::AuthenticationComplete`
+    pub const AUTHENTICATION: Self = Self(0x02);
+    /// `ConnectionEvent::ScoConnected`
+    pub const SCO: Self = Self(0x04);
+    /// `ConnectionEvent::RoleChanged`
+    pub const ROLE: Self = Self(0x08);
+    /// `ConnectionEvent::ModeChanged`
+    pub const MODE: Self = Self(0x10);
+    /// `ConnectionEvent::EncryptionChanged`
+    pub const ENCRYPTION: Self = Self(0x20);
+    /// `ConnectionEvent::SecurityLevelReached`
+    pub const SECURITY: Self = Self(0x40);
+    /// `ConnectionEvent::LinkAtRisk`
+    pub const LINK_AT_RISK: Self = Self(0x80);
+    /// `ConnectionEvent::LinkDegraded`
+    pub const LINK_DEGRADED: Self = Self(0x100);
+    /// `ConnectionEvent::Reconnecting`
+    pub const RECONNECTING: Self = Self(0x200);
+
+    /// No events
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Every kind of event
+    pub const fn all() -> Self {
+        Self(0x3ff)
+    }
+
+    /// Whether every kind in `other` is selected
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The kind `event` is
+    fn of(event: &ConnectionEvent) -> Self {
+        match event {
+            ConnectionEvent::Disconnected { .. } => Self::DISCONNECTED,
+            ConnectionEvent::AuthenticationComplete(_) => Self::AUTHENTICATION,
+            ConnectionEvent::ScoConnected(_) => Self::SCO,
+            ConnectionEvent::RoleChanged(_) => Self::ROLE,
+            ConnectionEvent::ModeChanged { .. } => Self::MODE,
+            ConnectionEvent::EncryptionChanged { .. } => Self::ENCRYPTION,
+            ConnectionEvent::SecurityLevelReached(_) => Self::SECURITY,
+            ConnectionEvent::LinkAtRisk { .. } => Self::LINK_AT_RISK,
+            ConnectionEvent::LinkDegraded { .. } => Self::LINK_DEGRADED,
+            ConnectionEvent::Reconnecting => Self::RECONNECTING,
+        }
+    }
+}
+
+impl BitOr for ConnectionEventMask {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+// Events a subscriber can fall behind by before it misses the newest
+const SUBSCRIBER_QUEUE_LEN: usize = 16;
+
+// RSSI samples a stream's reader can fall behind by before polling waits on it
+const RSSI_STREAM_LEN: usize = 4;
+
+impl Connection {
+    /// Address of the peer
+    pub fn address(&self) -> Address {
+        self.addr
+    }
+
+    /// Handle of the connection, for matching it up with HCI level state
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    /// Local role on the connection. Only waits on this connection's own state, never on the
+    /// manager.
+    pub async fn role(&self) -> Role {
+        self.shared.lock().await.role
+    }
+
+    /// What is known of the link, as of the latest events and reads on it. Doesn't send
+    /// anything to the controller, and only waits on this connection's own state.
+    pub async fn state(&self) -> ConnectionState {
+        let shared = self.shared.lock().await;
+        ConnectionState {
+            role: shared.role,
+            encrypted: shared.encrypted,
+            mode: shared.mode,
+            rssi: shared.rssi,
+            supervision_timeout: shared.supervision_timeout,
+        }
+    }
+
+    /// Disconnect the connection with the specified reason, and wait for the link to go down.
+    /// If the controller turns the disconnect away because it is busy, it is retried a few
+    /// times before failing. A link that isn't down within `AclConfig::set_disconnect_timeout`
+    /// is dropped anyway.
+    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::Disconnect { reason, wait: true, fut }).await
+    }
+
+    /// Like `disconnect`, but only waits for the controller to take the disconnect
+    pub async fn disconnect_no_wait(&mut self, reason: DisconnectReason) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::Disconnect { reason, wait: false, fut }).await
+    }
+
+    /// Queue data to be sent on the connection, in the specified priority class. Fails with
+    /// `Disconnected` once the link is down.
+    pub async fn send_data(&mut self, data: Bytes, class: TrafficClass) -> Result<(), AclError> {
+        self.tx.get(class).send(data).await.map_err(|_| AclError::Disconnected)
+    }
+
+    /// Queue a whole L2CAP PDU, header included, to be sent best effort. The dispatch splits
+    /// it into ACL packets that fit the controller's buffers, or the size set with
+    /// `set_max_tx_pdu`, so it should not be fragmented beforehand.
+    pub async fn send(&mut self, data: Bytes) -> Result<(), AclError> {
+        self.send_data(data, TrafficClass::BestEffort).await
+    }
+
+    /// Queue data in the specified priority class without waiting for room. If the class's
+    /// queue is full, the data is handed back in `SendError::Full`, for callers like real-time
+    /// audio that would rather drop a frame than stall.
+    pub fn try_send_data(&mut self, data: Bytes, class: TrafficClass) -> Result<(), SendError> {
+        self.tx.get(class).try_send(data).map_err(|e| match e {
+            TrySendError::Full(data) => SendError::Full(data),
+            TrySendError::Closed(data) => SendError::Disconnected(data),
+        })
+    }
+
+    /// Queue a whole L2CAP PDU best effort, as `send` does, without waiting for room
+    pub fn try_send(&mut self, data: Bytes) -> Result<(), SendError> {
+        self.try_send_data(data, TrafficClass::BestEffort)
+    }
+
+    /// PDUs queued on the connection across all classes, not yet taken by the dispatch. The
+    /// dispatch only takes more once Number Of Completed Packets frees the controller's
+    /// buffers, which all links share, so this grows while the controller is slow to send.
+    /// Data already handed to the controller isn't counted.
+    pub fn pending_tx_len(&self) -> usize {
+        self.tx.pending()
+    }
+
+    /// The next L2CAP PDU received on the connection, header included, reassembled from its
+    /// ACL packets. `None` once the link is down.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.channels.as_mut().unwrap().rx.recv().await
+    }
+
+    /// Receive the kinds of event in `mask` from now on, besides the connection's own events,
+    /// which still get every kind. Events reach subscribers as they are delivered, after any
+    /// coalescing. One that falls 16 events behind misses the events that don't fit, so a slow
+    /// subscriber never holds up the link or the others. The receiver ends once the link is
+    /// down, or straight away if it already is.
+    pub async fn subscribe(&self, mask: ConnectionEventMask) -> Receiver<ConnectionEvent> {
+        let (tx, rx) = channel(SUBSCRIBER_QUEUE_LEN);
+        // If the link is down the sender is dropped with the request, ending the receiver
+        let _ = self.requests.send(ConnectionRequest::Subscribe { mask, tx }).await;
+        rx
+    }
+
+    /// Data sent and received on the connection so far. Only ever grows while the link is up,
+    /// and doesn't wait on the connection's task.
+    pub fn stats(&self) -> TrafficStats {
+        self.traffic.snapshot()
+    }
+
+    /// Split outbound data into ACL packets of at most `size` bytes, for when the controller
+    /// needs packets smaller than its advertised buffer length. Applies to data not yet sent.
+    /// Capped to the buffer length, and 0 is taken as 1.
+    pub fn set_max_tx_pdu(&mut self, size: usize) {
+        self.tx_limit.set(size);
+    }
+
+    /// Whether the link is currently encrypted. Doesn't lock, so it is cheap enough to check
+    /// before every packet.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.load(Ordering::Relaxed)
+    }
+
+    /// The latest HCI events routed to the connection, oldest first. Only kept if
+    /// `AclConfig::set_recent_hci_events` asked for them.
+    pub async fn recent_hci_events(&self) -> Vec<EventPacket> {
+        self.shared.lock().await.recent_events.iter().cloned().collect()
+    }
+
+    /// Stash application state with the connection, replacing whatever was stashed before
+    pub async fn set_context<T: Any + Send>(&mut self, ctx: T) {
+        self.shared.lock().await.context = Some(Context(Box::new(ctx)));
+    }
+
+    /// The application state stashed with `set_context`, if it is a `T`
+    pub async fn context<T: Any + Send + Clone>(&self) -> Option<T> {
+        let shared = self.shared.lock().await;
+        shared.context.as_ref().and_then(|c| c.0.downcast_ref::<T>()).cloned()
+    }
+
+    /// A view of the connection's state, for holders that have no use for its data or events
+    pub fn view(&self) -> ConnectionView {
+        ConnectionView {
+            addr: self.addr,
+            handle: self.handle,
+            encrypted: self.encrypted.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Switch to the specified role, resolving once the controller reports the switch done.
+    /// Fails with the Role Change status if the switch itself fails, or with
+    /// `UnsupportedRemoteOrLmpFeature` if the remote does not support role switch, unless the
+    /// feature check is disabled in the config.
+    pub async fn switch_role(&mut self, role: Role) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::SwitchRole { role, fut }).await
+    }
+
+    /// Read the current role from the controller, correcting the cached role if a change was
+    /// missed
+    pub async fn discover_role(&mut self) -> Result<Role, AclError> {
+        self.request(|fut| ConnectionRequest::DiscoverRole { fut }).await
+    }
+
+    /// Read the peer's user-friendly name. Requests made while one is outstanding share its
+    /// answer.
+    pub async fn read_remote_name(&mut self) -> Result<String, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadRemoteName { fut }).await
+    }
+
+    /// Read the peer's LMP features (page 0), and keep them for later role switches. Requests
+    /// made while a read is outstanding share its answer.
+    pub async fn read_remote_features(&mut self) -> Result<u64, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadRemoteFeatures { fut }).await
+    }
+
+    /// Read one page of the peer's extended LMP features, such as page 1 for the host features
+    /// that say whether it supports Secure Connections. Pages past the last one the peer
+    /// reports on page 0 are refused with `InvalidHciCommandParameters`, reading page 0 first
+    /// if that isn't known yet. One read is allowed at a time.
+    pub async fn read_remote_extended_features(&mut self, page: u8) -> Result<u64, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadRemoteExtendedFeatures { page, fut }).await
+    }
+
+    /// Read the version of the peer's link manager. Requests made while a read is
+    /// outstanding share its answer.
+    pub async fn read_remote_version(&mut self) -> Result<RemoteVersion, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadRemoteVersion { fut }).await
+    }
+
+    /// Read the strength the peer is received at, in dB. On a BR/EDR link this is relative
+    /// to the controller's golden receive power range: 0 inside it, positive above it,
+    /// negative below.
+    pub async fn read_rssi(&mut self) -> Result<i8, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadRssi { fut }).await
+    }
+
+    /// Read the RSSI every `interval` and send each sample on the returned receiver, as for a
+    /// signal strength meter. A read that fails skips its sample. Polling stops once the
+    /// connection goes down or the receiver is dropped. Must be called within the runtime.
+    pub fn rssi_stream(&self, interval: Duration) -> Receiver<i8> {
+        let (tx, rx) = channel(RSSI_STREAM_LEN);
+        let requests = self.requests.clone();
+        tokio::spawn(async move {
+            let mut ticks = bt_common::time::interval(interval);
+            loop {
+                select! {
+                    _ = ticks.tick() => {},
+                    _ = requests.closed() => break,
+                    _ = tx.closed() => break,
+                }
+                let (fut, sample) = oneshot::channel();
+                if requests.send(ConnectionRequest::ReadRssi { fut }).await.is_err() {
+                    break;
+                }
+                match sample.await {
+                    Ok(Ok(rssi)) => {
+                        if tx.send(rssi).await.is_err() {
+                            break;
+                        }
+                    }
+                    // The connection went down with the read pending
+                    Ok(Err(ErrorCode::UnknownConnection)) | Err(_) => break,
+                    Ok(Err(status)) => debug!("skipping an RSSI sample: {:?}", status),
+                }
+            }
+        });
+        rx
+    }
+
+    /// Read the power this side transmits at on the link, in dBm. If the local controller
+    /// doesn't support the read, it fails with `UnknownHciCommand` without being sent.
+    pub async fn read_tx_power(&mut self, kind: TxPowerKind) -> Result<i8, ErrorCode> {
+        if !self.supports(ControllerFeature::ReadTxPower).await {
+            return Err(ErrorCode::UnknownHciCommand);
+        }
+        self.hci_request(|fut| ConnectionRequest::ReadTxPower { kind, fut }).await
+    }
+
+    /// Read a Bluetooth clock with its accuracy, as the clock in 0.3125ms ticks along with
+    /// the worst it may be off by, in ticks. `ClockKind::Piconet` reads the clock of the
+    /// piconet the link is in, which both ends of the link agree on. `ClockKind::Local`
+    /// reads the local controller's own clock, the same whichever link it is read through,
+    /// which is always known exactly. If the local controller doesn't support the read, it
+    /// fails with `UnknownHciCommand` without being sent.
+    pub async fn read_clock(&mut self, which: ClockKind) -> Result<(u32, u16), ErrorCode> {
+        if !self.supports(ControllerFeature::ReadClock).await {
+            return Err(ErrorCode::UnknownHciCommand);
+        }
+        self.hci_request(|fut| ConnectionRequest::ReadClock { which, fut }).await
+    }
+
+    /// Read the link quality the controller reports, from 0 to 255 where higher is better. How
+    /// it is measured is up to the controller, so only compare it against earlier reads.
+    pub async fn read_link_quality(&mut self) -> Result<u8, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadLinkQuality { fut }).await
+    }
+
+    /// Read how many consecutive times the peer failed to answer while the flush timeout was
+    /// running, since the counter was last reset
+    pub async fn read_failed_contact_counter(&mut self) -> Result<u16, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadFailedContactCounter { fut }).await
+    }
+
+    /// Reset the failed contact counter to 0
+    pub async fn reset_failed_contact_counter(&mut self) -> Result<(), ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ResetFailedContactCounter { fut }).await
+    }
+
+    /// Ask for a quality of service on the link, resolving with what the controller settled on,
+    /// which may differ from what was asked. A `Guaranteed` token rate is reserved from the
+    /// budget set by `AclConfig::set_max_guaranteed_bandwidth` first, and a request that
+    /// doesn't fit fails with `ConnectionRejectedLimitedResources` without being sent.
+    pub async fn setup_qos(&mut self, qos: QosParams) -> Result<QosResult, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::SetupQos { qos, fut }).await
+    }
+
+    /// Put the link in sniff mode, resolving once the controller reports the mode change.
+    /// Fails with `InvalidHciCommandParameters` if the minimum interval is above the maximum.
+    pub async fn enter_sniff(&mut self, params: SniffParams) -> Result<(), AclError> {
+        if params.min_interval > params.max_interval {
+            return Err(AclError::Hci(ErrorCode::InvalidHciCommandParameters));
+        }
+        self.request(|fut| ConnectionRequest::EnterSniff { params, fut }).await
+    }
+
+    /// Take the link out of sniff mode, resolving once the controller reports the mode change
+    pub async fn exit_sniff(&mut self) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::ExitSniff { fut }).await
+    }
+
+    /// Put the link in hold mode for an interval between the two, in baseband slots of
+    /// 0.625ms, resolving with the interval the controller chose once it reports the mode
+    /// change. The link goes back to active by itself once the interval is over. Fails with
+    /// `InvalidHciCommandParameters` if the minimum is above the maximum, or either is outside
+    /// 0x0002 to 0xfffe.
+    pub async fn enter_hold(
+        &mut self,
+        max_interval: u16,
+        min_interval: u16,
+    ) -> Result<u16, ErrorCode> {
+        let valid =
+            HOLD_INTERVALS.contains(&min_interval) && HOLD_INTERVALS.contains(&max_interval);
+        if !valid || min_interval > max_interval {
+            return Err(ErrorCode::InvalidHciCommandParameters);
+        }
+        self.hci_request(|fut| ConnectionRequest::EnterHold { max_interval, min_interval, fut })
+            .await
+    }
+
+    /// Authenticate the link, resolving once the controller reports authentication complete.
+    /// A failed authentication comes back with its status.
+    pub async fn authenticate(&mut self) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::Authenticate { fut }).await
+    }
+
+    /// Turn encryption on or off, resolving once the controller reports the change. A change
+    /// the controller or peer refuses fails with its status, leaving the link as it was.
+    pub async fn set_encryption(&mut self, enable: bool) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::SetEncryption { enable, fut }).await
+    }
+
+    /// Read the size of the encryption key the link settled on, in bytes. Checked once
+    /// `EncryptionChanged` reports encryption on, so a link with too short a key can be
+    /// disconnected. If the local controller doesn't support the read, it fails with
+    /// `UnknownHciCommand` without being sent.
+    pub async fn read_encryption_key_size(&mut self) -> Result<u8, ErrorCode> {
+        if !self.supports(ControllerFeature::ReadEncryptionKeySize).await {
+            return Err(ErrorCode::UnknownHciCommand);
+        }
+        self.hci_request(|fut| ConnectionRequest::ReadEncryptionKeySize { fut }).await
+    }
+
+    /// Set how long the controller retries outbound data before flushing it. Can be changed at
+    /// any time, for a link that alternates between signalling and streaming.
+    pub async fn set_reliability(&mut self, mode: ReliabilityMode) -> Result<(), AclError> {
+        let timeout = mode.flush_timeout();
+        self.request(|fut| ConnectionRequest::SetFlushTimeout { timeout, fut }).await
+    }
+
+    /// Choose what happens to inbound data the reader isn't keeping up with. Takes effect for
+    /// data received from now on.
+    pub async fn set_inbound_overflow(&mut self, mode: InboundOverflow) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::SetInboundOverflow { mode, fut }).await
+    }
+
+    /// Set the automatic flush timeout directly, in baseband slots of 0.625ms with 0 meaning
+    /// never flush. Values above 0x07ff, about 1.28s, are out of spec and fail with
+    /// `InvalidHciCommandParameters` without being sent.
+    pub async fn set_automatic_flush_timeout(&mut self, timeout: u16) -> Result<(), AclError> {
+        if timeout > MAX_FLUSH_TIMEOUT {
+            return Err(AclError::Hci(ErrorCode::InvalidHciCommandParameters));
+        }
+        self.request(|fut| ConnectionRequest::SetFlushTimeout { timeout, fut }).await
+    }
+
+    /// Read the automatic flush timeout, in baseband slots of 0.625ms with 0 meaning never flush
+    pub async fn read_automatic_flush_timeout(&mut self) -> Result<u16, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadFlushTimeout { fut }).await
+    }
+
+    /// Set which modes the link manager may use on the link, like turning role switches off
+    /// on a link that misbehaves during them. Modes the local controller doesn't support fail
+    /// with `UnsuportedFeatureOrParameterValue` without being sent.
+    pub async fn write_link_policy(&mut self, settings: LinkPolicy) -> Result<(), ErrorCode> {
+        if !LinkPolicy::supported_by(self.shared.lock().await.local_features).contains(settings) {
+            return Err(ErrorCode::UnsuportedFeatureOrParameterValue);
+        }
+        self.hci_request(|fut| ConnectionRequest::WriteLinkPolicy { settings, fut }).await
+    }
+
+    /// Set how long the link may go without hearing from the peer before it is dropped, in
+    /// baseband slots of 0.625ms. Only the central can set it. 0 turns the timeout off, and
+    /// fails with `InvalidHciCommandParameters` unless
+    /// `AclConfig::set_allow_no_supervision_timeout` allows it.
+    pub async fn write_link_supervision_timeout(&mut self, slots: u16) -> Result<(), ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::WriteSupervisionTimeout { slots, fut }).await
+    }
+
+    /// Read the link supervision timeout in effect, in baseband slots of 0.625ms with 0 meaning
+    /// none
+    pub async fn read_link_supervision_timeout(&mut self) -> Result<u16, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadSupervisionTimeout { fut }).await
+    }
+
+    /// Read which modes the link manager may use on the link
+    pub async fn read_link_policy(&mut self) -> Result<LinkPolicy, ErrorCode> {
+        self.hci_request(|fut| ConnectionRequest::ReadLinkPolicy { fut }).await
+    }
+
+    /// Drop the outbound data the controller holds for the link, resolving once the controller
+    /// has. Data still queued on this side is sent as usual.
+    pub async fn flush(&mut self) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::Flush { fut }).await
+    }
+
+    /// Ask for a synchronous connection to the peer, for audio on top of this link. Resolves
+    /// once the controller takes the request; the link is announced with
+    /// `ConnectionEvent::ScoConnected` when it comes up.
+    pub async fn request_sco(&mut self, params: ScoParams) -> Result<(), AclError> {
+        self.request(|fut| ConnectionRequest::SetupSco { params, fut }).await
+    }
+
+    /// Hand a request to the connection's task, failing with `Disconnected` if the link goes
+    /// down before it is answered
+    async fn request<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<Result<T, AclError>>) -> ConnectionRequest,
+    ) -> Result<T, AclError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send(make(tx)).await.map_err(|_| AclError::Disconnected)?;
+        rx.await.unwrap_or(Err(AclError::Disconnected))
+    }
+
+    /// Hand a request that is answered by the controller to the connection's task, failing
+    /// with `UnknownConnection` if the link goes down before it is answered
+    async fn hci_request<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<Result<T, ErrorCode>>) -> ConnectionRequest,
+    ) -> Result<T, ErrorCode> {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send(make(tx)).await.map_err(|_| ErrorCode::UnknownConnection)?;
+        rx.await.unwrap_or(Err(ErrorCode::UnknownConnection))
+    }
+
+    /// Whether the local controller supports `feature`, as `AclManager::supports` reports it
+    async fn supports(&self, feature: ControllerFeature) -> bool {
+        let shared = self.shared.lock().await;
+        feature.supported_by(&shared.local_commands, shared.local_features)
+    }
+}
+
+/// A synchronous (SCO/eSCO) connection, set up on top of a classic ACL connection
+#[derive(Clone, Debug)]
+pub struct ScoConnection {
+    handle: u16,
+    link_type: ScoLinkType,
+    shared: Arc<Mutex<ScoConnectionShared>>,
+}
+
+#[derive(Debug)]
+struct ScoConnectionShared {
+    air_mode: ScoAirMode,
+}
+
+impl ScoConnection {
+    /// The handle of the synchronous connection
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    /// Whether the link came up as SCO or eSCO
+    pub fn link_type(&self) -> ScoLinkType {
+        self.link_type
+    }
+
+    /// The air mode negotiated at setup. Wideband speech (mSBC) is carried as `Transparent`.
+    pub async fn air_mode(&self) -> ScoAirMode {
+        self.shared.lock().await.air_mode
+    }
+}
+
+/// Synchronous connection to ask for with `Connection::request_sco`, as HCI Setup Synchronous
+/// Connection takes it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScoParams {
+    /// Data rate sent to the peer, in octets per second
+    pub transmit_bandwidth: u32,
+    /// Data rate received from the peer, in octets per second
+    pub receive_bandwidth: u32,
+    /// Longest delay the link may add, in milliseconds
+    pub max_latency: u16,
+    /// Voice setting, the same bits as HCI Write Voice Setting takes
+    pub voice_setting: u16,
+    /// What to optimize retransmissions for, as HCI encodes it: none, power or link quality
+    pub retransmission_effort: u8,
+    /// Packet types the link may use, as HCI encodes them
+    pub packet_type: u16,
+}
+
+#[derive(Debug)]
+enum ConnectionRequest {
+    Disconnect { reason: DisconnectReason, wait: bool, fut: oneshot::Sender<Result<(), AclError>> },
+    SwitchRole { role: Role, fut: oneshot::Sender<Result<(), AclError>> },
+    Standby { fut: oneshot::Sender<Result<(), AclError>> },
+    Subscribe { mask: ConnectionEventMask, tx: Sender<ConnectionEvent> },
+    DiscoverRole { fut: oneshot::Sender<Result<Role, AclError>> },
+    SetFlushTimeout { timeout: u16, fut: oneshot::Sender<Result<(), AclError>> },
+    SetInboundOverflow { mode: InboundOverflow, fut: oneshot::Sender<Result<(), AclError>> },
+    ReadFlushTimeout { fut: oneshot::Sender<Result<u16, ErrorCode>> },
+    Flush { fut: oneshot::Sender<Result<(), AclError>> },
+    WriteLinkPolicy { settings: LinkPolicy, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    ReadLinkPolicy { fut: oneshot::Sender<Result<LinkPolicy, ErrorCode>> },
+    WriteSupervisionTimeout { slots: u16, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    ReadSupervisionTimeout { fut: oneshot::Sender<Result<u16, ErrorCode>> },
+    SetEncryption { enable: bool, fut: oneshot::Sender<Result<(), AclError>> },
+    Authenticate { fut: oneshot::Sender<Result<(), AclError>> },
+    EnterSniff { params: SniffParams, fut: oneshot::Sender<Result<(), AclError>> },
+    ExitSniff { fut: oneshot::Sender<Result<(), AclError>> },
+    EnterHold { max_interval: u16, min_interval: u16, fut: oneshot::Sender<Result<u16, ErrorCode>> },
+    ReadRemoteName { fut: oneshot::Sender<Result<String, ErrorCode>> },
+    ReadRemoteFeatures { fut: oneshot::Sender<Result<u64, ErrorCode>> },
+    ReadRemoteExtendedFeatures { page: u8, fut: oneshot::Sender<Result<u64, ErrorCode>> },
+    ReadRemoteVersion { fut: oneshot::Sender<Result<RemoteVersion, ErrorCode>> },
+    ReadRssi { fut: oneshot::Sender<Result<i8, ErrorCode>> },
+    ReadTxPower { kind: TxPowerKind, fut: oneshot::Sender<Result<i8, ErrorCode>> },
+    ReadClock { which: ClockKind, fut: oneshot::Sender<Result<(u32, u16), ErrorCode>> },
+    ReadEncryptionKeySize { fut: oneshot::Sender<Result<u8, ErrorCode>> },
+    ReadLinkQuality { fut: oneshot::Sender<Result<u8, ErrorCode>> },
+    ReadFailedContactCounter { fut: oneshot::Sender<Result<u16, ErrorCode>> },
+    ResetFailedContactCounter { fut: oneshot::Sender<Result<(), ErrorCode>> },
+    SetupQos { qos: QosParams, fut: oneshot::Sender<Result<QosResult, ErrorCode>> },
+    SetupSco { params: ScoParams, fut: oneshot::Sender<Result<(), AclError>> },
+    // A new connection from the peer replaces the link, which the manager sent a disconnect for
+    Superseded { disconnect_sent: bool },
+    // The controller reported a hardware error, which took the link down with the rest
+    ControllerReset,
+}
+
+struct ConnectionInternal {
+    addr: Address,
+    handle: u16,
+    shared: Arc<Mutex<ConnectionShared>>,
+    encrypted: Arc<AtomicBool>,
+    hci_evt_tx: Sender<EventPacket>,
+    requests: Sender<ConnectionRequest>,
+    outbound: OutboundSender,
+    tx_limit: core::TxPduLimit,
+    parked: ParkedChannels,
+    traffic: Arc<TrafficCounters>,
+    // What the link was connected with, to connect it the same way again after a suspend
+    params: ConnectParams,
+}
+
+impl ConnectionInternal {
+    fn connection(&self, channels: ConnectionChannels) -> Connection {
+        Connection {
+            addr: self.addr,
+            handle: self.handle,
+            encrypted: self.encrypted.clone(),
+            channels: Some(channels),
+            tx: self.outbound.clone(),
+            shared: self.shared.clone(),
+            requests: self.requests.clone(),
+            tx_limit: self.tx_limit.clone(),
+            parked: self.parked.clone(),
+            traffic: self.traffic.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ConnectionShared {
+    role: Role,
+    encrypted: bool,
+    // Mirrors `encrypted` for lock-free reads, only ever written along with it
+    encrypted_flag: Arc<AtomicBool>,
+    remote_features: Option<u64>,
+    // Features of the local controller, which requests are checked against before sending
+    local_features: u64,
+    local_commands: [u8; 64],
+    recent_events: VecDeque<EventPacket>,
+    context: Option<Context>,
+    mode: Mode,
+    rssi: Option<i8>,
+    supervision_timeout: Option<u16>,
+}
+
+/// Application state stashed with a connection
+struct Context(Box<dyn Any + Send>);
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Context")
+    }
+}
+
+impl ConnectionShared {
+    fn set_encrypted(&mut self, encrypted: bool) {
+        self.encrypted = encrypted;
+        self.encrypted_flag.store(encrypted, Ordering::Relaxed);
+    }
+
+    fn record_event(&mut self, evt: &EventPacket, keep: usize) {
+        if self.recent_events.len() == keep {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(evt.clone());
+    }
+}
+
+/// What is known of a link without asking the controller, from `Connection::state`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionState {
+    /// Local role on the connection
+    pub role: Role,
+    /// Whether the link is encrypted
+    pub encrypted: bool,
+    /// Power mode the link is in
+    pub mode: Mode,
+    /// RSSI at the latest read, if there was one
+    pub rssi: Option<i8>,
+    /// Supervision timeout as last written or read, in baseband slots of 0.625ms
+    pub supervision_timeout: Option<u16>,
+}
+
+/// A snapshot of an open connection, handed to `AclManager::for_each_connection` and returned
+/// by `AclManager::connection_info` and `AclManager::active_connections`
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    /// Address of the peer
+    pub addr: Address,
+    /// Handle of the connection
+    pub handle: u16,
+    /// Local role on the connection
+    pub role: Role,
+    /// Whether the link is currently encrypted
+    pub encrypted: bool,
+}
+
+/// The state of the manager's connects, from `AclManager::snapshot`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ManagerSnapshot {
+    /// Open connections
+    pub active_connections: usize,
+    /// Outgoing connects waiting for their turn to page
+    pub queued_connects: usize,
+    /// Failed outgoing connects waiting out their reconnect backoff
+    pub retrying_connects: usize,
+    /// The connection being set up
+    pub pending: PendingKind,
+}
+
+/// The connection the manager is setting up, if any
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PendingKind {
+    /// Paging the address
+    Outgoing(Address),
+    /// Accepted a connection request from the address
+    Incoming(Address),
+    /// Nothing is being set up
+    None,
+}
+
+/// What `AclManager::cancel_connect` found to cancel
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CancelOutcome {
+    /// The connect was queued or waiting to be retried, and was dropped before it paged
+    CancelledQueued,
+    /// The connect was paging, and the controller was asked to cancel it. It may still come up
+    /// if the cancel loses the race.
+    CancelSentToController,
+    /// The address is connecting to this side, which can't be cancelled. It is left for the
+    /// caller to disconnect once it is up.
+    IncomingInProgress,
+    /// There was no connect to cancel, because the address is connected
+    AlreadyConnected,
+    /// There was no connect to the address, nor a connection
+    NotFound,
+}
+
+/// Which transmit power level `Connection::read_tx_power` reads
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxPowerKind {
+    /// The level currently transmitted at
+    Current,
+    /// The highest level the link may transmit at
+    Maximum,
+}
+
+impl From<TxPowerKind> for TransmitPowerLevelType {
+    fn from(kind: TxPowerKind) -> Self {
+        match kind {
+            TxPowerKind::Current => TransmitPowerLevelType::Current,
+            TxPowerKind::Maximum => TransmitPowerLevelType::Maximum,
+        }
+    }
+}
+
+/// Which clock `Connection::read_clock` reads
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClockKind {
+    /// The local controller's native clock
+    Local,
+    /// The clock of the piconet the link is in, the central's
+    Piconet,
+}
+
+impl From<ClockKind> for WhichClock {
+    fn from(which: ClockKind) -> Self {
+        match which {
+            ClockKind::Local => WhichClock::Local,
+            ClockKind::Piconet => WhichClock::Piconet,
+        }
+    }
+}
+
+/// Version information the peer's link manager reported, from `Connection::read_remote_version`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RemoteVersion {
+    /// LMP version, as assigned by the Bluetooth SIG
+    pub lmp_version: u8,
+    /// Company identifier of the controller's manufacturer
+    pub manufacturer_name: u16,
+    /// Manufacturer specific revision of the link manager
+    pub lmp_subversion: u16,
+}
+
+/// Sniff mode parameters for `Connection::enter_sniff`, with intervals and timeout in baseband
+/// slots of 0.625ms as HCI Sniff Mode takes them
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SniffParams {
+    /// Longest interval between sniff anchor points
+    pub max_interval: u16,
+    /// Shortest interval between sniff anchor points, at most `max_interval`
+    pub min_interval: u16,
+    /// Receive slots at each anchor point
+    pub attempt: u16,
+    /// Receive slots kept listening after the last packet
+    pub timeout: u16,
+}
+
+/// Link policy settings of a connection, the modes the link manager may use on it. Combine
+/// them with `|`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LinkPolicy(u16);
+
+impl LinkPolicy {
+    /// Switching roles
+    pub const ROLE_SWITCH: Self = Self(0x01);
+    /// Hold mode
+    pub const HOLD_MODE: Self = Self(0x02);
+    /// Sniff mode
+    pub const SNIFF_MODE: Self = Self(0x04);
+    /// Park state
+    pub const PARK_MODE: Self = Self(0x08);
+
+    /// No mode allowed
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The settings as HCI carries them
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Settings from their HCI value, dropping bits no mode is assigned to
+    pub const fn from_bits_truncate(bits: u16) -> Self {
+        Self(bits & 0x0f)
+    }
+
+    /// Whether every mode in `other` is allowed by these settings
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The modes a controller with the specified LMP features can use
+    fn supported_by(lmp_features: u64) -> Self {
+        let has = |bit: u32| lmp_features & (1 << bit) != 0;
+        let mut policy = Self::empty();
+        for (bit, mode) in [
+            (5, Self::ROLE_SWITCH),
+            (6, Self::HOLD_MODE),
+            (7, Self::SNIFF_MODE),
+            (8, Self::PARK_MODE),
+        ] {
+            if has(bit) {
+                policy = policy | mode;
+            }
+        }
+        policy
+    }
+}
+
+impl BitOr for LinkPolicy {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Optional controller capabilities, as `AclManager::supports` checks them
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControllerFeature {
+    /// Reading the local or piconet clock
+    ReadClock,
+    /// Reading the power transmitted at on a link
+    ReadTxPower,
+    /// Reading the size of a link's encryption key
+    ReadEncryptionKeySize,
+    /// Classifying channels for adaptive frequency hopping
+    AfhChannelClassification,
+    /// Switching roles
+    RoleSwitch,
+    /// Hold mode
+    HoldMode,
+    /// Sniff mode
+    SniffMode,
+    /// Park state
+    ParkMode,
+}
+
+impl ControllerFeature {
+    fn supported_by(self, commands: &[u8; 64], lmp_features: u64) -> bool {
+        let policy = |mode| LinkPolicy::supported_by(lmp_features).contains(mode);
+        match self {
+            Self::ReadClock => command_supported(commands, OpCode::ReadClock),
+            Self::ReadTxPower => command_supported(commands, OpCode::ReadTransmitPowerLevel),
+            Self::ReadEncryptionKeySize => {
+                command_supported(commands, OpCode::ReadEncryptionKeySize)
+            }
+            Self::AfhChannelClassification => {
+                command_supported(commands, OpCode::SetAfhHostChannelClassification)
+            }
+            Self::RoleSwitch => policy(LinkPolicy::ROLE_SWITCH),
+            Self::HoldMode => policy(LinkPolicy::HOLD_MODE),
+            Self::SniffMode => policy(LinkPolicy::SNIFF_MODE),
+            Self::ParkMode => policy(LinkPolicy::PARK_MODE),
+        }
+    }
+}
+
+/// Quality of service to ask for with `Connection::setup_qos`, as HCI QoS Setup takes it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QosParams {
+    /// Kind of service
+    pub service_type: ServiceType,
+    /// Average data rate, in octets per second
+    pub token_rate: u32,
+    /// Highest data rate, in octets per second
+    pub peak_bandwidth: u32,
+    /// Longest delay between data being sent and it going over the air, in microseconds
+    pub latency: u32,
+    /// Largest difference between the longest and shortest delay, in microseconds
+    pub delay_variation: u32,
+}
+
+/// Quality of service the controller settled on, from `Connection::setup_qos`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QosResult {
+    /// Kind of service
+    pub service_type: ServiceType,
+    /// Average data rate, in octets per second
+    pub token_rate: u32,
+    /// Highest data rate, in octets per second
+    pub peak_bandwidth: u32,
+    /// Longest delay between data being sent and it going over the air, in microseconds
+    pub latency: u32,
+    /// Largest difference between the longest and shortest delay, in microseconds
+    pub delay_variation: u32,
+}
+
+type QosReply = oneshot::Sender<Result<QosResult, ErrorCode>>;
+
+/// Action to apply to a connection from `AclManager::for_each_connection`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionAction {
+    /// Disconnect with the specified reason
+    Disconnect(DisconnectReason),
+    /// Switch to the specified role
+    SwitchRole(Role),
+}
+
+type ConnectionVisitor = Box<dyn Fn(&ConnectionInfo) -> Option<ConnectionAction> + Send>;
+
+/// Where an incoming connection accepted with `AcceptThenSwitchIfSupported` is in becoming
+/// central. Driven by the connection's own task, so the manager is not held up by the reads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AcceptSwitch {
+    /// Nothing left to do
+    Settled,
+    /// Waiting on the remote features
+    ReadingFeatures,
+    /// Waiting on the role change
+    Switching,
+}
+
+/// A role a new link has to switch to before it is reported as connected, and where the
+/// connection's task tells the manager whether it did
+struct RoleGate {
+    role: Role,
+    settled: Sender<(Address, Result<(), ErrorCode>)>,
+}
+
+/// Where an incoming connection is in reaching the security level its policy requires
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SecuritySetup {
+    /// Nothing left to do
+    Done,
+    /// Waiting on authentication, on the way to the level
+    Authenticating(SecurityLevel),
+    /// Waiting on encryption, on the way to the level
+    Encrypting(SecurityLevel),
+    /// The level could not be reached, so the link is being disconnected
+    Failed(ErrorCode),
+}
+
+impl SecuritySetup {
+    /// How setting up security ended, if it has
+    fn outcome(self) -> Option<Result<(), ErrorCode>> {
+        match self {
+            Self::Done => Some(Ok(())),
+            Self::Failed(status) => Some(Err(status)),
+            Self::Authenticating(_) | Self::Encrypting(_) => None,
+        }
+    }
+}
+
+/// What is kept about a peer in standby to speed up reconnecting to it
+#[derive(Clone, Copy, Debug, Default)]
+struct ReconnectHints {
+    clock_offset: Option<u16>,
+    remote_features: Option<u64>,
+}
+
+/// Clock offsets read from peers, and when their link went down
+type ClockOffsets = Arc<Mutex<HashMap<Address, (u16, Instant)>>>;
+
+/// Schedule another attempt of an outgoing connect that failed with `status`, if the failure is
+/// transient and the policy has attempts left. Returns whether one was scheduled.
+fn schedule_retry(
+    retries: &mut Retries,
+    alarm: &mut Alarm,
+    policy: Option<ReconnectPolicy>,
+    addr: Address,
+    params: ConnectParams,
+    status: ErrorCode,
+) -> bool {
+    if !status::is_retryable(status) {
+        retries.forget(addr);
+        return false;
+    }
+    match policy.and_then(|policy| retries.schedule(addr, params, policy)) {
+        Some(delay) => {
+            info!(
+                "connect to {} failed: {:?}, retrying in {:?}",
+                Link::unconnected(addr),
+                status,
+                delay
+            );
+            arm_retry(alarm, retries);
+            true
+        }
+        None => false,
+    }
+}
+
+fn arm_retry(alarm: &mut Alarm, retries: &Retries) {
+    match retries.next_due() {
+        Some(delay) => alarm.reset(delay),
+        None => alarm.cancel(),
+    }
+}
+
+/// Clock offset to page the specified peer with: the one it was put in standby with, or else
+/// one cached when it last disconnected, if still recent enough to be of use
+async fn known_clock_offset(
+    addr: Address,
+    standby: &Arc<Mutex<HashMap<Address, ReconnectHints>>>,
+    clock_offsets: &ClockOffsets,
+    lifetime: Option<Duration>,
+) -> Option<u16> {
+    if let Some(offset) = standby.lock().await.get(&addr).and_then(|h| h.clock_offset) {
+        return Some(offset);
+    }
+    let mut clock_offsets = clock_offsets.lock().await;
+    match (clock_offsets.get(&addr), lifetime) {
+        (Some((offset, at)), Some(lifetime)) if at.elapsed() < lifetime => Some(*offset),
+        _ => {
+            clock_offsets.remove(&addr);
+            None
+        }
+    }
+}
+
+/// How a link is identified in logs: always the address, plus the handle and role once known,
+/// so churning connections to the same peer can be told apart
+#[derive(Clone, Copy)]
+struct Link {
+    addr: Address,
+    handle: Option<u16>,
+    role: Option<Role>,
+}
+
+impl Link {
+    fn unconnected(addr: Address) -> Self {
+        Link { addr, handle: None, role: None }
+    }
+
+    fn connected(addr: Address, handle: u16, role: Role) -> Self {
+        Link { addr, handle: Some(handle), role: Some(role) }
+    }
+
+    /// The link to `addr`, with its handle and role if it is connected
+    async fn find(addr: Address, connections: &HashMap<u16, ConnectionInternal>) -> Self {
+        match connections.iter().find(|(_, c)| c.addr == addr) {
+            Some((handle, c)) => Link::connected(addr, *handle, c.shared.lock().await.role),
+            None => Link::unconnected(addr),
+        }
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        match (self.handle, self.role) {
+            (Some(handle), Some(role)) => write!(f, " [handle 0x{:03x}, {:?}]", handle, role),
+            (Some(handle), None) => write!(f, " [handle 0x{:03x}]", handle),
+            (None, Some(role)) => write!(f, " [{:?}]", role),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl AclManager {
+    /// Hand out a new `Connection` for the specified handle, if the link is still up and the
+    /// previous `Connection` for it has been dropped
+    pub async fn take_connection(&mut self, handle: u16) -> Result<Option<Connection>, AclError> {
+        self.request(|fut| Request::TakeConnection { handle, fut }).await
+    }
+
+    /// Receive a summary of every event the manager reports from now on, including connects
+    /// answered through `connect_and_wait`. Any number of receivers can subscribe, and none of
+    /// them holds up `evt_rx` or the others: one that falls more than 32 events behind gets
+    /// `RecvError::Lagged` with the number it missed, then carries on from the oldest kept.
+    pub fn subscribe(&self) -> broadcast::Receiver<EventInfo> {
+        self.event_info.subscribe()
+    }
+
+    /// Backpressure on the manager's internal channels since it started
+    pub fn metrics(&self) -> AclMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// The local device name, as read from the controller at startup or last set with
+    /// `set_local_name`. Empty if the controller could not report it.
+    pub fn local_name(&self) -> String {
+        self.local_name.lock().unwrap().clone()
+    }
+
+    /// Octets per second currently reserved by guaranteed service QoS, across all connections
+    pub fn reserved_bandwidth(&self) -> u32 {
+        self.bandwidth.reserved()
+    }
+
+    /// Standard codecs the controller supports, as read at startup
+    pub fn local_codecs(&self) -> Result<Vec<Codec>, ErrorCode> {
+        self.codecs.as_ref().as_ref().map(|c| c.standard.clone()).map_err(|status| *status)
+    }
+
+    /// Vendor specific codecs the controller supports, as read at startup
+    pub fn local_vendor_codecs(&self) -> Result<Vec<VendorCodec>, ErrorCode> {
+        self.codecs.as_ref().as_ref().map(|c| c.vendor.clone()).map_err(|status| *status)
+    }
+
+    /// Whether the controller supports `feature`, as it reported its commands and features at
+    /// startup. Taken to support everything it could not report.
+    pub fn supports(&self, feature: ControllerFeature) -> bool {
+        feature.supported_by(&self.local_commands, self.lmp_features)
+    }
+
+    /// Change the local device name. It can be at most 248 bytes of UTF-8.
+    pub async fn set_local_name(&mut self, name: String) -> Result<(), AclError> {
+        self.request(|fut| Request::SetLocalName { name, fut }).await?
+    }
+
+    /// Link keys created or changed by the controller, for whichever component owns key
+    /// storage. There is only one such owner, so only the first call, from any clone, gets
+    /// them; later calls get None.
+    pub async fn link_key_events(&mut self) -> Option<Receiver<LinkKeyEvent>> {
+        self.link_key_rx.lock().await.take()
+    }
+
+    /// Connect to the specified address, or queue it if a connection is already pending
+    pub async fn connect(&mut self, addr: Address) -> Result<(), AclError> {
+        let params = ConnectParams::default();
+        self.send_request(Request::Connect { addr, params, fut: None }).await
+    }
+
+    /// Connect to the specified address and read its name once connected, for flows that show
+    /// the peer by name straight away. The result is returned here rather than as an `Event`.
+    /// The connection is still returned if the name can't be read, with an empty name.
+    pub async fn connect_and_read_name(
+        &mut self,
+        addr: Address,
+    ) -> Result<(Connection, String), AclError> {
+        let mut connection = self.connect_and_wait(addr).await?;
+        let name = match connection.read_remote_name().await {
+            Ok(name) => name,
+            Err(status) => {
+                let link = Link { addr, handle: Some(connection.handle), role: None };
+                warn!("could not read name of {}: {:?}", link, status);
+                String::new()
+            }
+        };
+        Ok((connection, name))
+    }
+
+    /// Connect to the specified address and wait for the outcome, which is returned here rather
+    /// than as an `Event`. Calls for an address another call is already waiting on share its
+    /// connect: the first caller gets the connection, and the others fail with
+    /// `ConnectionAlreadyExists` once it is up.
+    pub async fn connect_and_wait(&mut self, addr: Address) -> Result<Connection, AclError> {
+        let (tx, rx) = oneshot::channel();
+        let params = ConnectParams::default();
+        self.send_request(Request::Connect { addr, params, fut: Some(tx) }).await?;
+        match rx.await {
+            Ok(Event::ConnectSuccess(connection)) => Ok(connection),
+            Ok(Event::ConnectFail { reason, .. }) => Err(AclError::Hci(reason)),
+            // Dropped without paging, because the address is already connected
+            _ => Err(AclError::Hci(ErrorCode::ConnectionAlreadyExists)),
+        }
+    }
+
+    /// Connect to the specified address and authenticate the link, returning the connection
+    /// only once both succeed within `timeout`. Past it, a connect still paging is cancelled
+    /// and a link still authenticating is disconnected, failing with `TimedOut`. A failed
+    /// authentication disconnects the link too.
+    pub async fn connect_authenticated(
+        &mut self,
+        addr: Address,
+        timeout: Duration,
+    ) -> Result<Connection, AclError> {
+        let mut connected = None;
+        let result = tokio::time::timeout(timeout, async {
+            let connection = connected.insert(self.connect_and_wait(addr).await?);
+            connection.authenticate().await
+        })
+        .await;
+        let error = match result {
+            Ok(Ok(())) => return Ok(connected.expect("authenticated without a connection")),
+            Ok(Err(error)) => error,
+            Err(_) => AclError::TimedOut,
+        };
+        match connected {
+            Some(mut connection) => {
+                let _ =
+                    connection.disconnect_no_wait(DisconnectReason::AuthenticationFailure).await;
+            }
+            // Still paging, unless the connect is what failed
+            None if error == AclError::TimedOut => {
+                self.cancel_connect(addr).await?;
+            }
+            None => {}
+        }
+        Err(error)
+    }
+
+    /// Connect to the first of the specified addresses that answers, for accessories that can
+    /// be reached at more than one. The controller pages one address at a time, so they are
+    /// tried in order, each waiting its turn in the queue like any connect. Cancel the group
+    /// with `cancel_connect` on the address being paged: the addresses after it are then
+    /// not tried.
+    pub async fn connect_any(
+        &mut self,
+        addrs: Vec<Address>,
+    ) -> Result<Connection, ConnectAnyError> {
+        let mut failures = Vec::new();
+        for addr in addrs {
+            match self.connect_and_wait(addr).await {
+                Ok(connection) => return Ok(connection),
+                // What a waiting connect is failed with when it is cancelled
+                Err(AclError::Hci(ErrorCode::UnknownConnection)) => {
+                    return Err(ConnectAnyError::Cancelled)
+                }
+                Err(error @ AclError::Hci(_)) => failures.push((addr, error)),
+                Err(error) => {
+                    failures.push((addr, error));
+                    break;
+                }
+            }
+        }
+        Err(ConnectAnyError::Failed(failures))
+    }
+
+    /// Connect to the specified address with the specified options, or queue it if a
+    /// connection is already pending. Fails with `InvalidAddressType` without paging if the
+    /// address is of a type only LE can use.
+    pub async fn connect_with_params(
+        &mut self,
+        addr: Address,
+        params: ConnectParams,
+    ) -> Result<(), AclError> {
+        match params.addr_type {
+            AddressType::PublicDeviceAddress | AddressType::PublicIdentityAddress => {}
+            AddressType::RandomDeviceAddress | AddressType::RandomIdentityAddress => {
+                return Err(AclError::InvalidAddressType)
+            }
+        }
+        self.send_request(Request::Connect { addr, params, fut: None }).await
+    }
+
+    /// Cancel the connection to the specified address, if it is pending, queued or waiting to
+    /// be retried. If it already came up, it is left for the caller to disconnect.
+    pub async fn cancel_connect(&mut self, addr: Address) -> Result<CancelOutcome, AclError> {
+        self.request(|fut| Request::CancelConnect { addr, fut }).await
+    }
+
+    /// Cancel every outgoing connect, whether paging, queued or waiting to be retried, and
+    /// return their addresses, the one paging first. A connection being set up from the peer's
+    /// side can't be cancelled, so it is left alone and not returned.
+    pub async fn cancel_all_connects(&mut self) -> Result<Vec<Address>, AclError> {
+        self.request(|fut| Request::CancelAllConnects { fut }).await
+    }
+
+    /// Accept or reject an incoming connection that the accept policy deferred
+    pub async fn resolve_incoming(&mut self, addr: Address, accept: bool) -> Result<(), AclError> {
+        self.send_request(Request::ResolveIncoming { addr, accept }).await
+    }
+
+    /// Disconnect from the specified address, but keep what was learned about the peer so a
+    /// later `connect` can skip rediscovering it. Unlike `Connection::disconnect`, the clock
+    /// offset and remote features are retained until the next successful connection. Fails
+    /// with `NotConnected` if there is no connection to the address.
+    pub async fn standby(&mut self, addr: Address) -> Result<(), AclError> {
+        self.request(|fut| Request::Standby { addr, fut }).await?
+    }
+
+    /// Whether the specified address was put in standby and has not reconnected since
+    pub async fn is_standby(&mut self, addr: Address) -> Result<bool, AclError> {
+        self.request(|fut| Request::IsStandby { addr, fut }).await
+    }
+
+    /// Call `f` on every open connection and apply the actions it returns. No connection can
+    /// come or go while this runs, so the decisions are made against a consistent set.
+    pub async fn for_each_connection(
+        &mut self,
+        f: impl Fn(&ConnectionInfo) -> Option<ConnectionAction> + Send + 'static,
+    ) -> Result<(), AclError> {
+        self.request(|fut| Request::ForEachConnection { f: Box::new(f), fut }).await
+    }
+
+    /// The open connection to the specified address, if there is one. A connect still pending
+    /// to it doesn't count, and is left alone.
+    pub async fn connection_info(
+        &mut self,
+        addr: Address,
+    ) -> Result<Option<ConnectionInfo>, AclError> {
+        self.request(|fut| Request::QueryConnection { addr, fut }).await
+    }
+
+    /// How many connections are open, queued and waiting to be retried, and which connect is
+    /// pending, all as of the same moment in the manager's loop
+    pub async fn snapshot(&mut self) -> Result<ManagerSnapshot, AclError> {
+        self.request(|fut| Request::Snapshot { fut }).await
+    }
+
+    /// Every open connection, as of a single moment
+    pub async fn active_connections(&mut self) -> Result<Vec<ConnectionInfo>, AclError> {
+        self.request(|fut| Request::ActiveConnections { fut }).await
+    }
+
+    /// Whether a connection to the specified address is being set up, either side having
+    /// started it, or an outgoing one is queued or waiting to be retried
+    pub async fn is_connecting(&self, addr: Address) -> Result<bool, AclError> {
+        self.request(|fut| Request::IsConnecting { addr, fut }).await
+    }
+
+    /// Check every queued connect against the connect policy again, failing those it now
+    /// refuses. Call after changing whatever the policy depends on, so queued connects don't
+    /// go ahead under the old rules. A connect already sent to the controller is unaffected.
+    pub async fn revalidate_queue(&mut self) -> Result<(), AclError> {
+        self.request(|fut| Request::RevalidateQueue { fut }).await
+    }
+
+    /// Put every open connection in standby ahead of a system suspend. Resolves once they have
+    /// all been disconnected.
+    pub async fn on_system_suspend(&mut self) -> Result<(), AclError> {
+        self.request(|fut| Request::Suspend { fut }).await
+    }
+
+    /// Register or withdraw one want for incoming connections. Page scan is enabled while any
+    /// are registered, and disabled to save power once the last is withdrawn, so every call
+    /// with `true` should eventually be balanced by one with `false`.
+    pub async fn set_incoming_connections_enabled(
+        &mut self,
+        enabled: bool,
+    ) -> Result<(), AclError> {
+        self.request(|fut| Request::SetIncomingConnectionsEnabled { enabled, fut }).await
+    }
+
+    /// Accept incoming connections only from the addresses added here. While none are added,
+    /// every incoming connection goes to the accept policy as usual; once any are, others are
+    /// rejected with `UnacceptableBdAddr` before the policy is asked.
+    pub async fn add_to_incoming_allowlist(&mut self, addr: Address) -> Result<(), AclError> {
+        self.request(|fut| Request::SetIncomingAllowed { addr, allowed: true, fut }).await
+    }
+
+    /// Take an address back off the incoming allowlist. Connections already open are unaffected.
+    pub async fn remove_from_incoming_allowlist(&mut self, addr: Address) -> Result<(), AclError> {
+        self.request(|fut| Request::SetIncomingAllowed { addr, allowed: false, fut }).await
+    }
+
+    /// Turn incoming connections away, or take them again. While disabled, every connection
+    /// request is rejected with `LimitedResources` before the allowlist or the accept policy is
+    /// asked, and page scan is left as it is. One deferred on the accept policy is rejected as
+    /// well. Resolves once the manager applied it, so requests it handles afterwards all follow
+    /// the new setting. Enabled by default.
+    pub async fn set_incoming_enabled(&mut self, enabled: bool) -> Result<(), AclError> {
+        self.request(|fut| Request::SetIncomingEnabled { enabled, fut }).await
+    }
+
+    /// Turn inquiry scan, which makes this device discoverable, and page scan, which makes it
+    /// connectable, on or off. With page scan off the controller reports no Connection
+    /// Request, so no incoming connection can come in. `set_incoming_connections_enabled`
+    /// also turns page scan on and off as its wants come and go, leaving inquiry scan as set
+    /// here.
+    pub async fn write_scan_enable(&mut self, inquiry: bool, page: bool) -> Result<(), AclError> {
+        self.request(|fut| Request::WriteScanEnable { inquiry, page, fut })
+            .await?
+            .map_err(AclError::Hci)
+    }
+
+    /// Read whether page scan is standard or interlaced
+    pub async fn page_scan_type(&self) -> Result<PageScanType, AclError> {
+        self.request(|fut| Request::ReadPageScanType { fut }).await?.map_err(AclError::Hci)
+    }
+
+    /// Switch page scan between standard and interlaced. Interlaced scanning answers incoming
+    /// connections sooner, at the cost of more power while page scan is on.
+    pub async fn set_page_scan_type(&mut self, value: PageScanType) -> Result<(), AclError> {
+        self.request(|fut| Request::WritePageScanType { value, fut }).await?.map_err(AclError::Hci)
+    }
+
+    /// Set how long the controller pages a device before giving up with `PageTimeout`, in
+    /// baseband slots of 0.625ms, for every outgoing connect. 0 stops paging altogether, and
+    /// fails with `InvalidHciCommandParameters` unless `AclConfig::set_allow_no_page_timeout`
+    /// allows it.
+    pub async fn write_page_timeout(&mut self, slots: u16) -> Result<(), AclError> {
+        self.request(|fut| Request::WritePageTimeout { slots, fut }).await?.map_err(AclError::Hci)
+    }
+
+    /// Set how long the controller waits for an incoming connection request to be accepted or
+    /// rejected before it rejects it by itself, in baseband slots of 0.625ms. The accept policy
+    /// runs within this time, so a policy doing slow work like a bond database lookup needs
+    /// it raised: one that takes longer finds the request already rejected. Fails with
+    /// `InvalidHciCommandParameters` without being sent outside of 0x0001 to 0xB540, 29 seconds.
+    pub async fn write_connection_accept_timeout(&mut self, slots: u16) -> Result<(), AclError> {
+        self.request(|fut| Request::WriteConnectionAcceptTimeout { slots, fut })
+            .await?
+            .map_err(AclError::Hci)
+    }
+
+    /// Read how long the controller waits for an incoming connection request to be accepted or
+    /// rejected, in baseband slots of 0.625ms
+    pub async fn connection_accept_timeout(&self) -> Result<u16, AclError> {
+        self.request(|fut| Request::ReadConnectionAcceptTimeout { fut })
+            .await?
+            .map_err(AclError::Hci)
+    }
+
+    /// Set the link policy the controller gives every connection made from now on, which
+    /// `Connection::write_link_policy` can still change for one link. Modes the local
+    /// controller doesn't support fail with `UnsuportedFeatureOrParameterValue` without being
+    /// sent.
+    pub async fn write_default_link_policy(
+        &mut self,
+        settings: LinkPolicy,
+    ) -> Result<(), AclError> {
+        self.request(|fut| Request::WriteDefaultLinkPolicy { settings, fut })
+            .await?
+            .map_err(AclError::Hci)
+    }
+
+    /// Tell the controller which of the 79 channels adaptive frequency hopping should treat as
+    /// bad, one bit a channel from channel 0 in the lowest bit of the first byte, clear for bad.
+    /// Fails with `InvalidHciCommandParameters` without being sent if fewer than the 20
+    /// channels the spec asks for are left, and with `UnknownHciCommand` if the controller
+    /// doesn't support it.
+    pub async fn set_afh_host_channel_classification(
+        &mut self,
+        channels: [u8; 10],
+    ) -> Result<(), AclError> {
+        if afh_channels(&channels) < MIN_AFH_CHANNELS {
+            return Err(AclError::Hci(ErrorCode::InvalidHciCommandParameters));
+        }
+        if !self.supports(ControllerFeature::AfhChannelClassification) {
+            return Err(AclError::Hci(ErrorCode::UnknownHciCommand));
+        }
+        self.request(|fut| Request::SetAfhChannels { channels, fut }).await?.map_err(AclError::Hci)
+    }
+
+    /// Reconnect the connections put in standby by `on_system_suspend`, one at a time. They are
+    /// queued in the order they were suspended, with the parameters they were connected with.
+    pub async fn on_system_resume(&mut self) -> Result<(), AclError> {
+        self.send_request(Request::Resume).await
+    }
+
+    /// Disconnect every open link with the specified reason, and wind the manager down the way
+    /// stopping the module does. Resolves once the last link is down. Calling it again, or
+    /// after the module was stopped, waits for the same links.
+    pub async fn shutdown(&mut self, reason: DisconnectReason) -> Result<(), AclError> {
+        self.request(|fut| Request::Shutdown { reason, fut: Some(fut) }).await
+    }
+
+    /// Disconnect every open link with the specified reason, resolving once they are all down.
+    /// A link still not down after `AclConfig::set_disconnect_timeout` is given up on, as
+    /// `Connection::disconnect` does. Unlike `shutdown`, the manager keeps running: outgoing
+    /// connects issued meanwhile are queued and paged once it resolves, while incoming
+    /// connections are rejected with `LimitedResources` until then.
+    pub async fn disconnect_all(&mut self, reason: DisconnectReason) -> Result<(), AclError> {
+        self.request(|fut| Request::DisconnectAll { reason, fut }).await
+    }
+
+    async fn send_request(&self, req: Request) -> Result<(), AclError> {
+        send_counted(&self.req_tx, req, &self.metrics.requests)
+            .await
+            .map_err(|_| AclError::ChannelClosed)
+    }
+
+    async fn request<T>(
+        &self,
+        make: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> Result<T, AclError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_request(make(tx)).await?;
+        rx.await.map_err(|_| AclError::ChannelClosed)
+    }
+}
+
+enum Request {
+    Connect { addr: Address, params: ConnectParams, fut: Option<oneshot::Sender<Event>> },
+    CancelConnect { addr: Address, fut: oneshot::Sender<CancelOutcome> },
+    CancelAllConnects { fut: oneshot::Sender<Vec<Address>> },
+    ResolveIncoming { addr: Address, accept: bool },
+    Standby { addr: Address, fut: oneshot::Sender<Result<(), AclError>> },
+    IsStandby { addr: Address, fut: oneshot::Sender<bool> },
+    IsConnecting { addr: Address, fut: oneshot::Sender<bool> },
+    QueryConnection { addr: Address, fut: oneshot::Sender<Option<ConnectionInfo>> },
+    ActiveConnections { fut: oneshot::Sender<Vec<ConnectionInfo>> },
+    Snapshot { fut: oneshot::Sender<ManagerSnapshot> },
+    TakeConnection { handle: u16, fut: oneshot::Sender<Option<Connection>> },
+    ForEachConnection { f: ConnectionVisitor, fut: oneshot::Sender<()> },
+    Suspend { fut: oneshot::Sender<()> },
+    RevalidateQueue { fut: oneshot::Sender<()> },
+    SetLocalName { name: String, fut: oneshot::Sender<Result<(), AclError>> },
+    SetIncomingConnectionsEnabled { enabled: bool, fut: oneshot::Sender<()> },
+    SetIncomingAllowed { addr: Address, allowed: bool, fut: oneshot::Sender<()> },
+    SetIncomingEnabled { enabled: bool, fut: oneshot::Sender<()> },
+    WriteScanEnable { inquiry: bool, page: bool, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    ReadPageScanType { fut: oneshot::Sender<Result<PageScanType, ErrorCode>> },
+    WritePageScanType { value: PageScanType, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    WritePageTimeout { slots: u16, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    WriteDefaultLinkPolicy { settings: LinkPolicy, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    WriteConnectionAcceptTimeout { slots: u16, fut: oneshot::Sender<Result<(), ErrorCode>> },
+    ReadConnectionAcceptTimeout { fut: oneshot::Sender<Result<u16, ErrorCode>> },
+    SetAfhChannels { channels: [u8; 10], fut: oneshot::Sender<Result<(), ErrorCode>> },
+    Shutdown { reason: DisconnectReason, fut: Option<oneshot::Sender<()>> },
+    DisconnectAll { reason: DisconnectReason, fut: oneshot::Sender<()> },
+    Resume,
+}
+
+#[provides]
+async fn provide_acl_manager(
     mut hci: CommandSender,
     mut events: EventRegistry,
     mut dispatch: core::AclDispatch,
+    config: AclConfig,
     rt: Arc<Runtime>,
 ) -> AclManager {
-    let (req_tx, mut req_rx) = channel::<Request>(10);
-    let (conn_evt_tx, conn_evt_rx) = channel::<Event>(10);
+    let capacities = config.channel_capacities;
+    let (req_tx, mut req_rx) = channel::<Request>(capacities.requests);
+    let (conn_evt_tx, conn_evt_rx) = channel::<Event>(capacities.manager_events);
+    let (event_info, _) = broadcast::channel(EVENT_INFO_CAPACITY);
+    let conn_evt_tx =
+        EventSinks { tx: conn_evt_tx, info: event_info.clone(), connects: Default::default() };
+    let (unresponsive_tx, mut unresponsive_rx) = channel::<()>(1);
+    let (link_key_tx, link_key_rx) = channel::<LinkKeyEvent>(10);
+    let metrics = Arc::new(Counters::default());
+    let loop_metrics = metrics.clone();
     let local_rt = rt.clone();
 
+    let response = hci.send(ReadLocalNameBuilder {}).await;
+    let local_name = Arc::new(std::sync::Mutex::new(match response.get_status() {
+        ErrorCode::Success => null_terminated_to_string(response.get_local_name()),
+        status => {
+            warn!("could not read local name: {:?}", status);
+            String::new()
+        }
+    }));
+    let loop_local_name = local_name.clone();
+    let bandwidth = BandwidthBudget::new(config.max_guaranteed_bandwidth);
+    let loop_bandwidth = bandwidth.clone();
+
+    let response = hci.send(ReadLocalSupportedCodecsV1Builder {}).await;
+    let codecs = Arc::new(match response.get_status() {
+        ErrorCode::Success => Ok(LocalCodecs {
+            standard: response.get_supported_codecs().iter().map(|&id| Codec { id }).collect(),
+            // Company in the low octets, the company's own codec number in the high ones
+            vendor: response
+                .get_vendor_specific_codecs()
+                .iter()
+                .map(|&c| VendorCodec { company_id: c as u16, codec_id: (c >> 16) as u16 })
+                .collect(),
+        }),
+        status => {
+            warn!("could not read local codecs: {:?}", status);
+            Err(status)
+        }
+    });
+
+    // Some controllers refuse a connect asking for packet types they lack
+    let response = hci.send(ReadLocalSupportedFeaturesBuilder {}).await;
+    // Taken to support everything if they can't be read
+    let lmp_features = match response.get_status() {
+        ErrorCode::Success => response.get_lmp_features(),
+        _ => u64::MAX,
+    };
+    let packet_types = match response.get_status() {
+        ErrorCode::Success => {
+            let types = supported_packet_types(DEFAULT_PACKET_TYPES, lmp_features);
+            if types != DEFAULT_PACKET_TYPES {
+                info!("narrowed ACL packet types to 0x{:04x} for the controller", types);
+            }
+            types
+        }
+        status => {
+            warn!("could not read local features: {:?}", status);
+            DEFAULT_PACKET_TYPES
+        }
+    };
+
+    // Commands the controller may lack are checked against these before they are sent, since
+    // it can answer them in ways that never get back to the command
+    let response = hci.send(ReadLocalSupportedCommandsBuilder {}).await;
+    // Taken to support everything if they can't be read
+    let local_commands = match response.get_status() {
+        ErrorCode::Success => *response.get_supported_commands(),
+        status => {
+            warn!("could not read local supported commands: {:?}", status);
+            [0xff; 64]
+        }
+    };
+
+    if let Some(policy) = config.default_link_policy {
+        let supported = LinkPolicy::supported_by(lmp_features);
+        let settings = LinkPolicy::from_bits_truncate(policy.bits() & supported.bits());
+        if settings != policy {
+            warn!("default link policy {:?} narrowed to {:?} for the controller", policy, settings);
+        }
+        let cmd =
+            WriteDefaultLinkPolicySettingsBuilder { default_link_policy_settings: settings.bits() };
+        match hci.send(cmd).await.get_status() {
+            ErrorCode::Success => {}
+            status => warn!("could not write the default link policy: {:?}", status),
+        }
+    }
+
+    // Registered before the manager is handed out, so no event arrives with nobody to take it.
+    // One another module already claimed panics naming it, rather than leaving a manager that
+    // never hears of its connections.
+    let (evt_tx, mut evt_rx) = channel(capacities.hci_events);
+    events.register(EventCode::ConnectionComplete, evt_tx.clone()).await;
+    events.register(EventCode::ConnectionRequest, evt_tx.clone()).await;
+    events.register(EventCode::HardwareError, evt_tx.clone()).await;
+    events.register(EventCode::AuthenticationComplete, evt_tx.clone()).await;
+    events.register(EventCode::EncryptionChange, evt_tx.clone()).await;
+    events.register(EventCode::SynchronousConnectionComplete, evt_tx.clone()).await;
+    events.register(EventCode::RoleChange, evt_tx.clone()).await;
+    events.register(EventCode::LinkKeyNotification, evt_tx.clone()).await;
+    events.register(EventCode::LinkKeyRequest, evt_tx.clone()).await;
+    events.register(EventCode::IoCapabilityRequest, evt_tx.clone()).await;
+    events.register(EventCode::UserConfirmationRequest, evt_tx.clone()).await;
+    events.register(EventCode::UserPasskeyRequest, evt_tx.clone()).await;
+    // Either side can change the mode, so this is watched even if no one asks to
+    events.register(EventCode::ModeChange, evt_tx.clone()).await;
+    // Completions of reads only some features issue, registered when first needed
+    let lazy_events = LazyEvents::new(events.clone(), evt_tx);
+    // Links lost with `auto_reconnect_on_link_loss` set connect again through the manager
+    let relink_tx = req_tx.clone();
+
     local_rt.spawn(async move {
         let connections: Arc<Mutex<HashMap<u16, ConnectionInternal>>> = Arc::new(Mutex::new(HashMap::new()));
-        let mut connect_queue: Vec<Address> = Vec::new();
-        let mut pending = PendingConnect::None;
-
-        let (evt_tx, mut evt_rx) = channel(3);
-        events.register(EventCode::ConnectionComplete, evt_tx.clone()).await;
-        events.register(EventCode::ConnectionRequest, evt_tx.clone()).await;
-        events.register(EventCode::AuthenticationComplete, evt_tx).await;
+        let standby: Arc<Mutex<HashMap<Address, ReconnectHints>>> = Arc::new(Mutex::new(HashMap::new()));
+        let clock_offsets: ClockOffsets = Arc::new(Mutex::new(HashMap::new()));
+        let mut connect_queue: Vec<(Address, ConnectParams)> = Vec::new();
+        // Connections put in standby by a system suspend, to be restored on resume
+        let mut suspended: Vec<(Address, ConnectParams)> = Vec::new();
+        let mut state = AclState::default();
+        let mut deferred: Option<Address> = None;
+        // Incoming connections are only accepted from these, unless there are none
+        let mut incoming_allowlist: HashSet<Address> = HashSet::new();
+        let mut incoming_enabled = true;
+        let mut deferred_timeout = Alarm::new();
+        // The outgoing connect being paged, if it already failed with a timeout and only waits
+        // on the completion of its cancel
+        let mut timed_out: Option<Address> = None;
+        // The outgoing connect being paged, if it was cancelled and only waits on the
+        // completion of its cancel
+        let mut cancelled: Option<Address> = None;
+        let mut connect_deadline = Alarm::new();
+        // Failed outgoing connects waiting out their backoff
+        let mut retries = Retries::default();
+        let mut retry_alarm = Alarm::new();
+        // Signalled by connections as they go down
+        let slot_freed = Arc::new(Notify::new());
+        // Outstanding wants for incoming connections, page scan is on while there are any
+        let mut incoming_wanted: usize = 0;
+        // Set once the module is stopped, after which no new link is kept
+        let mut shutting_down = false;
+        // Callers of `shutdown` waiting for the last link to go down
+        let mut shutdown_waiters: Vec<oneshot::Sender<()>> = Vec::new();
+        // Callers waiting on the result of their own connect, instead of the event stream
+        let mut connect_waiters: HashMap<Address, Vec<oneshot::Sender<Event>>> = HashMap::new();
+        // Links held back from being reported as connected until they switch to the required
+        // role, or are secured under `auto_secure_incoming`, told apart by the address their
+        // connection's task reports back with
+        let mut gated_links: HashMap<Address, Connection> = HashMap::new();
+        let (gate_settled_tx, mut gate_settled_rx) = channel(10);
+        // Bulk disconnects still waiting on their links, during which no connect is paged
+        let mut disconnecting_all: usize = 0;
+        let (disconnected_all_tx, mut disconnected_all_rx) = channel::<oneshot::Sender<()>>(1);
+        // Whether `Event::QueueActive` was the last of the two sent
+        let mut queue_active = false;
 
         loop {
+            // Every arm comes back through here, including those that end early
+            if config.queue_events != QueueEvents::Off {
+                let incoming = config.queue_events == QueueEvents::OutgoingAndIncoming;
+                let active = !connect_queue.is_empty() || retries.len() > 0 || match state.pending {
+                    PendingConnect::Outgoing(_) => true,
+                    PendingConnect::Incoming(_) => incoming,
+                    PendingConnect::None => incoming && deferred.is_some(),
+                };
+                if active != queue_active {
+                    queue_active = active;
+                    let event = if active { Event::QueueActive } else { Event::QueueIdle };
+                    conn_evt_tx.send(event, &loop_metrics.manager_events).await;
+                }
+            }
             select! {
                 Some(req) = req_rx.recv() => {
                     match req {
-                        Request::Connect { addr } => {
+                        Request::Connect { addr, params, fut } => {
                             if connections.lock().await.values().any(|c| c.addr == addr) {
-                                warn!("already connected: {}", addr);
-                                return;
+                                warn!("already connected: {}", Link::find(addr, &*connections.lock().await).await);
+                                continue;
+                            }
+                            tracing::debug!(parent: &conn_evt_tx.connect_span(addr), "connect requested");
+                            if let Some(fut) = fut {
+                                let waiters = connect_waiters.entry(addr).or_default();
+                                waiters.push(fut);
+                                // Answered by the connect already made for the first caller
+                                if waiters.len() > 1 {
+                                    continue;
+                                }
+                            }
+                            // Paging it again once this one is answered would only be turned down
+                            let paging = state.pending == PendingConnect::Outgoing(addr) && cancelled != Some(addr) && timed_out != Some(addr);
+                            if paging || connect_queue.iter().any(|(p, _)| *p == addr) {
+                                debug!("already connecting: {}", Link::unconnected(addr));
+                                continue;
+                            }
+                            // Starts over, rather than being paged again by the retry as well
+                            if retries.forget(addr) {
+                                arm_retry(&mut retry_alarm, &retries);
+                            }
+                            if shutting_down {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionTerminatedByLocalHost), &loop_metrics.manager_events).await;
+                            } else if let Err(reason) = (config.connect_policy)(addr) {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, reason), &loop_metrics.manager_events).await;
+                            } else if at_limit(&config, &connections).await && config.connection_limit.map(|l| l.when_full) == Some(WhenFull::Fail) {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionLimitExceeded), &loop_metrics.manager_events).await;
+                            } else if state.pending == PendingConnect::None && disconnecting_all == 0 && !at_limit(&config, &connections).await {
+                                state.pending = PendingConnect::Outgoing(addr);
+                                state.pending_params = params;
+                                let clock_offset = known_clock_offset(addr, &standby, &clock_offsets, config.clock_offset_lifetime).await;
+                                send_expecting_status(&mut hci, build_connect(addr, &params, clock_offset, packet_types, lmp_features), &unresponsive_tx).await;
+                                if let Some(timeout) = config.connect_timeout {
+                                    connect_deadline.reset(timeout);
+                                }
+                            } else {
+                                connect_queue.insert(0, (addr, params));
+                            }
+                        },
+                        Request::CancelConnect { addr, fut } => {
+                            let queued = connect_queue.iter().any(|(p, _)| *p == addr);
+                            connect_queue.retain(|(p, _)| *p != addr);
+                            let retrying = retries.forget(addr);
+                            if retrying {
+                                arm_retry(&mut retry_alarm, &retries);
+                            }
+                            let paging = state.pending == PendingConnect::Outgoing(addr);
+                            if paging {
+                                hci.send(CreateConnectionCancelBuilder { bd_addr: addr }).await;
+                                cancelled = Some(addr);
+                            }
+                            let outcome = if paging {
+                                CancelOutcome::CancelSentToController
+                            } else if state.pending == PendingConnect::Incoming(addr) {
+                                CancelOutcome::IncomingInProgress
+                            } else if queued || retrying {
+                                CancelOutcome::CancelledQueued
+                            } else if connections.lock().await.values().any(|c| c.addr == addr) {
+                                CancelOutcome::AlreadyConnected
+                            } else {
+                                CancelOutcome::NotFound
+                            };
+                            // Answered now, so a connect made before the completion of the
+                            // cancelled one comes in isn't mistaken for having joined it
+                            for fut in connect_waiters.remove(&addr).unwrap_or_default() {
+                                let _ = fut.send(connect_fail(addr, ErrorCode::UnknownConnection));
+                            }
+                            reply(fut, outcome);
+                        },
+                        Request::CancelAllConnects { fut } => {
+                            let mut addrs = Vec::new();
+                            if let PendingConnect::Outgoing(addr) = state.pending {
+                                hci.send(CreateConnectionCancelBuilder { bd_addr: addr }).await;
+                                cancelled = Some(addr);
+                                addrs.push(addr);
                             }
-                            if let PendingConnect::None = pending {
-                                pending = PendingConnect::Outgoing(addr);
-                                hci.send(build_create_connection(addr)).await;
+                            addrs.extend(connect_queue.drain(..).rev().map(|(addr, _)| addr));
+                            addrs.extend(retries.clear());
+                            retry_alarm.cancel();
+                            for addr in &addrs {
+                                for fut in connect_waiters.remove(addr).unwrap_or_default() {
+                                    let _ = fut.send(connect_fail(*addr, ErrorCode::UnknownConnection));
+                                }
+                            }
+                            reply(fut, addrs);
+                        },
+                        Request::ResolveIncoming { addr, accept } => {
+                            if deferred != Some(addr) {
+                                warn!("no deferred incoming connection from {}", Link::unconnected(addr));
+                            } else if accept {
+                                deferred = None;
+                                deferred_timeout.cancel();
+                                // What held while it was deferred may have changed since
+                                let existing = connections.lock().await.values().find(|c| c.addr == addr).map(|c| (c.handle, c.requests.clone()));
+                                let busy = state.pending != PendingConnect::None || (existing.is_none() && at_limit(&config, &connections).await);
+                                if shutting_down || disconnecting_all > 0 || !incoming_enabled || busy {
+                                    reject_incoming(&mut hci, &conn_evt_tx, addr, RejectConnectionReason::LimitedResources, &loop_metrics.manager_events).await;
+                                } else {
+                                    if let Some((stale, requests)) = existing {
+                                        supersede(&mut hci, addr, stale, requests).await;
+                                    }
+                                    accept_incoming(&mut hci, &conn_evt_tx, &mut state, addr, AcceptConnectionRequestRole::BecomeCentral, &loop_metrics.manager_events).await;
+                                }
                             } else {
-                                connect_queue.insert(0, addr);
+                                deferred = None;
+                                deferred_timeout.cancel();
+                                reject_incoming(&mut hci, &conn_evt_tx, addr, RejectConnectionReason::SecurityReasons, &loop_metrics.manager_events).await;
                             }
                         },
-                        Request::CancelConnect { addr, fut } => {
-                            connect_queue.retain(|p| *p != addr);
-                            if pending == PendingConnect::Outgoing(addr) {
+                        Request::Standby { addr, fut } => {
+                            let requests = connections.lock().await.values().find(|c| c.addr == addr).map(|c| c.requests.clone());
+                            match requests {
+                                Some(requests) => if let Err(e) = requests.send(ConnectionRequest::Standby { fut }).await {
+                                    // Went down in the meantime
+                                    cancel_request(e.0);
+                                },
+                                None => {
+                                    warn!("not connected: {}", Link::unconnected(addr));
+                                    reply(fut, Err(AclError::NotConnected));
+                                },
+                            }
+                        },
+                        Request::Suspend { fut } => {
+                            let mut links = Vec::new();
+                            for (handle, c) in connections.lock().await.iter() {
+                                suspended.push((c.addr, c.params));
+                                links.push((Link::connected(c.addr, *handle, c.shared.lock().await.role), c.requests.clone()));
+                            }
+                            // Standby waits on events this loop dispatches, and a busy link's
+                            // requests may need this loop to drain, so neither can be waited on here
+                            rt.spawn(async move {
+                                let mut done = Vec::new();
+                                for (link, requests) in links {
+                                    let (tx, rx) = oneshot::channel();
+                                    if requests.send(ConnectionRequest::Standby { fut: tx }).await.is_err() {
+                                        warn!("could not put {} in standby", link);
+                                        continue;
+                                    }
+                                    done.push(rx);
+                                }
+                                for rx in done {
+                                    let _ = rx.await;
+                                }
+                                reply(fut, ());
+                            });
+                        },
+                        Request::RevalidateQueue { fut } => {
+                            let mut refused = Vec::new();
+                            connect_queue.retain(|(addr, _)| match (config.connect_policy)(*addr) {
+                                Ok(()) => true,
+                                Err(reason) => {
+                                    refused.push((*addr, reason));
+                                    false
+                                },
+                            });
+                            // The queue is popped from the back, so this reports in the order connects were issued
+                            for (addr, reason) in refused.into_iter().rev() {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, reason), &loop_metrics.manager_events).await;
+                            }
+                            reply(fut, ());
+                        },
+                        Request::SetLocalName { name, fut } => {
+                            let mut builder = WriteLocalNameBuilder { local_name: [0; 248] };
+                            if name.len() > builder.local_name.len() {
+                                reply(fut, Err(AclError::Hci(ErrorCode::InvalidHciCommandParameters)));
+                                continue;
+                            }
+                            builder.local_name[..name.len()].copy_from_slice(name.as_bytes());
+                            let result = match hci.send(builder).await.get_status() {
+                                ErrorCode::Success => {
+                                    *loop_local_name.lock().unwrap() = name;
+                                    Ok(())
+                                },
+                                status => Err(AclError::Hci(status)),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::SetIncomingAllowed { addr, allowed, fut } => {
+                            if allowed {
+                                incoming_allowlist.insert(addr);
+                            } else {
+                                incoming_allowlist.remove(&addr);
+                            }
+                            reply(fut, ());
+                        },
+                        Request::SetIncomingEnabled { enabled, fut } => {
+                            incoming_enabled = enabled;
+                            // Turned away too, rather than left for the user to accept
+                            if let (false, Some(addr)) = (enabled, deferred) {
+                                deferred = None;
+                                deferred_timeout.cancel();
+                                reject_incoming(&mut hci, &conn_evt_tx, addr, RejectConnectionReason::LimitedResources, &loop_metrics.manager_events).await;
+                            }
+                            reply(fut, ());
+                        },
+                        Request::SetIncomingConnectionsEnabled { enabled, fut } => {
+                            let was_wanted = incoming_wanted > 0;
+                            if enabled {
+                                incoming_wanted += 1;
+                            } else if incoming_wanted == 0 {
+                                warn!("incoming connections disabled more often than enabled");
+                            } else {
+                                incoming_wanted -= 1;
+                            }
+                            if was_wanted != (incoming_wanted > 0) {
+                                set_page_scan(&mut hci, incoming_wanted > 0).await;
+                            }
+                            reply(fut, ());
+                        },
+                        Request::ReadPageScanType { fut } => {
+                            let response = hci.send(ReadPageScanTypeBuilder {}).await;
+                            let result = match response.get_status() {
+                                ErrorCode::Success => Ok(response.get_page_scan_type()),
+                                status => Err(status),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::WriteScanEnable { inquiry, page, fut } => {
+                            let result = match hci.send(WriteScanEnableBuilder { scan_enable: scan_enable(inquiry, page) }).await.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(status),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::WritePageScanType { value, fut } => {
+                            let result = match hci.send(WritePageScanTypeBuilder { page_scan_type: value }).await.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(status),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::WritePageTimeout { slots, fut } => {
+                            let result = if slots == 0 && !config.allow_no_page_timeout {
+                                Err(ErrorCode::InvalidHciCommandParameters)
+                            } else {
+                                match hci.send(WritePageTimeoutBuilder { page_timeout: slots }).await.get_status() {
+                                    ErrorCode::Success => Ok(()),
+                                    status => Err(status),
+                                }
+                            };
+                            reply(fut, result);
+                        },
+                        Request::WriteConnectionAcceptTimeout { slots, fut } => {
+                            let result = if !CONNECTION_ACCEPT_TIMEOUTS.contains(&slots) {
+                                Err(ErrorCode::InvalidHciCommandParameters)
+                            } else {
+                                match hci.send(WriteConnectionAcceptTimeoutBuilder { conn_accept_timeout: slots }).await.get_status() {
+                                    ErrorCode::Success => Ok(()),
+                                    status => Err(status),
+                                }
+                            };
+                            reply(fut, result);
+                        },
+                        Request::ReadConnectionAcceptTimeout { fut } => {
+                            let response = hci.send(ReadConnectionAcceptTimeoutBuilder {}).await;
+                            let result = match response.get_status() {
+                                ErrorCode::Success => Ok(response.get_conn_accept_timeout()),
+                                status => Err(status),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::WriteDefaultLinkPolicy { settings, fut } => {
+                            let result = if !LinkPolicy::supported_by(lmp_features).contains(settings) {
+                                Err(ErrorCode::UnsuportedFeatureOrParameterValue)
+                            } else {
+                                match hci.send(WriteDefaultLinkPolicySettingsBuilder { default_link_policy_settings: settings.bits() }).await.get_status() {
+                                    ErrorCode::Success => Ok(()),
+                                    status => Err(status),
+                                }
+                            };
+                            reply(fut, result);
+                        },
+                        Request::SetAfhChannels { channels, fut } => {
+                            let cmd = SetAfhHostChannelClassificationBuilder { afh_host_channel_classification: channels };
+                            let result = match hci.send(cmd).await.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(status),
+                            };
+                            reply(fut, result);
+                        },
+                        Request::Shutdown { reason, fut } => {
+                            if let Some(fut) = fut {
+                                shutdown_waiters.push(fut);
+                            }
+                            if shutting_down {
+                                // Already winding down, the links it disconnected go away on their own
+                                if connections.lock().await.is_empty() {
+                                    shutdown_waiters.drain(..).for_each(|fut| reply(fut, ()));
+                                }
+                                continue;
+                            }
+                            // Connects still to come are dealt with before the open links, so
+                            // none can complete after the links are torn down. A pending one the
+                            // cancel misses is dropped when it completes.
+                            shutting_down = true;
+                            for (addr, _) in connect_queue.drain(..).rev() {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionTerminatedByLocalHost), &loop_metrics.manager_events).await;
+                            }
+                            for addr in retries.clear() {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionTerminatedByLocalHost), &loop_metrics.manager_events).await;
+                            }
+                            retry_alarm.cancel();
+                            suspended.clear();
+                            if let Some(addr) = deferred.take() {
+                                deferred_timeout.cancel();
+                                reject_incoming(&mut hci, &conn_evt_tx, addr, RejectConnectionReason::LimitedResources, &loop_metrics.manager_events).await;
+                            }
+                            if let PendingConnect::Outgoing(addr) = state.pending {
                                 hci.send(CreateConnectionCancelBuilder { bd_addr: addr }).await;
                             }
-                            fut.send(()).unwrap();
+                            lazy_events.close().await;
+                            let handles: Vec<u16> = connections.lock().await.keys().copied().collect();
+                            lazy_events.release(handles.is_empty()).await;
+                            if handles.is_empty() {
+                                shutdown_waiters.drain(..).for_each(|fut| reply(fut, ()));
+                            }
+                            for handle in handles {
+                                hci.send(DisconnectBuilder { connection_handle: handle, reason }).await;
+                            }
+                        },
+                        Request::DisconnectAll { reason, fut } => {
+                            let links: Vec<_> = connections.lock().await.values().map(|c| c.requests.clone()).collect();
+                            disconnecting_all += 1;
+                            // Disconnects wait on events this loop dispatches, and a busy link's
+                            // requests on room, so neither can be waited on here
+                            let disconnected_all_tx = disconnected_all_tx.clone();
+                            rt.spawn(async move {
+                                let mut done = Vec::new();
+                                for requests in links {
+                                    let (tx, rx) = oneshot::channel();
+                                    // A link whose task is gone is already down
+                                    if requests.send(ConnectionRequest::Disconnect { reason, wait: true, fut: tx }).await.is_ok() {
+                                        done.push(rx);
+                                    }
+                                }
+                                for rx in done {
+                                    let _ = rx.await;
+                                }
+                                let _ = disconnected_all_tx.send(fut).await;
+                            });
+                        },
+                        Request::Resume if shutting_down => {},
+                        Request::Resume => {
+                            for (addr, params) in suspended.drain(..) {
+                                connect_queue.insert(0, (addr, params));
+                            }
+                            page_next(&mut hci, &mut state, &mut connect_queue, disconnecting_all, &config, &connections, &standby, &clock_offsets, packet_types, lmp_features, &unresponsive_tx, &mut connect_deadline).await;
+                        },
+                        Request::IsConnecting { addr, fut } => {
+                            let connecting = matches!(state.pending, PendingConnect::Outgoing(a) | PendingConnect::Incoming(a) if a == addr) || connect_queue.iter().any(|(p, _)| *p == addr) || retries.is_scheduled(addr);
+                            reply(fut, connecting);
+                        },
+                        Request::QueryConnection { addr, fut } => {
+                            let info = match connections.lock().await.iter().find(|(_, c)| c.addr == addr) {
+                                Some((handle, c)) => {
+                                    let shared = c.shared.lock().await;
+                                    Some(ConnectionInfo { addr, handle: *handle, role: shared.role, encrypted: shared.encrypted })
+                                },
+                                None => None,
+                            };
+                            reply(fut, info);
+                        },
+                        Request::ActiveConnections { fut } => {
+                            let mut active = Vec::new();
+                            for (handle, c) in connections.lock().await.iter() {
+                                let shared = c.shared.lock().await;
+                                active.push(ConnectionInfo { addr: c.addr, handle: *handle, role: shared.role, encrypted: shared.encrypted });
+                            }
+                            reply(fut, active);
+                        },
+                        Request::Snapshot { fut } => {
+                            let pending = match state.pending {
+                                PendingConnect::Outgoing(addr) => PendingKind::Outgoing(addr),
+                                PendingConnect::Incoming(addr) => PendingKind::Incoming(addr),
+                                PendingConnect::None => PendingKind::None,
+                            };
+                            reply(fut, ManagerSnapshot {
+                                active_connections: connections.lock().await.len(),
+                                queued_connects: connect_queue.len(),
+                                retrying_connects: retries.len(),
+                                pending,
+                            });
+                        },
+                        Request::TakeConnection { handle, fut } => {
+                            let connection = connections.lock().await.get(&handle).and_then(|c| {
+                                let channels = c.parked.take()?;
+                                Some(c.connection(channels))
+                            });
+                            reply(fut, connection);
+                        },
+                        Request::IsStandby { addr, fut } => {
+                            reply(fut, standby.lock().await.contains_key(&addr));
+                        },
+                        Request::ForEachConnection { f, fut } => {
+                            for (handle, c) in connections.lock().await.iter() {
+                                let info = {
+                                    let shared = c.shared.lock().await;
+                                    ConnectionInfo { addr: c.addr, handle: *handle, role: shared.role, encrypted: shared.encrypted }
+                                };
+                                match f(&info) {
+                                    Some(ConnectionAction::Disconnect(reason)) => {
+                                        hci.send(DisconnectBuilder { connection_handle: *handle, reason }).await;
+                                    },
+                                    Some(ConnectionAction::SwitchRole(role)) => {
+                                        // Not waited on, since the switch may need the remote features read first
+                                        let (tx, rx) = oneshot::channel();
+                                        let link = Link::connected(info.addr, info.handle, info.role);
+                                        if c.requests.try_send(ConnectionRequest::SwitchRole { role, fut: tx }).is_err() {
+                                            warn!("could not request role switch on {}", link);
+                                            continue;
+                                        }
+                                        rt.spawn(async move {
+                                            if let Ok(Err(status)) = rx.await {
+                                                warn!("role switch with {} failed: {:?}", link, status);
+                                            }
+                                        });
+                                    },
+                                    None => {},
+                                }
+                            }
+                            reply(fut, ());
+                        },
+                    }
+                }
+                Some((addr, result)) = gate_settled_rx.recv() => {
+                    let mut connection = match gated_links.remove(&addr) {
+                        Some(connection) => connection,
+                        None => continue,
+                    };
+                    let event = match result {
+                        Ok(()) => Event::ConnectSuccess(connection),
+                        Err(status) => {
+                            warn!("{} was not set up as required: {:?}", Link::find(addr, &*connections.lock().await).await, status);
+                            // Already on its way down if the link went away or could not be secured
+                            let _ = connection.disconnect_no_wait(DisconnectReason::RemoteUserTerminatedConnection).await;
+                            connect_fail(addr, status)
+                        },
+                    };
+                    send_connect_result(&conn_evt_tx, &mut connect_waiters, event, &loop_metrics.manager_events).await;
+                },
+                // The last link of a bulk disconnect is down, so connects queued meanwhile go ahead
+                Some(fut) = disconnected_all_rx.recv() => {
+                    disconnecting_all -= 1;
+                    page_next(&mut hci, &mut state, &mut connect_queue, disconnecting_all, &config, &connections, &standby, &clock_offsets, packet_types, lmp_features, &unresponsive_tx, &mut connect_deadline).await;
+                    reply(fut, ());
+                },
+                Some(()) = unresponsive_rx.recv() => {
+                    error!("controller stopped acknowledging commands");
+                    cancelled = None;
+                    // One that timed out was already answered, while an incoming one was announced
+                    match (state.pending.take(), timed_out.take()) {
+                        (PendingConnect::Outgoing(addr), None) | (PendingConnect::Incoming(addr), _) => {
+                            send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
+                        },
+                        _ => {},
+                    }
+                    for (addr, _) in connect_queue.drain(..).rev() {
+                        send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
+                    }
+                    for addr in retries.clear() {
+                        send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
+                    }
+                    retry_alarm.cancel();
+                    conn_evt_tx.send(Event::HardwareError, &loop_metrics.manager_events).await;
+                }
+                _ = connect_deadline.expired(), if timed_out.is_none() && matches!(state.pending, PendingConnect::Outgoing(_)) => {
+                    if let PendingConnect::Outgoing(addr) = state.pending {
+                        warn!("connect to {} timed out, cancelling", Link::unconnected(addr));
+                        // Stays pending until the controller completes the cancel, so that completion
+                        // isn't taken for the next connect paging the same address
+                        timed_out = Some(addr);
+                        hci.send(CreateConnectionCancelBuilder { bd_addr: addr }).await;
+                        let policy = state.pending_params.reconnect_policy.or(config.reconnect_policy);
+                        if !schedule_retry(&mut retries, &mut retry_alarm, policy, addr, state.pending_params, ErrorCode::PageTimeout) {
+                            send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::PageTimeout), &loop_metrics.manager_events).await;
+                        }
+                    }
+                }
+                // A failed connect waited out its backoff, and pages again once its turn comes
+                _ = retry_alarm.expired(), if retries.next_due().is_some() => {
+                    for (addr, params) in retries.take_due() {
+                        connect_queue.insert(0, (addr, params));
+                    }
+                    arm_retry(&mut retry_alarm, &retries);
+                    page_next(&mut hci, &mut state, &mut connect_queue, disconnecting_all, &config, &connections, &standby, &clock_offsets, packet_types, lmp_features, &unresponsive_tx, &mut connect_deadline).await;
+                }
+                // A connection went down, making room for a connect queued over the limit
+                _ = slot_freed.notified(), if !shutdown_waiters.is_empty() || (state.pending == PendingConnect::None && !connect_queue.is_empty()) => {
+                    if shutting_down {
+                        if connections.lock().await.is_empty() {
+                            shutdown_waiters.drain(..).for_each(|fut| reply(fut, ()));
                         }
+                        continue;
                     }
+                    page_next(&mut hci, &mut state, &mut connect_queue, disconnecting_all, &config, &connections, &standby, &clock_offsets, packet_types, lmp_features, &unresponsive_tx, &mut connect_deadline).await;
+                }
+                _ = deferred_timeout.expired(), if deferred.is_some() => {
+                    let addr = deferred.take().unwrap();
+                    warn!("deferred incoming connection from {} was not resolved in time", Link::unconnected(addr));
+                    reject_incoming(&mut hci, &conn_evt_tx, addr, RejectConnectionReason::SecurityReasons, &loop_metrics.manager_events).await;
                 }
                 Some(evt) = evt_rx.recv() => {
+                    let outcome = handle_event(&mut state, &evt);
                     match evt.specialize() {
+                        ConnectionComplete(evt) if evt.get_link_type() != LinkType::Acl => {
+                            // Its handle is not an ACL one, so it is left alone rather than disconnected
+                            debug!("ignoring {:?} connection complete for {}", evt.get_link_type(), Link::unconnected(evt.get_bd_addr()));
+                        },
                         ConnectionComplete(evt) => {
                             let addr = evt.get_bd_addr();
                             let status = evt.get_status();
                             let handle = evt.get_connection_handle();
-                            let role = match pending.take() {
-                                PendingConnect::Outgoing(a) if a == addr => Role::Central,
-                                PendingConnect::Incoming(a) if a == addr => Role::Peripheral,
-                                _ => panic!("No prior connection request for {}", addr),
+                            let encrypted = evt.get_encryption_enabled() == Enable::Enabled;
+                            let Completion { incoming, role, params, switch_after_accept } = match outcome {
+                                EventOutcome::Completed(completion) => completion,
+                                _ => {
+                                    warn!("no prior connection request for {}", Link { addr, handle: Some(handle), role: None });
+                                    if status == ErrorCode::Success && handle <= MAX_HANDLE {
+                                        hci.send(DisconnectBuilder {
+                                            connection_handle: handle,
+                                            reason: DisconnectReason::RemoteUserTerminatedConnection,
+                                        }).await;
+                                    }
+                                    continue;
+                                },
+                            };
+                            let connect_span = conn_evt_tx.connect_span(addr);
+                            tracing::debug!(parent: &connect_span, ?status, handle, incoming, "connection complete");
+                            let answered = !incoming && timed_out == Some(addr);
+                            if answered {
+                                timed_out = None;
+                            }
+                            if !incoming && cancelled == Some(addr) {
+                                cancelled = None;
+                            }
+                            let accept_switch = if switch_after_accept { AcceptSwitch::ReadingFeatures } else { AcceptSwitch::Settled };
+                            let auto_secure = incoming && config.auto_secure_incoming;
+                            let security = if auto_secure {
+                                SecurityLevel::Authenticated
+                            } else if incoming {
+                                (config.incoming_security_policy)(addr)
+                            } else {
+                                SecurityLevel::None
                             };
 
                             match status {
+                                // Came up before the cancel took effect, after the connect was
+                                // already reported as failed
+                                ErrorCode::Success if answered => {
+                                    if handle <= MAX_HANDLE {
+                                        warn!("{} came up after its connect timed out, disconnecting", Link::connected(addr, handle, role));
+                                        hci.send(DisconnectBuilder {
+                                            connection_handle: handle,
+                                            reason: DisconnectReason::RemoteUserTerminatedConnection,
+                                        }).await;
+                                    }
+                                },
+                                _ if answered => {},
+                                // Nothing can be done over a handle the controller can't have
+                                // assigned, so it is kept out of the connection map
+                                ErrorCode::Success if handle > MAX_HANDLE => {
+                                    warn!("{} came up with out of range handle", Link::connected(addr, handle, role));
+                                    send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::UnspecifiedError), &loop_metrics.manager_events).await;
+                                },
+                                // Raced with shutdown, so the link is dropped rather than kept
+                                ErrorCode::Success if shutting_down => {
+                                    warn!("{} came up during shutdown, disconnecting", Link::connected(addr, handle, role));
+                                    hci.send(DisconnectBuilder {
+                                        connection_handle: handle,
+                                        reason: DisconnectReason::RemoteDeviceTerminatedConnectionPowerOff,
+                                    }).await;
+                                    send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionTerminatedByLocalHost), &loop_metrics.manager_events).await;
+                                },
                                 ErrorCode::Success => {
-                                    let mut core_conn = dispatch.register(handle, Bluetooth::Classic).await;
-                                    let shared = Arc::new(Mutex::new(ConnectionShared { role }));
-                                    let (evt_tx, evt_rx) = channel(10);
-                                    let (req_tx, req_rx) = channel(10);
-                                    let connection = Connection {
-                                        addr,
-                                        shared: shared.clone(),
-                                        rx: core_conn.rx.take().unwrap(),
-                                        tx: core_conn.tx.take().unwrap(),
-                                        requests: req_tx,
-                                        evt_rx,
-                                    };
-                                    let connection_internal = ConnectionInternal {
-                                        addr,
-                                        shared,
-                                        hci_evt_tx: core_conn.evt_tx.clone(),
+                                    retries.forget(addr);
+                                    // The controller gave out a handle it never reported down, so
+                                    // the link that had it is gone and is failed as lost
+                                    let stale = connections.lock().await.remove(&handle);
+                                    if let Some(stale) = stale {
+                                        warn!("{} was not reported down before its handle was reused", Link::connected(stale.addr, handle, stale.shared.lock().await.role));
+                                        let lost = DisconnectionCompleteBuilder { status: ErrorCode::Success, connection_handle: handle, reason: ErrorCode::ConnectionTimeout };
+                                        let _ = stale.hci_evt_tx.send(lost.build().into()).await;
+                                    }
+                                    // A link the dispatch can't carry data for is of no use, so it is
+                                    // taken down rather than handed out
+                                    let core_conn = match dispatch.register(handle, Bluetooth::Classic).await {
+                                        Some(core_conn) if core_conn.rx.is_some() && core_conn.tx.is_some() => Some(core_conn),
+                                        registered => {
+                                            warn!("{} could not be registered for data, disconnecting", Link::connected(addr, handle, role));
+                                            if let Some(core_conn) = registered {
+                                                core_conn.close().await;
+                                            }
+                                            hci.send(DisconnectBuilder {
+                                                connection_handle: handle,
+                                                reason: DisconnectReason::RemoteUserTerminatedConnection,
+                                            }).await;
+                                            send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::ConnectionFailedEstablishment), &loop_metrics.manager_events).await;
+                                            None
+                                        },
                                     };
+                                    if let Some(core_conn) = core_conn {
+                                        config.stats_sink.established(ConnectionEstablished {
+                                            addr,
+                                            handle,
+                                            role,
+                                            link_type: evt.get_link_type(),
+                                            encryption_enabled: encrypted,
+                                        });
+                                        let hints = standby.lock().await.remove(&addr).unwrap_or_default();
+                                        let encrypted_flag = Arc::new(AtomicBool::new(encrypted));
+                                        let shared = Arc::new(Mutex::new(ConnectionShared {
+                                            role,
+                                            encrypted,
+                                            encrypted_flag: encrypted_flag.clone(),
+                                            remote_features: hints.remote_features,
+                                            local_features: lmp_features,
+                                            local_commands,
+                                            recent_events: VecDeque::new(),
+                                            context: None,
+                                            mode: Mode::Active,
+                                            rssi: None,
+                                            supervision_timeout: None,
+                                        }));
+                                        let (evt_tx, evt_rx) = channel(capacities.connection_events);
+                                        let (req_tx, req_rx) = channel(capacities.connection_requests);
+                                        let (outbound_tx, outbound) = outbound_queues();
+                                        let traffic = Arc::new(TrafficCounters::default());
+                                        let parked = ParkedChannels::default();
+                                        let connection_internal = ConnectionInternal {
+                                            addr,
+                                            handle,
+                                            shared: shared.clone(),
+                                            encrypted: encrypted_flag,
+                                            hci_evt_tx: core_conn.evt_tx.clone(),
+                                            requests: req_tx,
+                                            outbound: outbound_tx,
+                                            tx_limit: core_conn.tx_limit.clone(),
+                                            parked: parked.clone(),
+                                            traffic: traffic.clone(),
+                                            params,
+                                        };
+                                        // Inbound data goes through the connection's task, which counts it
+                                        let (inbound_tx, rx) = channel(10);
+                                        let connection = connection_internal.connection(ConnectionChannels { rx, evt_rx });
+                                        let role_gate = match params.require_role {
+                                            Some(required) if required != role => Some(RoleGate { role: required, settled: gate_settled_tx.clone() }),
+                                            _ => None,
+                                        };
+                                        let security_gate = if auto_secure { Some(gate_settled_tx.clone()) } else { None };
+                                        let gated = role_gate.is_some() || security_gate.is_some();
 
-                                    assert!(connections.lock().await.insert(handle, connection_internal).is_none());
-                                    rt.spawn(run_connection(handle, evt_tx, req_rx, core_conn, connections.clone(), hci.clone()));
-                                    conn_evt_tx.send(Event::ConnectSuccess(connection)).await.unwrap();
+                                        // Any link that had the handle before was dropped above
+                                        connections.lock().await.insert(handle, connection_internal);
+                                        rt.spawn(run_connection(
+                                            handle, addr, shared, evt_tx, req_rx, outbound, core_conn, connections.clone(), standby.clone(),
+                                            hci.clone(), config.clone(), unresponsive_tx.clone(), loop_metrics.clone(), accept_switch, security,
+                                            lazy_events.clone(), loop_bandwidth.clone(), slot_freed.clone(), clock_offsets.clone(), traffic,
+                                            inbound_tx, role_gate, security_gate, params, relink_tx.clone(), parked,
+                                        ).instrument(info_span!(parent: &connect_span, "acl_connection", addr = %addr, handle)));
+                                        if let Some(timeout) = params.link_supervision_timeout {
+                                            let status = hci.send(WriteLinkSupervisionTimeoutBuilder {
+                                                connection_handle: handle,
+                                                link_supervision_timeout: timeout,
+                                            }).await.get_status();
+                                            if status != ErrorCode::Success {
+                                                warn!("could not set supervision timeout for {}: {:?}", Link::connected(addr, handle, role), status);
+                                            }
+                                        }
+                                        if gated {
+                                            gated_links.insert(addr, connection);
+                                        } else {
+                                            send_connect_result(&conn_evt_tx, &mut connect_waiters, Event::ConnectSuccess(connection), &loop_metrics.manager_events).await;
+                                        }
+                                    }
                                 },
-                                _ => conn_evt_tx.send(Event::ConnectFail { addr, reason: status }).await.unwrap(),
+                                // Queued connects to the same peer would only be turned down again
+                                _ if FailureClass::of(status) == FailureClass::Refused => {
+                                    connect_queue.retain(|(p, _)| *p != addr);
+                                    retries.forget(addr);
+                                    send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, status), &loop_metrics.manager_events).await;
+                                },
+                                // A connect for the same address issued after this attempt was
+                                // cancelled supersedes it, so the attempt is retried rather than failed
+                                _ if connect_queue.iter().any(|(p, _)| *p == addr) => {},
+                                _ if !incoming && schedule_retry(&mut retries, &mut retry_alarm, params.reconnect_policy.or(config.reconnect_policy), addr, params, status) => {},
+                                _ => send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, status), &loop_metrics.manager_events).await,
                             }
+
+                            page_next(&mut hci, &mut state, &mut connect_queue, disconnecting_all, &config, &connections, &standby, &clock_offsets, packet_types, lmp_features, &unresponsive_tx, &mut connect_deadline).await;
                         },
                         EventChild::ConnectionRequest(evt) => {
                             let addr = evt.get_bd_addr();
-                            pending = PendingConnect::Incoming(addr);
-                            if connections.lock().await.values().any(|c| c.addr == addr) {
-                                hci.send(RejectConnectionRequestBuilder {
-                                    bd_addr: addr,
-                                    reason: RejectConnectionReason::UnacceptableBdAddr
-                                }).await;
+                            let existing = connections.lock().await.values().find(|c| c.addr == addr).map(|c| (c.handle, c.requests.clone()));
+                            let decision = if shutting_down || disconnecting_all > 0 || !incoming_enabled {
+                                AcceptDecision::Reject(RejectConnectionReason::LimitedResources)
+                            } else if !incoming_allowlist.is_empty() && !incoming_allowlist.contains(&addr) {
+                                AcceptDecision::Reject(RejectConnectionReason::UnacceptableBdAddr)
+                            } else if let (Some(_), DuplicateIncoming::Reject(reason)) = (&existing, config.duplicate_incoming) {
+                                AcceptDecision::Reject(reason)
+                            } else if existing.is_none() && at_limit(&config, &connections).await {
+                                AcceptDecision::Reject(RejectConnectionReason::LimitedResources)
+                            } else if deferred.is_some() {
+                                // Only one incoming connection can wait on a decision at a time
+                                AcceptDecision::Reject(RejectConnectionReason::LimitedResources)
+                            } else if state.pending != PendingConnect::None {
+                                // Only one connection is set up at a time, and another already is
+                                AcceptDecision::Reject(RejectConnectionReason::LimitedResources)
+                            } else {
+                                (config.accept_policy)(addr)
+                            };
+                            // A deferred one only replaces the link once it is accepted
+                            let accepted = !matches!(decision, AcceptDecision::Reject(_) | AcceptDecision::Defer);
+                            if let (true, Some((stale, requests))) = (accepted, existing) {
+                                supersede(&mut hci, addr, stale, requests).await;
+                            }
+                            match decision {
+                                AcceptDecision::AcceptAsCentral => {
+                                    accept_incoming(&mut hci, &conn_evt_tx, &mut state, addr, AcceptConnectionRequestRole::BecomeCentral, &loop_metrics.manager_events).await;
+                                },
+                                AcceptDecision::AcceptAsPeripheral => {
+                                    accept_incoming(&mut hci, &conn_evt_tx, &mut state, addr, AcceptConnectionRequestRole::RemainPeripheral, &loop_metrics.manager_events).await;
+                                },
+                                AcceptDecision::AcceptThenSwitchIfSupported => {
+                                    accept_incoming(&mut hci, &conn_evt_tx, &mut state, addr, AcceptConnectionRequestRole::RemainPeripheral, &loop_metrics.manager_events).await;
+                                    state.pending_accept_switch = true;
+                                },
+                                AcceptDecision::Reject(reason) => {
+                                    reject_incoming(&mut hci, &conn_evt_tx, addr, reason, &loop_metrics.manager_events).await;
+                                },
+                                AcceptDecision::Defer => {
+                                    deferred = Some(addr);
+                                    deferred_timeout.reset(config.incoming_accept_timeout);
+                                },
+                            }
+                        },
+                        AuthenticationComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        ModeChange(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        EncryptionChange(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        SynchronousConnectionComplete(e) => dispatch_to_addr(e.get_bd_addr(), &connections, evt, &loop_metrics.hci_events).await,
+                        ReadRemoteExtendedFeaturesComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        ReadRemoteSupportedFeaturesComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        ReadRemoteVersionInformationComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        QosSetupComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        ReadClockOffsetComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt, &loop_metrics.hci_events).await,
+                        // A switch for the pending connection was taken by the state
+                        RoleChange(e) => if let EventOutcome::Other = outcome {
+                            dispatch_to_addr(e.get_bd_addr(), &connections, evt, &loop_metrics.hci_events).await;
+                        },
+                        RemoteNameRequestComplete(e) => dispatch_to_addr(e.get_bd_addr(), &connections, evt, &loop_metrics.hci_events).await,
+                        LinkKeyRequest(evt) => {
+                            let bd_addr = evt.get_bd_addr();
+                            match config.key_store.get(bd_addr) {
+                                Some(link_key) => {
+                                    hci.send(LinkKeyRequestReplyBuilder { bd_addr, link_key }).await;
+                                },
+                                None => {
+                                    hci.send(LinkKeyRequestNegativeReplyBuilder { bd_addr }).await;
+                                },
+                            }
+                        },
+                        LinkKeyNotification(evt) => {
+                            let addr = evt.get_bd_addr();
+                            config.key_store.store(addr, *evt.get_link_key(), evt.get_key_type());
+                            // Don't hold up other events if the key owner is not keeping up
+                            if link_key_tx.try_send((addr, *evt.get_link_key(), evt.get_key_type())).is_err() {
+                                warn!("dropped link key notification for {}", Link::find(addr, &*connections.lock().await).await);
+                            }
+                        },
+                        IoCapabilityRequest(evt) => {
+                            let bd_addr = evt.get_bd_addr();
+                            let io_capability = config.pairing_delegate.io_capability_request(bd_addr);
+                            // Nothing can be compared or entered without input or output
+                            let authentication_requirements = match io_capability {
+                                IoCapability::NoInputNoOutput => AuthenticationRequirements::GeneralBonding,
+                                _ => AuthenticationRequirements::GeneralBondingMitmProtection,
+                            };
+                            hci.send(IoCapabilityRequestReplyBuilder {
+                                bd_addr,
+                                io_capability,
+                                oob_present: OobDataPresent::NotPresent,
+                                authentication_requirements,
+                            }).await;
+                        },
+                        UserConfirmationRequest(evt) => {
+                            let bd_addr = evt.get_bd_addr();
+                            if config.pairing_delegate.confirm_passkey(bd_addr, evt.get_numeric_value()) {
+                                hci.send(UserConfirmationRequestReplyBuilder { bd_addr }).await;
                             } else {
-                                hci.send(AcceptConnectionRequestBuilder {
-                                    bd_addr: addr,
-                                    role: AcceptConnectionRequestRole::BecomeCentral
-                                }).await;
+                                hci.send(UserConfirmationRequestNegativeReplyBuilder { bd_addr }).await;
+                            }
+                        },
+                        UserPasskeyRequest(evt) => {
+                            let bd_addr = evt.get_bd_addr();
+                            match config.pairing_delegate.passkey_request(bd_addr) {
+                                Some(numeric_value) => {
+                                    hci.send(UserPasskeyRequestReplyBuilder { bd_addr, numeric_value }).await;
+                                },
+                                None => {
+                                    hci.send(UserPasskeyRequestNegativeReplyBuilder { bd_addr }).await;
+                                },
+                            }
+                        },
+                        // The controller lost its links with its state, so none of them can be carried on.
+                        // They are dropped from the map right away, as the handles may be given out again.
+                        HardwareError(evt) => {
+                            error!("controller hardware error {:#04x}, dropping every link", evt.get_hardware_code());
+                            let links: Vec<_> = connections.lock().await.drain().map(|(_, c)| c.requests).collect();
+                            for requests in links {
+                                let _ = requests.send(ConnectionRequest::ControllerReset).await;
+                            }
+                            cancelled = None;
+                            match (state.pending.take(), timed_out.take()) {
+                                (PendingConnect::Outgoing(addr), None) | (PendingConnect::Incoming(addr), _) => {
+                                    send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
+                                },
+                                _ => {},
+                            }
+                            state = AclState::default();
+                            deferred = None;
+                            deferred_timeout.cancel();
+                            for (addr, _) in connect_queue.drain(..).rev() {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
                             }
+                            for addr in retries.clear() {
+                                send_connect_result(&conn_evt_tx, &mut connect_waiters, connect_fail(addr, ErrorCode::HardwareFailure), &loop_metrics.manager_events).await;
+                            }
+                            retry_alarm.cancel();
+                            conn_evt_tx.send(Event::ControllerReset, &loop_metrics.manager_events).await;
                         },
-                        AuthenticationComplete(e) => dispatch_to(e.get_connection_handle(), &connections, evt).await,
-                        _ => unimplemented!(),
+                        _ => warn!("unhandled event {:?}", evt.get_event_code()),
                     }
                 }
             }
         }
-    });
+    });
+
+    AclManager {
+        req_tx,
+        evt_rx: Arc::new(Mutex::new(conn_evt_rx)),
+        link_key_rx: Arc::new(Mutex::new(Some(link_key_rx))),
+        event_info,
+        metrics,
+        local_name,
+        codecs,
+        bandwidth,
+        local_commands,
+        lmp_features,
+    }
+}
+
+/// Disconnect the link `addr` already has, as the new connection it asked for was accepted
+async fn supersede(
+    hci: &mut CommandSender,
+    addr: Address,
+    stale: u16,
+    requests: Sender<ConnectionRequest>,
+) {
+    info!("{} asked to connect again, replacing its link", Link::unconnected(addr));
+    let cmd = DisconnectBuilder {
+        connection_handle: stale,
+        reason: DisconnectReason::RemoteUserTerminatedConnection,
+    };
+    let disconnect_sent = hci.send(cmd).await.get_status() == ErrorCode::Success;
+    let _ = requests.send(ConnectionRequest::Superseded { disconnect_sent }).await;
+}
+
+/// Accept an incoming connection as the one being set up, and tell the event receiver it is on
+/// its way
+async fn accept_incoming(
+    hci: &mut CommandSender,
+    tx: &EventSinks,
+    state: &mut AclState,
+    addr: Address,
+    role: AcceptConnectionRequestRole,
+    counters: &ChannelCounters,
+) {
+    state.pending = PendingConnect::Incoming(addr);
+    state.pending_accept_switch = false;
+    hci.send(build_accept_connection(addr, role)).await;
+    tracing::debug!(parent: &tx.connect_span(addr), ?role, "incoming accepted");
+    tx.send(Event::IncomingConnecting { addr }, counters).await;
+}
+
+/// Turn down an incoming connection, and tell the event receiver it was
+async fn reject_incoming(
+    hci: &mut CommandSender,
+    tx: &EventSinks,
+    addr: Address,
+    reason: RejectConnectionReason,
+    counters: &ChannelCounters,
+) {
+    hci.send(build_reject_connection(addr, reason)).await;
+    tx.send(Event::IncomingRejected { addr, reason }, counters).await;
+}
+
+/// Hand a connect result to the callers waiting on that connect, or to the event stream if
+/// nobody is, or they have all since given up. Only one of them can have the connection, so
+/// the rest are told it already exists. Subscribers hear of the result either way.
+async fn send_connect_result(
+    tx: &EventSinks,
+    waiters: &mut HashMap<Address, Vec<oneshot::Sender<Event>>>,
+    event: Event,
+    counters: &ChannelCounters,
+) {
+    let (addr, others) = match &event {
+        Event::ConnectSuccess(connection) => (connection.addr, ErrorCode::ConnectionAlreadyExists),
+        Event::ConnectFail { addr, reason, .. } => (*addr, *reason),
+        _ => return tx.send(event, counters).await,
+    };
+    let span = tx.end_connect_span(addr);
+    match &event {
+        Event::ConnectSuccess(connection) => {
+            tracing::info!(parent: &span, handle = connection.handle, "connected")
+        }
+        Event::ConnectFail { reason, .. } => {
+            tracing::warn!(parent: &span, ?reason, "connect failed")
+        }
+        _ => {}
+    }
+    tx.publish(&event);
+    let mut event = Some(event);
+    for waiter in waiters.remove(&addr).unwrap_or_default() {
+        match event.take() {
+            Some(next) => event = waiter.send(next).err(),
+            None => {
+                let _ = waiter.send(connect_fail(addr, others));
+            }
+        }
+    }
+    if let Some(event) = event {
+        send_tracked(&tx.tx, event, counters).await;
+    }
+}
+
+// Connection handles are 12 bits, and the top of that range is reserved
+const MAX_HANDLE: u16 = 0x0eff;
+
+// Below the HCI layer's own command timeout, so the manager can report a wedged controller
+// before that gives up on it
+const COMMAND_STATUS_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Send a command acknowledged with a command status, flagging the controller as unresponsive
+/// if the status does not arrive in time
+async fn send_expecting_status<T: Into<CommandPacket> + CommandExpectations>(
+    hci: &mut CommandSender,
+    cmd: T,
+    unresponsive: &Sender<()>,
+) -> Option<T::ResponseType> {
+    match timeout(COMMAND_STATUS_TIMEOUT, hci.send(cmd)).await {
+        Ok(response) => Some(response),
+        Err(_) => {
+            // Fine to drop if full, the controller has already been flagged
+            let _ = unresponsive.try_send(());
+            None
+        }
+    }
+}
+
+// Longest automatic flush timeout the spec allows, in baseband slots
+const MAX_FLUSH_TIMEOUT: u16 = 0x07ff;
+
+// How many times a disconnect is sent before its failure is reported, and how long to wait
+// between attempts for whatever is keeping the controller busy to finish
+const DISCONNECT_ATTEMPTS: usize = 3;
+const DISCONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+struct DisconnectAttempt {
+    reason: DisconnectReason,
+    attempts: usize,
+    // Everyone who asked for it, including those who asked while it was being retried
+    waiters: Vec<DisconnectWaiter>,
+}
+
+struct DisconnectWaiter {
+    fut: oneshot::Sender<Result<(), AclError>>,
+    // Answered once the link is down, rather than once the controller takes the disconnect
+    until_down: bool,
+}
+
+impl DisconnectAttempt {
+    /// Answer those who asked for the disconnect, handing back the ones to be answered once the
+    /// link is down if the controller took it
+    fn resolve(self, result: Result<(), AclError>) -> Vec<oneshot::Sender<Result<(), AclError>>> {
+        let mut until_down = Vec::new();
+        for waiter in self.waiters {
+            if waiter.until_down && result.is_ok() {
+                until_down.push(waiter.fut);
+            } else {
+                let _ = waiter.fut.send(result);
+            }
+        }
+        until_down
+    }
+}
+
+/// Where a disconnect stands after being sent
+enum DisconnectProgress {
+    /// Turned away for now, to be sent again
+    Retry(DisconnectAttempt),
+    /// Answered with this outcome. If taken, those waiting on the link to go down are handed
+    /// back.
+    Done(Result<Vec<oneshot::Sender<Result<(), AclError>>>, AclError>),
+}
+
+/// Send the disconnect, resolving it unless the controller is only temporarily unable to
+/// accept it, in which case it is handed back to be retried
+async fn try_disconnect(
+    hci: &mut CommandSender,
+    handle: u16,
+    mut attempt: DisconnectAttempt,
+    unresponsive: &Sender<()>,
+) -> DisconnectProgress {
+    let cmd = DisconnectBuilder { connection_handle: handle, reason: attempt.reason };
+    let status = match send_expecting_status(hci, cmd, unresponsive).await {
+        Some(evt) => evt.get_status(),
+        None => ErrorCode::HardwareFailure,
+    };
+    attempt.attempts += 1;
+    let result = match status {
+        ErrorCode::Success => Ok(()),
+        // Mode changes and role switches in progress make the controller refuse a disconnect
+        ErrorCode::CommandDisallowed | ErrorCode::ControllerBusy
+            if attempt.attempts < DISCONNECT_ATTEMPTS =>
+        {
+            return DisconnectProgress::Retry(attempt)
+        }
+        _ => Err(AclError::Hci(status)),
+    };
+    let until_down = attempt.resolve(result);
+    DisconnectProgress::Done(result.map(|()| until_down))
+}
+
+/// Turn page scan on or off, leaving inquiry scan as it is
+async fn set_page_scan(hci: &mut CommandSender, enabled: bool) {
+    let response = hci.send(ReadScanEnableBuilder {}).await;
+    if response.get_status() != ErrorCode::Success {
+        warn!("could not read scan enable: {:?}", response.get_status());
+        return;
+    }
+    let inquiry = matches!(
+        response.get_scan_enable(),
+        ScanEnable::InquiryScanOnly | ScanEnable::InquiryAndPageScan
+    );
+    let scan_enable = scan_enable(inquiry, enabled);
+    let status = hci.send(WriteScanEnableBuilder { scan_enable }).await.get_status();
+    if status != ErrorCode::Success {
+        warn!("could not write scan enable: {:?}", status);
+    }
+}
+
+fn scan_enable(inquiry: bool, page: bool) -> ScanEnable {
+    match (inquiry, page) {
+        (false, false) => ScanEnable::NoScans,
+        (false, true) => ScanEnable::PageScanOnly,
+        (true, false) => ScanEnable::InquiryScanOnly,
+        (true, true) => ScanEnable::InquiryAndPageScan,
+    }
+}
+
+const DEFAULT_PACKET_TYPES: u16 = 0x4408 /* DM 1,3,5 */ | 0x8810; /*DH 1,3,5 */
+
+/// Whether `commands`, as Read Local Supported Commands reports them, include `opcode`
+fn command_supported(commands: &[u8; 64], opcode: OpCode) -> bool {
+    match OpCodeIndex::try_from(opcode) {
+        // Indexes are encoded as octet * 10 + bit
+        Ok(index) => {
+            let index = index as usize;
+            commands[index / 10] & (1 << (index % 10)) != 0
+        }
+        Err(_) => false,
+    }
+}
+
+/// Narrow `requested` to the ACL packet types the local controller supports. The basic rate
+/// bits allow a packet type, while the EDR bits forbid one, so EDR types are ruled out by
+/// setting their bits.
+fn supported_packet_types(requested: u16, lmp_features: u64) -> u16 {
+    let has = |bit: u32| lmp_features & (1 << bit) != 0;
+    let mut types = requested;
+    if !has(0) {
+        types &= !0x0c00; // DM3, DH3
+    }
+    if !has(1) {
+        types &= !0xc000; // DM5, DH5
+    }
+    if !has(25) {
+        types |= 0x1102; // 2-DH1, 2-DH3, 2-DH5
+    }
+    if !has(26) {
+        types |= 0x2204; // 3-DH1, 3-DH3, 3-DH5
+    }
+    if !has(39) {
+        types |= 0x0300; // 2-DH3, 3-DH3
+    }
+    if !has(40) {
+        types |= 0x3000; // 2-DH5, 3-DH5
+    }
+    types
+}
+
+fn build_create_connection(
+    bd_addr: Address,
+    clock_offset: Option<u16>,
+    packet_type: u16,
+) -> CreateConnectionBuilder {
+    CreateConnectionBuilder {
+        bd_addr,
+        packet_type,
+        page_scan_repetition_mode: PageScanRepetitionMode::R1,
+        clock_offset: clock_offset.unwrap_or(0),
+        clock_offset_valid: match clock_offset {
+            Some(_) => ClockOffsetValid::Valid,
+            None => ClockOffsetValid::Invalid,
+        },
+        allow_role_switch: CreateConnectionRoleSwitch::AllowRoleSwitch,
+    }
+}
+
+// Create Connection for a connect with the specified options
+fn build_connect(
+    bd_addr: Address,
+    params: &ConnectParams,
+    clock_offset: Option<u16>,
+    packet_types: u16,
+    lmp_features: u64,
+) -> CreateConnectionBuilder {
+    let packet_type =
+        params.packet_types.map_or(packet_types, |t| supported_packet_types(t, lmp_features));
+    let allow_role_switch = match (params.require_role, params.allow_role_switch) {
+        // The peer is kept from taking central away, or given the chance to spare a switch
+        (Some(Role::Central), _) | (None, false) => CreateConnectionRoleSwitch::RemainCentral,
+        (Some(Role::Peripheral), _) | (None, true) => CreateConnectionRoleSwitch::AllowRoleSwitch,
+    };
+    CreateConnectionBuilder {
+        page_scan_repetition_mode: params.page_scan_repetition_mode,
+        allow_role_switch,
+        ..build_create_connection(bd_addr, params.clock_offset.or(clock_offset), packet_type)
+    }
+}
+
+fn build_accept_connection(
+    bd_addr: Address,
+    role: AcceptConnectionRequestRole,
+) -> AcceptConnectionRequestBuilder {
+    AcceptConnectionRequestBuilder { bd_addr, role }
+}
+
+fn build_reject_connection(
+    bd_addr: Address,
+    reason: RejectConnectionReason,
+) -> RejectConnectionRequestBuilder {
+    RejectConnectionRequestBuilder { bd_addr, reason }
+}
+
+/// Whether the connection limit, if any, leaves no room for another link
+async fn at_limit(
+    config: &AclConfig,
+    connections: &Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
+) -> bool {
+    match config.connection_limit {
+        Some(limit) => connections.lock().await.len() >= limit.max,
+        None => false,
+    }
+}
+
+/// Take the queued connect the strategy picks to page next
+async fn next_queued(
+    queue: &mut Vec<(Address, ConnectParams)>,
+    standby: &Arc<Mutex<HashMap<Address, ReconnectHints>>>,
+    strategy: &dyn NextConnectionStrategy,
+) -> Option<(Address, ConnectParams)> {
+    if queue.is_empty() {
+        return None;
+    }
+    let standby = standby.lock().await;
+    // The queue is newest first
+    let candidates: Vec<QueuedConnect> = queue
+        .iter()
+        .rev()
+        .map(|(addr, params)| QueuedConnect {
+            addr: *addr,
+            params: *params,
+            clock_offset_known: standby.get(addr).and_then(|h| h.clock_offset).is_some(),
+        })
+        .collect();
+    let mut picked = strategy.pick(&candidates);
+    if picked >= candidates.len() {
+        error!(
+            "strategy picked {} of {} queued connects, paging the first",
+            picked,
+            candidates.len()
+        );
+        picked = 0;
+    }
+    Some(queue.remove(queue.len() - 1 - picked))
+}
+
+/// Page the next queued connect, unless a connect is already pending, links are being
+/// disconnected or there is no room for another
+#[allow(clippy::too_many_arguments)]
+async fn page_next(
+    hci: &mut CommandSender,
+    state: &mut AclState,
+    queue: &mut Vec<(Address, ConnectParams)>,
+    disconnecting_all: usize,
+    config: &AclConfig,
+    connections: &Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
+    standby: &Arc<Mutex<HashMap<Address, ReconnectHints>>>,
+    clock_offsets: &ClockOffsets,
+    packet_types: u16,
+    lmp_features: u64,
+    unresponsive: &Sender<()>,
+    connect_deadline: &mut Alarm,
+) {
+    if state.pending != PendingConnect::None
+        || disconnecting_all > 0
+        || at_limit(config, connections).await
+    {
+        return;
+    }
+    if let Some((next, params)) =
+        next_queued(queue, standby, &*config.next_connection_strategy).await
+    {
+        state.pending = PendingConnect::Outgoing(next);
+        state.pending_params = params;
+        let clock_offset =
+            known_clock_offset(next, standby, clock_offsets, config.clock_offset_lifetime).await;
+        let cmd = build_connect(next, &params, clock_offset, packet_types, lmp_features);
+        send_expecting_status(hci, cmd, unresponsive).await;
+        if let Some(timeout) = config.connect_timeout {
+            connect_deadline.reset(timeout);
+        }
+    }
+}
+
+async fn dispatch_to(
+    handle: u16,
+    connections: &Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
+    event: EventPacket,
+    counters: &ChannelCounters,
+) {
+    if let Some(c) = connections.lock().await.get_mut(&handle) {
+        send_tracked(&c.hci_evt_tx, event, counters).await;
+    }
+}
+
+async fn dispatch_to_addr(
+    addr: Address,
+    connections: &Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
+    event: EventPacket,
+    counters: &ChannelCounters,
+) {
+    if let Some(c) = connections.lock().await.values_mut().find(|c| c.addr == addr) {
+        send_tracked(&c.hci_evt_tx, event, counters).await;
+    }
+}
+
+// The name field is fixed size, padded with NULs after a name that may not be valid UTF-8
+fn remote_name(name: &[u8; 248]) -> String {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+// LMP feature page 0, byte 0 bit 5
+const ROLE_SWITCH_FEATURE: u64 = 1 << 5;
+
+// Fewest channels adaptive frequency hopping may be left with
+const MIN_AFH_CHANNELS: u32 = 20;
+
+/// How many channels an AFH channel classification leaves usable. The top bit stands for no
+/// channel, and is reserved.
+fn afh_channels(channels: &[u8; 10]) -> u32 {
+    let reserved = channels[9] & 0x80;
+    channels.iter().map(|byte| byte.count_ones()).sum::<u32>() - reserved.count_ones()
+}
+
+// Hold intervals HCI Hold Mode takes, in baseband slots
+const HOLD_INTERVALS: std::ops::RangeInclusive<u16> = 0x0002..=0xfffe;
+
+// Connection accept timeouts HCI Write Connection Accept Timeout takes, in baseband slots
+const CONNECTION_ACCEPT_TIMEOUTS: std::ops::RangeInclusive<u16> = 0x0001..=0xb540;
+
+async fn switch_role(
+    hci: &mut CommandSender,
+    bd_addr: Address,
+    role: Role,
+) -> Result<(), ErrorCode> {
+    match hci.send(SwitchRoleBuilder { bd_addr, role }).await.get_status() {
+        ErrorCode::Success => Ok(()),
+        status => Err(status),
+    }
+}
+
+/// Hold a role switch the controller took until its Role Change arrives, or answer it now if
+/// it was refused
+fn hold_switch(
+    result: Result<(), ErrorCode>,
+    fut: oneshot::Sender<Result<(), AclError>>,
+    switching: &mut Option<oneshot::Sender<Result<(), AclError>>>,
+) {
+    match result {
+        Ok(()) => *switching = Some(fut),
+        Err(status) => {
+            let _ = fut.send(Err(AclError::Hci(status)));
+        }
+    }
+}
 
-    AclManager { req_tx, evt_rx: Arc::new(Mutex::new(conn_evt_rx)) }
+async fn switch_role_if_supported(
+    hci: &mut CommandSender,
+    link: Link,
+    role: Role,
+    features: u64,
+) -> Result<(), ErrorCode> {
+    if features & ROLE_SWITCH_FEATURE == 0 {
+        warn!("{} does not support role switch", link);
+        return Err(ErrorCode::UnsupportedRemoteOrLmpFeature);
+    }
+    switch_role(hci, link.addr, role).await
 }
 
-fn build_create_connection(bd_addr: Address) -> CreateConnectionBuilder {
-    CreateConnectionBuilder {
-        bd_addr,
-        packet_type: 0x4408 /* DM 1,3,5 */ | 0x8810, /*DH 1,3,5 */
-        page_scan_repetition_mode: PageScanRepetitionMode::R1,
-        clock_offset: 0,
-        clock_offset_valid: ClockOffsetValid::Invalid,
-        allow_role_switch: CreateConnectionRoleSwitch::AllowRoleSwitch,
+/// The failed contact counter and RSSI of the link, if both could be read
+async fn read_link_quality(hci: &mut CommandSender, handle: u16) -> Option<(u16, i8)> {
+    let failed = hci.send(ReadFailedContactCounterBuilder { connection_handle: handle }).await;
+    if failed.get_status() != ErrorCode::Success {
+        return None;
+    }
+    let rssi = hci.send(ReadRssiBuilder { connection_handle: handle }).await;
+    if rssi.get_status() != ErrorCode::Success {
+        return None;
     }
+    Some((failed.get_failed_contact_counter(), rssi.get_rssi() as i8))
 }
 
-async fn dispatch_to(
+async fn enter_standby(
+    hci: &mut CommandSender,
     handle: u16,
-    connections: &Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
-    event: EventPacket,
+    addr: Address,
+    clock_offset: Option<u16>,
+    shared: &Arc<Mutex<ConnectionShared>>,
+    standby: &Arc<Mutex<HashMap<Address, ReconnectHints>>>,
 ) {
-    if let Some(c) = connections.lock().await.get_mut(&handle) {
-        c.hci_evt_tx.send(event).await.unwrap();
+    let hints =
+        ReconnectHints { clock_offset, remote_features: shared.lock().await.remote_features };
+    standby.lock().await.insert(addr, hints);
+    hci.send(DisconnectBuilder {
+        connection_handle: handle,
+        reason: DisconnectReason::RemoteUserTerminatedConnection,
+    })
+    .await;
+}
+
+/// Take the next step towards `level` from where the link is, returning `Done` once it is
+/// reached
+async fn step_security(
+    hci: &mut CommandSender,
+    handle: u16,
+    level: SecurityLevel,
+    authenticated: bool,
+    encrypted: bool,
+) -> Result<SecuritySetup, ErrorCode> {
+    if level == SecurityLevel::None {
+        return Ok(SecuritySetup::Done);
+    }
+    // Encryption needs a link key, so an unencrypted link is authenticated first
+    if !authenticated && (level == SecurityLevel::Authenticated || !encrypted) {
+        let cmd = AuthenticationRequestedBuilder { connection_handle: handle };
+        return match hci.send(cmd).await.get_status() {
+            ErrorCode::Success => Ok(SecuritySetup::Authenticating(level)),
+            status => Err(status),
+        };
+    }
+    if !encrypted {
+        let cmd = SetConnectionEncryptionBuilder {
+            connection_handle: handle,
+            encryption_enable: Enable::Enabled,
+        };
+        return match hci.send(cmd).await.get_status() {
+            ErrorCode::Success => Ok(SecuritySetup::Encrypting(level)),
+            status => Err(status),
+        };
+    }
+    Ok(SecuritySetup::Done)
+}
+
+/// Act on the outcome of a security step: report the level once reached, or disconnect if it
+/// can't be
+async fn finish_security(
+    hci: &mut CommandSender,
+    handle: u16,
+    link: Link,
+    level: SecurityLevel,
+    outcome: Result<SecuritySetup, ErrorCode>,
+    evt_tx: &mut EventSender,
+) -> SecuritySetup {
+    match outcome {
+        Ok(SecuritySetup::Done) if level != SecurityLevel::None => {
+            let event = ConnectionEvent::SecurityLevelReached(level);
+            evt_tx.send(event).await;
+            SecuritySetup::Done
+        }
+        Ok(setup) => setup,
+        Err(status) => {
+            warn!("{} could not reach {:?}: {:?}", link, level, status);
+            let reason = DisconnectReason::AuthenticationFailure;
+            hci.send(DisconnectBuilder { connection_handle: handle, reason }).await;
+            SecuritySetup::Failed(status)
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_connection(
     handle: u16,
+    addr: Address,
+    shared: Arc<Mutex<ConnectionShared>>,
     evt_tx: Sender<ConnectionEvent>,
     mut req_rx: Receiver<ConnectionRequest>,
+    mut outbound: OutboundQueues,
     mut core: core::Connection,
     connections: Arc<Mutex<HashMap<u16, ConnectionInternal>>>,
+    standby: Arc<Mutex<HashMap<Address, ReconnectHints>>>,
     mut hci: CommandSender,
+    config: AclConfig,
+    unresponsive: Sender<()>,
+    metrics: Arc<Counters>,
+    mut accept_switch: AcceptSwitch,
+    required_security: SecurityLevel,
+    lazy_events: LazyEvents,
+    bandwidth: BandwidthBudget,
+    slot_freed: Arc<Notify>,
+    clock_offsets: ClockOffsets,
+    traffic: Arc<TrafficCounters>,
+    inbound_tx: Sender<Bytes>,
+    mut role_gate: Option<RoleGate>,
+    mut security_gate: Option<Sender<(Address, Result<(), ErrorCode>)>>,
+    params: ConnectParams,
+    relink: Sender<Request>,
+    parked: ParkedChannels,
 ) {
-    loop {
+    let mut evt_tx = EventSender::new(evt_tx, parked, config.event_coalescing, metrics.clone());
+    let core_tx = core.tx.take().unwrap();
+    let mut core_rx = core.rx.take().unwrap();
+    let mut authenticated = false;
+    let encrypted = shared.lock().await.encrypted;
+    let outcome =
+        step_security(&mut hci, handle, required_security, authenticated, encrypted).await;
+    let mut security = finish_security(
+        &mut hci,
+        handle,
+        Link::connected(addr, handle, shared.lock().await.role),
+        required_security,
+        outcome,
+        &mut evt_tx,
+    )
+    .await;
+    if accept_switch == AcceptSwitch::ReadingFeatures {
+        lazy_events.ensure(EventCode::ReadRemoteSupportedFeaturesComplete).await;
+        let status = hci
+            .send(ReadRemoteSupportedFeaturesBuilder { connection_handle: handle })
+            .await
+            .get_status();
+        if status != ErrorCode::Success {
+            warn!(
+                "could not read features of {}: {:?}",
+                Link::connected(addr, handle, Role::Peripheral),
+                status
+            );
+            accept_switch = AcceptSwitch::Settled;
+            evt_tx.send(ConnectionEvent::RoleChanged(Role::Peripheral)).await;
+        }
+    }
+    if let Some(gate) = role_gate.as_ref() {
+        if let Err(status) = switch_role(&mut hci, addr, gate.role).await {
+            let _ = gate.settled.send((addr, Err(status))).await;
+            role_gate = None;
+        }
+    }
+    // Read to page the peer faster should it disconnect and be connected again
+    let mut clock_offset = None;
+    if config.clock_offset_lifetime.is_some() {
+        lazy_events.ensure(EventCode::ReadClockOffsetComplete).await;
+        let status =
+            hci.send(ReadClockOffsetBuilder { connection_handle: handle }).await.get_status();
+        if status != ErrorCode::Success {
+            let link = Link::connected(addr, handle, shared.lock().await.role);
+            warn!("could not read clock offset of {}: {:?}", link, status);
+        }
+    }
+    // At most one packet is pulled from the priority queues while waiting for the dispatch to
+    // take it, so higher priority data arriving in the meantime is not stuck behind a backlog
+    let mut staged: Option<Bytes> = None;
+    // Received data waiting for the connection's receiver to take it
+    let mut inbound: Option<Bytes> = None;
+    let mut inbound_overflow = InboundOverflow::Wait;
+    // Reset by every packet sent or received, and disconnects the link once it expires
+    let mut idle = Alarm::new();
+    if let Some(timeout) = config.idle_timeout {
+        idle.reset(timeout);
+    }
+    let connected_at = Instant::now();
+    let mut role_switches = 0;
+    // A role switch waiting on the remote features to be read
+    let mut pending_switch: Option<(Role, oneshot::Sender<Result<(), AclError>>)> = None;
+    // A role switch the controller took, waiting on its Role Change
+    let mut switching: Option<oneshot::Sender<Result<(), AclError>>> = None;
+    // An encryption change the controller took, waiting on its Encryption Change
+    let mut pending_encryption: Option<oneshot::Sender<Result<(), AclError>>> = None;
+    // An authentication the controller took, waiting on its Authentication Complete
+    let mut pending_auth: Option<oneshot::Sender<Result<(), AclError>>> = None;
+    // A mode change the controller took, waiting on its Mode Change
+    let mut pending_mode: Option<oneshot::Sender<Result<(), AclError>>> = None;
+    // A hold the controller took, waiting on its Mode Change for the interval it chose
+    let mut pending_hold: Option<oneshot::Sender<Result<u16, ErrorCode>>> = None;
+    // Reads of the peer's name, all answered by the one Remote Name Request Complete
+    let mut name_waiters: Vec<oneshot::Sender<Result<String, ErrorCode>>> = Vec::new();
+    // Reads of the peer's features and version, answered by their completions
+    let mut features_waiters: Vec<oneshot::Sender<Result<u64, ErrorCode>>> = Vec::new();
+    let mut version_waiters: Vec<oneshot::Sender<Result<RemoteVersion, ErrorCode>>> = Vec::new();
+    // A read of an extended features page the controller took, and the last page the peer has,
+    // once any page was read
+    let mut pending_extended: Option<(u8, oneshot::Sender<Result<u64, ErrorCode>>)> = None;
+    let mut max_feature_page: Option<u8> = None;
+    // A QoS setup the controller took, waiting on its QoS Setup Complete, and the rate that
+    // was reserved before it, to go back to if it fails
+    let mut pending_qos: Option<(QosReply, Option<u32>)> = None;
+    // Token rate the link holds in the bandwidth budget
+    let mut reserved_rate: Option<u32> = None;
+    // A standby waiting on the clock offset to be read
+    let mut pending_standby: Option<oneshot::Sender<Result<(), AclError>>> = None;
+    // A disconnect the controller turned away, sent again when the alarm expires
+    let mut pending_disconnect: Option<DisconnectAttempt> = None;
+    // Set once a disconnect has been sent and taken, so later ones need not be sent, and the
+    // link going down is reported as local
+    let mut expecting_disconnect = false;
+    // Set if the peer connected again before the loss of this link was reported
+    let mut superseded = false;
+    let mut disconnect_retry = Alarm::new();
+    // Disconnects waiting on the link to go down, and how long it has left to
+    let mut down_waiters: Vec<oneshot::Sender<Result<(), AclError>>> = Vec::new();
+    let mut disconnect_deadline = Alarm::new();
+    let mut risk_check =
+        interval(config.link_risk.map(|r| r.interval).unwrap_or(Duration::from_secs(1)));
+    let mut failed_contacts: Option<u16> = None;
+    let mut at_risk = false;
+    let mut quality_check =
+        interval(config.link_degradation.map(|d| d.interval).unwrap_or(Duration::from_secs(1)));
+    // Checks in a row the link quality was below the threshold at
+    let mut low_quality_samples = 0;
+    // Set while an authenticated link has until the deadline to become encrypted
+    let mut awaiting_encryption = false;
+    let mut encryption_deadline = Alarm::new();
+    // Every timer of the connection is an arm of this select, rather than a task of its own,
+    // so they all go when the loop ends and none can fire for a link that is gone
+    let reason = loop {
+        // A link held back until it is secured is let through once it is, and failed once it
+        // can't be, by which time it is being disconnected
+        if let (Some(settled), Some(result)) = (security_gate.as_ref(), security.outcome()) {
+            if result.is_err() {
+                expecting_disconnect = true;
+                disconnect_deadline.reset(config.disconnect_timeout);
+            }
+            let _ = settled.send((addr, result)).await;
+            security_gate = None;
+        }
         select! {
+            Some(data) = outbound.recv(), if staged.is_none() => staged = Some(data),
+            Ok(permit) = core_tx.reserve(), if staged.is_some() => {
+                let data = staged.take().unwrap();
+                traffic.sent(data.len());
+                permit.send(data);
+                if let Some(timeout) = config.idle_timeout {
+                    idle.reset(timeout);
+                }
+            },
+            Some(data) = core_rx.recv(), if inbound.is_none() => {
+                traffic.received(data.len());
+                match inbound_overflow {
+                    InboundOverflow::Wait => inbound = Some(data),
+                    InboundOverflow::DropNewest => if let Err(TrySendError::Full(_)) = inbound_tx.try_send(data) {
+                        traffic.dropped();
+                    },
+                }
+                if let Some(timeout) = config.idle_timeout {
+                    idle.reset(timeout);
+                }
+            },
+            Ok(permit) = inbound_tx.reserve(), if inbound.is_some() => permit.send(inbound.take().unwrap()),
+            _ = idle.expired(), if config.idle_timeout.is_some() && !expecting_disconnect && pending_disconnect.is_none() => {
+                info!("disconnecting idle {}", Link::connected(addr, handle, shared.lock().await.role));
+                // Nobody waits on the outcome, which the link going down reports
+                let reason = DisconnectReason::RemoteUserTerminatedConnection;
+                let attempt = DisconnectAttempt { reason, attempts: 0, waiters: Vec::new() };
+                match try_disconnect(&mut hci, handle, attempt, &unresponsive).await {
+                    DisconnectProgress::Retry(attempt) => {
+                        pending_disconnect = Some(attempt);
+                        disconnect_retry.reset(DISCONNECT_RETRY_DELAY);
+                    },
+                    DisconnectProgress::Done(Ok(waiters)) => {
+                        expecting_disconnect = true;
+                        down_waiters.extend(waiters);
+                        disconnect_deadline.reset(config.disconnect_timeout);
+                    },
+                    // Tried again once the link has been idle for another while
+                    DisconnectProgress::Done(Err(_)) => idle.reset(config.idle_timeout.unwrap()),
+                }
+            },
             Some(evt) = core.evt_rx.recv() => {
+                if config.recent_hci_events > 0 {
+                    shared.lock().await.record_event(&evt, config.recent_hci_events);
+                }
                 match evt.specialize() {
-                    DisconnectionComplete(evt) => {
-                        connections.lock().await.remove(&handle);
-                        evt_tx.send(ConnectionEvent::Disconnected(evt.get_reason())).await.unwrap();
-                        return; // At this point, there is nothing more to run on the connection.
+                    DisconnectionComplete(evt) => break evt.get_reason(),
+                    AuthenticationComplete(evt) => {
+                        evt_tx.send(ConnectionEvent::AuthenticationComplete(evt.get_status())).await;
+                        authenticated |= evt.get_status() == ErrorCode::Success;
+                        if let Some(fut) = pending_auth.take() {
+                            let _ = fut.send(match evt.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(AclError::Hci(status)),
+                            });
+                        }
+                        if let SecuritySetup::Authenticating(level) = security {
+                            let outcome = match evt.get_status() {
+                                ErrorCode::Success => {
+                                    let encrypted = shared.lock().await.encrypted;
+                                    step_security(&mut hci, handle, level, authenticated, encrypted).await
+                                },
+                                status => Err(status),
+                            };
+                            security = finish_security(&mut hci, handle, Link::connected(addr, handle, shared.lock().await.role), level, outcome, &mut evt_tx).await;
+                        }
+                        // A link still on its way to its policy's security level gets encrypted on the way
+                        if config.require_encryption_after_auth && evt.get_status() == ErrorCode::Success
+                            && security == SecuritySetup::Done && !shared.lock().await.encrypted {
+                            let cmd = SetConnectionEncryptionBuilder { connection_handle: handle, encryption_enable: Enable::Enabled };
+                            match hci.send(cmd).await.get_status() {
+                                ErrorCode::Success => {
+                                    awaiting_encryption = true;
+                                    encryption_deadline.reset(config.encryption_after_auth_timeout);
+                                },
+                                status => {
+                                    let link = Link::connected(addr, handle, shared.lock().await.role);
+                                    warn!("could not encrypt {} after authentication: {:?}", link, status);
+                                    hci.send(DisconnectBuilder { connection_handle: handle, reason: DisconnectReason::AuthenticationFailure }).await;
+                                },
+                            }
+                        }
+                    },
+                    ReadRemoteVersionInformationComplete(evt) => {
+                        let result = match evt.get_status() {
+                            ErrorCode::Success => Ok(RemoteVersion {
+                                lmp_version: evt.get_version(),
+                                manufacturer_name: evt.get_manufacturer_name(),
+                                lmp_subversion: evt.get_sub_version(),
+                            }),
+                            status => Err(status),
+                        };
+                        for fut in version_waiters.drain(..) {
+                            let _ = fut.send(result);
+                        }
+                    },
+                    QosSetupComplete(evt) => {
+                        let (fut, previous) = match pending_qos.take() {
+                            Some(pending) => pending,
+                            None => continue,
+                        };
+                        let result = match evt.get_status() {
+                            ErrorCode::Success => Ok(QosResult {
+                                service_type: evt.get_service_type(),
+                                token_rate: evt.get_token_rate(),
+                                peak_bandwidth: evt.get_peak_bandwidth(),
+                                latency: evt.get_latency(),
+                                delay_variation: evt.get_delay_variation(),
+                            }),
+                            status => Err(status),
+                        };
+                        // What is held follows what the controller settled on
+                        reserved_rate = match result {
+                            Ok(QosResult { service_type: ServiceType::Guaranteed, token_rate, .. }) => {
+                                if bandwidth.reserve(handle, token_rate).is_err() {
+                                    warn!("{} was granted more guaranteed bandwidth than the budget has", Link::connected(addr, handle, shared.lock().await.role));
+                                }
+                                Some(token_rate)
+                            },
+                            Ok(_) => {
+                                bandwidth.release(handle);
+                                None
+                            },
+                            Err(_) => {
+                                hold_rate(&bandwidth, handle, previous);
+                                previous
+                            },
+                        };
+                        let _ = fut.send(result);
+                    },
+                    RemoteNameRequestComplete(evt) => {
+                        let result = match evt.get_status() {
+                            ErrorCode::Success => Ok(remote_name(evt.get_remote_name())),
+                            status => Err(status),
+                        };
+                        for fut in name_waiters.drain(..) {
+                            let _ = fut.send(result.clone());
+                        }
+                    },
+                    ModeChange(evt) => {
+                        if evt.get_status() == ErrorCode::Success {
+                            shared.lock().await.mode = evt.get_current_mode();
+                            evt_tx.send(ConnectionEvent::ModeChanged { mode: evt.get_current_mode(), interval: evt.get_interval() }).await;
+                        }
+                        if let Some(fut) = pending_mode.take() {
+                            reply(fut, match evt.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(AclError::Hci(status)),
+                            });
+                        }
+                        if let Some(fut) = pending_hold.take() {
+                            let _ = fut.send(match evt.get_status() {
+                                ErrorCode::Success => Ok(evt.get_interval()),
+                                status => Err(status),
+                            });
+                        }
+                    },
+                    EncryptionChange(evt) => {
+                        let encrypted = evt.get_encryption_enabled() != EncryptionEnabled::Off;
+                        if evt.get_status() == ErrorCode::Success {
+                            tracing::info!(enabled = encrypted, "encryption changed");
+                            shared.lock().await.set_encrypted(encrypted);
+                            evt_tx.send(ConnectionEvent::EncryptionChanged { enabled: encrypted }).await;
+                        } else {
+                            tracing::warn!(status = ?evt.get_status(), "encryption change failed");
+                        }
+                        if let Some(fut) = pending_encryption.take() {
+                            let _ = fut.send(match evt.get_status() {
+                                ErrorCode::Success => Ok(()),
+                                status => Err(AclError::Hci(status)),
+                            });
+                        }
+                        if awaiting_encryption && encrypted && evt.get_status() == ErrorCode::Success {
+                            awaiting_encryption = false;
+                            encryption_deadline.cancel();
+                        }
+                        if let SecuritySetup::Encrypting(level) = security {
+                            let outcome = match (evt.get_status(), encrypted) {
+                                (ErrorCode::Success, true) => step_security(&mut hci, handle, level, authenticated, encrypted).await,
+                                (ErrorCode::Success, false) => Err(ErrorCode::EncryptionModeNotAcceptable),
+                                (status, _) => Err(status),
+                            };
+                            security = finish_security(&mut hci, handle, Link::connected(addr, handle, shared.lock().await.role), level, outcome, &mut evt_tx).await;
+                        }
+                    },
+                    SynchronousConnectionComplete(evt) => {
+                        if evt.get_status() == ErrorCode::Success && evt.get_connection_handle() > MAX_HANDLE {
+                            let link = Link::connected(addr, handle, shared.lock().await.role);
+                            warn!("synchronous connection on {} came up with out of range handle", link);
+                        } else if evt.get_status() == ErrorCode::Success {
+                            let sco = ScoConnection {
+                                handle: evt.get_connection_handle(),
+                                link_type: evt.get_link_type(),
+                                shared: Arc::new(Mutex::new(ScoConnectionShared { air_mode: evt.get_air_mode() })),
+                            };
+                            evt_tx.send(ConnectionEvent::ScoConnected(sco)).await;
+                        } else {
+                            let link = Link::connected(addr, handle, shared.lock().await.role);
+                            warn!("synchronous connection on {} failed: {:?}", link, evt.get_status());
+                        }
+                    },
+                    ReadRemoteSupportedFeaturesComplete(evt) => {
+                        let status = evt.get_status();
+                        if status == ErrorCode::Success {
+                            shared.lock().await.remote_features = Some(evt.get_lmp_features());
+                        }
+                        for fut in features_waiters.drain(..) {
+                            let _ = fut.send(if status == ErrorCode::Success { Ok(evt.get_lmp_features()) } else { Err(status) });
+                        }
+                        if let Some((role, fut)) = pending_switch.take() {
+                            let result = match status {
+                                ErrorCode::Success => {
+                                    let link = Link::connected(addr, handle, shared.lock().await.role);
+                                    switch_role_if_supported(&mut hci, link, role, evt.get_lmp_features()).await
+                                },
+                                _ => Err(status),
+                            };
+                            hold_switch(result, fut, &mut switching);
+                        }
+                        if accept_switch == AcceptSwitch::ReadingFeatures {
+                            let result = match status {
+                                ErrorCode::Success => {
+                                    let link = Link::connected(addr, handle, Role::Peripheral);
+                                    switch_role_if_supported(&mut hci, link, Role::Central, evt.get_lmp_features()).await
+                                },
+                                _ => Err(status),
+                            };
+                            if result.is_ok() {
+                                accept_switch = AcceptSwitch::Switching;
+                            } else {
+                                accept_switch = AcceptSwitch::Settled;
+                                evt_tx.send(ConnectionEvent::RoleChanged(Role::Peripheral)).await;
+                            }
+                        }
+                    },
+                    RoleChange(evt) => {
+                        let mut shared = shared.lock().await;
+                        let changed = evt.get_status() == ErrorCode::Success;
+                        if changed {
+                            tracing::info!(role = ?evt.get_new_role(), "role changed");
+                            shared.role = evt.get_new_role();
+                            role_switches += 1;
+                        } else {
+                            tracing::warn!(status = ?evt.get_status(), "role change failed");
+                        }
+                        if let Some(fut) = switching.take() {
+                            let _ = fut.send(if changed { Ok(()) } else { Err(AclError::Hci(evt.get_status())) });
+                        }
+                        let settling = accept_switch == AcceptSwitch::Switching;
+                        if settling {
+                            accept_switch = AcceptSwitch::Settled;
+                        }
+                        if changed || settling {
+                            evt_tx.send(ConnectionEvent::RoleChanged(shared.role)).await;
+                        }
+                        // The manager looks the link up before it takes the result
+                        drop(shared);
+                        if let Some(gate) = role_gate.take() {
+                            let result = match evt.get_status() {
+                                ErrorCode::Success if evt.get_new_role() == gate.role => Ok(()),
+                                ErrorCode::Success => Err(ErrorCode::RoleSwitchFailed),
+                                status => Err(status),
+                            };
+                            let _ = gate.settled.send((addr, result)).await;
+                        }
+                    },
+                    ReadRemoteExtendedFeaturesComplete(evt) => {
+                        let status = evt.get_status();
+                        if status == ErrorCode::Success {
+                            max_feature_page = Some(evt.get_maximum_page_number());
+                        }
+                        if let Some((page, fut)) = pending_extended.take() {
+                            match status {
+                                ErrorCode::Success if evt.get_page_number() == page => {
+                                    let _ = fut.send(Ok(evt.get_extended_lmp_features()));
+                                },
+                                // Page 0 was read to learn how many pages there are
+                                ErrorCode::Success if page > evt.get_maximum_page_number() => {
+                                    let _ = fut.send(Err(ErrorCode::InvalidHciCommandParameters));
+                                },
+                                ErrorCode::Success => {
+                                    let status = hci.send(ReadRemoteExtendedFeaturesBuilder { connection_handle: handle, page_number: page }).await.get_status();
+                                    if status == ErrorCode::Success {
+                                        pending_extended = Some((page, fut));
+                                    } else {
+                                        let _ = fut.send(Err(status));
+                                    }
+                                },
+                                status => {
+                                    let _ = fut.send(Err(status));
+                                },
+                            }
+                        }
+                    },
+                    ReadClockOffsetComplete(evt) => {
+                        if evt.get_status() == ErrorCode::Success {
+                            clock_offset = Some(evt.get_clock_offset());
+                        }
+                        if let Some(fut) = pending_standby.take() {
+                            let clock_offset = match evt.get_status() {
+                                ErrorCode::Success => Some(evt.get_clock_offset()),
+                                _ => None,
+                            };
+                            enter_standby(&mut hci, handle, addr, clock_offset, &shared, &standby).await;
+                            expecting_disconnect = true;
+                            disconnect_deadline.reset(config.disconnect_timeout);
+                            reply(fut, Ok(()));
+                        }
                     },
-                    AuthenticationComplete(_) => evt_tx.send(ConnectionEvent::AuthenticationComplete).await.unwrap(),
-                    _ => unimplemented!(),
+                    _ => {
+                        let link = Link::connected(addr, handle, shared.lock().await.role);
+                        warn!("unhandled event {:?} on {}", evt.get_event_code(), link);
+                    },
+                }
+            },
+            _ = disconnect_retry.expired(), if pending_disconnect.is_some() => {
+                let attempt = pending_disconnect.take().unwrap();
+                match try_disconnect(&mut hci, handle, attempt, &unresponsive).await {
+                    DisconnectProgress::Retry(attempt) => {
+                        pending_disconnect = Some(attempt);
+                        disconnect_retry.reset(DISCONNECT_RETRY_DELAY);
+                    },
+                    DisconnectProgress::Done(Ok(waiters)) => {
+                        expecting_disconnect = true;
+                        down_waiters.extend(waiters);
+                        disconnect_deadline.reset(config.disconnect_timeout);
+                    },
+                    DisconnectProgress::Done(Err(_)) => {},
+                }
+            },
+            _ = disconnect_deadline.expired(), if expecting_disconnect => {
+                // The controller lost track of the link, and won't say it went down, so it is
+                // dropped here for the connection not to be held forever
+                warn!("{} was not down in time after a disconnect", Link::connected(addr, handle, shared.lock().await.role));
+                core.close().await;
+                break ErrorCode::ConnectionTimeout;
+            },
+            _ = evt_tx.due() => evt_tx.flush_due().await,
+            _ = encryption_deadline.expired(), if awaiting_encryption => {
+                awaiting_encryption = false;
+                warn!("{} authenticated but was not encrypted in time", Link::connected(addr, handle, shared.lock().await.role));
+                hci.send(DisconnectBuilder { connection_handle: handle, reason: DisconnectReason::AuthenticationFailure }).await;
+                expecting_disconnect = true;
+                disconnect_deadline.reset(config.disconnect_timeout);
+            },
+            _ = risk_check.tick(), if config.link_risk.is_some() => {
+                let thresholds = config.link_risk.unwrap();
+                if let Some((failed, rssi)) = read_link_quality(&mut hci, handle).await {
+                    shared.lock().await.rssi = Some(rssi);
+                    // The counter only counts consecutive failures, so it drops back once contact is made
+                    let rise = failed_contacts.map(|last| failed.saturating_sub(last)).unwrap_or(0);
+                    failed_contacts = Some(failed);
+                    let risky = rise >= thresholds.failed_contacts && rssi < thresholds.rssi;
+                    if risky && !at_risk {
+                        evt_tx.send(ConnectionEvent::LinkAtRisk { failed_contacts: failed, rssi }).await;
+                    }
+                    at_risk = risky;
+                }
+            },
+            _ = quality_check.tick(), if config.link_degradation.is_some() => {
+                let thresholds = config.link_degradation.unwrap();
+                let response = hci.send(ReadLinkQualityBuilder { connection_handle: handle }).await;
+                // A failed read leaves the count where it was
+                if response.get_status() == ErrorCode::Success {
+                    let quality = response.get_link_quality();
+                    if quality >= thresholds.quality {
+                        low_quality_samples = 0;
+                    } else {
+                        low_quality_samples += 1;
+                        if low_quality_samples == thresholds.samples {
+                            evt_tx.send(ConnectionEvent::LinkDegraded { quality }).await;
+                        }
+                    }
                 }
             },
             Some(req) = req_rx.recv() => {
                 match req {
-                    ConnectionRequest::Disconnect{reason, fut} => {
-                        hci.send(DisconnectBuilder { connection_handle: handle, reason }).await;
-                        fut.send(()).unwrap();
-                    }
+                    ConnectionRequest::Disconnect{reason, wait, fut} => {
+                        let waiter = DisconnectWaiter { fut, until_down: wait };
+                        if expecting_disconnect {
+                            // Already on its way down, and a second disconnect would only be refused
+                            if wait {
+                                down_waiters.push(waiter.fut);
+                            } else {
+                                reply(waiter.fut, Ok(()));
+                            }
+                        } else if let Some(attempt) = pending_disconnect.as_mut() {
+                            attempt.waiters.push(waiter);
+                        } else {
+                            let attempt = DisconnectAttempt { reason, attempts: 0, waiters: vec![waiter] };
+                            match try_disconnect(&mut hci, handle, attempt, &unresponsive).await {
+                                DisconnectProgress::Retry(attempt) => {
+                                    pending_disconnect = Some(attempt);
+                                    disconnect_retry.reset(DISCONNECT_RETRY_DELAY);
+                                },
+                                DisconnectProgress::Done(Ok(waiters)) => {
+                                    expecting_disconnect = true;
+                                    down_waiters.extend(waiters);
+                                    disconnect_deadline.reset(config.disconnect_timeout);
+                                },
+                                DisconnectProgress::Done(Err(_)) => {},
+                            }
+                        }
+                    },
+                    ConnectionRequest::SwitchRole{role, fut} => {
+                        let features = shared.lock().await.remote_features;
+                        if switching.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                        } else if config.skip_role_switch_feature_check {
+                            hold_switch(switch_role(&mut hci, addr, role).await, fut, &mut switching);
+                        } else if let Some(features) = features {
+                            let link = Link::connected(addr, handle, shared.lock().await.role);
+                            hold_switch(switch_role_if_supported(&mut hci, link, role, features).await, fut, &mut switching);
+                        } else if pending_switch.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                        } else if !features_waiters.is_empty() {
+                            pending_switch = Some((role, fut));
+                        } else {
+                            lazy_events.ensure(EventCode::ReadRemoteSupportedFeaturesComplete).await;
+                            let status = hci.send(ReadRemoteSupportedFeaturesBuilder { connection_handle: handle }).await.get_status();
+                            if status == ErrorCode::Success {
+                                pending_switch = Some((role, fut));
+                            } else {
+                                reply(fut, Err(AclError::Hci(status)));
+                            }
+                        }
+                    },
+                    ConnectionRequest::DiscoverRole{fut} => {
+                        let evt = hci.send(RoleDiscoveryBuilder { connection_handle: handle }).await;
+                        let result = match evt.get_status() {
+                            ErrorCode::Success => {
+                                let role = evt.get_current_role();
+                                let mut shared = shared.lock().await;
+                                if shared.role != role {
+                                    let link = Link::connected(addr, handle, shared.role);
+                                    warn!("role of {} was {:?}, but controller reports {:?}", link, shared.role, role);
+                                    shared.role = role;
+                                }
+                                Ok(role)
+                            },
+                            status => Err(AclError::Hci(status)),
+                        };
+                        reply(fut, result);
+                    },
+                    ConnectionRequest::ReadRemoteFeatures{fut} => {
+                        features_waiters.push(fut);
+                        // Already asked, by this request or a role switch waiting on the answer
+                        if features_waiters.len() > 1 || pending_switch.is_some() || accept_switch == AcceptSwitch::ReadingFeatures {
+                            continue;
+                        }
+                        lazy_events.ensure(EventCode::ReadRemoteSupportedFeaturesComplete).await;
+                        let status = hci.send(ReadRemoteSupportedFeaturesBuilder { connection_handle: handle }).await.get_status();
+                        if status != ErrorCode::Success {
+                            for fut in features_waiters.drain(..) {
+                                let _ = fut.send(Err(status));
+                            }
+                        }
+                    },
+                    ConnectionRequest::ReadRemoteExtendedFeatures{page, fut} => {
+                        let first = match max_feature_page {
+                            _ if pending_extended.is_some() => Err(ErrorCode::CommandDisallowed),
+                            Some(max) if page > max => Err(ErrorCode::InvalidHciCommandParameters),
+                            Some(_) => Ok(page),
+                            None => Ok(0),
+                        };
+                        match first {
+                            Ok(page_number) => {
+                                lazy_events.ensure(EventCode::ReadRemoteExtendedFeaturesComplete).await;
+                                let status = hci.send(ReadRemoteExtendedFeaturesBuilder { connection_handle: handle, page_number }).await.get_status();
+                                if status == ErrorCode::Success {
+                                    pending_extended = Some((page, fut));
+                                } else {
+                                    let _ = fut.send(Err(status));
+                                }
+                            },
+                            Err(status) => {
+                                let _ = fut.send(Err(status));
+                            },
+                        }
+                    },
+                    ConnectionRequest::ReadRemoteVersion{fut} => {
+                        version_waiters.push(fut);
+                        // Already asked, this one is answered by the same completion
+                        if version_waiters.len() > 1 {
+                            continue;
+                        }
+                        lazy_events.ensure(EventCode::ReadRemoteVersionInformationComplete).await;
+                        let status = hci.send(ReadRemoteVersionInformationBuilder { connection_handle: handle }).await.get_status();
+                        if status != ErrorCode::Success {
+                            for fut in version_waiters.drain(..) {
+                                let _ = fut.send(Err(status));
+                            }
+                        }
+                    },
+                    ConnectionRequest::ReadRemoteName{fut} => {
+                        name_waiters.push(fut);
+                        // Already asked, this one is answered by the same completion
+                        if name_waiters.len() > 1 {
+                            continue;
+                        }
+                        lazy_events.ensure(EventCode::RemoteNameRequestComplete).await;
+                        let cmd = RemoteNameRequestBuilder {
+                            bd_addr: addr,
+                            page_scan_repetition_mode: PageScanRepetitionMode::R1,
+                            clock_offset: 0,
+                            clock_offset_valid: ClockOffsetValid::Invalid,
+                        };
+                        let status = hci.send(cmd).await.get_status();
+                        if status != ErrorCode::Success {
+                            for fut in name_waiters.drain(..) {
+                                let _ = fut.send(Err(status));
+                            }
+                        }
+                    },
+                    ConnectionRequest::ReadRssi{fut} => {
+                        let response = hci.send(ReadRssiBuilder { connection_handle: handle }).await;
+                        let result = for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_rssi() as i8);
+                        if let Ok(rssi) = result {
+                            shared.lock().await.rssi = Some(rssi);
+                        }
+                        let _ = fut.send(result);
+                    },
+                    ConnectionRequest::ReadTxPower{kind, fut} => {
+                        let cmd = ReadTransmitPowerLevelBuilder { connection_handle: handle, transmit_power_level_type: kind.into() };
+                        let response = hci.send(cmd).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_transmit_power_level() as i8));
+                    },
+                    ConnectionRequest::ReadClock{which, fut} => {
+                        let response = hci.send(ReadClockBuilder { connection_handle: handle, which_clock: which.into() }).await;
+                        // The handle is ignored for the local clock, so the answer needn't carry it
+                        let answered = match which {
+                            ClockKind::Local => handle,
+                            ClockKind::Piconet => response.get_connection_handle(),
+                        };
+                        let _ = fut.send(for_handle(handle, response.get_status(), answered).map(|_| (response.get_clock(), response.get_accuracy())));
+                    },
+                    ConnectionRequest::ReadEncryptionKeySize{fut} => {
+                        let response = hci.send(ReadEncryptionKeySizeBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_key_size()));
+                    },
+                    ConnectionRequest::ReadLinkQuality{fut} => {
+                        let response = hci.send(ReadLinkQualityBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_link_quality()));
+                    },
+                    ConnectionRequest::ReadFailedContactCounter{fut} => {
+                        let response = hci.send(ReadFailedContactCounterBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_failed_contact_counter()));
+                    },
+                    ConnectionRequest::ResetFailedContactCounter{fut} => {
+                        let response = hci.send(ResetFailedContactCounterBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()));
+                    },
+                    ConnectionRequest::SetupQos{qos, fut} => {
+                        if pending_qos.is_some() {
+                            let _ = fut.send(Err(ErrorCode::CommandDisallowed));
+                            continue;
+                        }
+                        if qos.service_type == ServiceType::Guaranteed {
+                            if let Err(available) = bandwidth.reserve(handle, qos.token_rate) {
+                                warn!("{} asked for {} octets/s of guaranteed bandwidth, {} are left", Link::connected(addr, handle, shared.lock().await.role), qos.token_rate, available);
+                                let _ = fut.send(Err(ErrorCode::ConnectionRejectedLimitedResources));
+                                continue;
+                            }
+                        }
+                        lazy_events.ensure(EventCode::QosSetupComplete).await;
+                        let cmd = QosSetupBuilder {
+                            connection_handle: handle,
+                            service_type: qos.service_type,
+                            token_rate: qos.token_rate,
+                            peak_bandwidth: qos.peak_bandwidth,
+                            latency: qos.latency,
+                            delay_variation: qos.delay_variation,
+                        };
+                        match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => pending_qos = Some((fut, reserved_rate)),
+                            status => {
+                                hold_rate(&bandwidth, handle, reserved_rate);
+                                let _ = fut.send(Err(status));
+                            },
+                        }
+                    },
+                    ConnectionRequest::EnterSniff{params, fut} => {
+                        if pending_mode.is_some() || pending_hold.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                            continue;
+                        }
+                        let cmd = SniffModeBuilder {
+                            connection_handle: handle,
+                            sniff_max_interval: params.max_interval,
+                            sniff_min_interval: params.min_interval,
+                            sniff_attempt: params.attempt,
+                            sniff_timeout: params.timeout,
+                        };
+                        match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => pending_mode = Some(fut),
+                            status => reply(fut, Err(AclError::Hci(status))),
+                        }
+                    },
+                    ConnectionRequest::ExitSniff{fut} => {
+                        if pending_mode.is_some() || pending_hold.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                            continue;
+                        }
+                        match hci.send(ExitSniffModeBuilder { connection_handle: handle }).await.get_status() {
+                            ErrorCode::Success => pending_mode = Some(fut),
+                            status => reply(fut, Err(AclError::Hci(status))),
+                        }
+                    },
+                    ConnectionRequest::EnterHold{max_interval, min_interval, fut} => {
+                        if pending_mode.is_some() || pending_hold.is_some() {
+                            let _ = fut.send(Err(ErrorCode::CommandDisallowed));
+                            continue;
+                        }
+                        let cmd = HoldModeBuilder {
+                            connection_handle: handle,
+                            hold_mode_max_interval: max_interval,
+                            hold_mode_min_interval: min_interval,
+                        };
+                        match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => pending_hold = Some(fut),
+                            status => {
+                                let _ = fut.send(Err(status));
+                            },
+                        }
+                    },
+                    ConnectionRequest::Authenticate{fut} => {
+                        if pending_auth.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                            continue;
+                        }
+                        let cmd = AuthenticationRequestedBuilder { connection_handle: handle };
+                        match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => pending_auth = Some(fut),
+                            status => reply(fut, Err(AclError::Hci(status))),
+                        }
+                    },
+                    ConnectionRequest::SetEncryption{enable, fut} => {
+                        if pending_encryption.is_some() {
+                            reply(fut, Err(AclError::Hci(ErrorCode::CommandDisallowed)));
+                            continue;
+                        }
+                        let encryption_enable = if enable { Enable::Enabled } else { Enable::Disabled };
+                        let cmd = SetConnectionEncryptionBuilder { connection_handle: handle, encryption_enable };
+                        match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => pending_encryption = Some(fut),
+                            status => reply(fut, Err(AclError::Hci(status))),
+                        }
+                    },
+                    ConnectionRequest::SetInboundOverflow{mode, fut} => {
+                        inbound_overflow = mode;
+                        reply(fut, Ok(()));
+                    },
+                    ConnectionRequest::SetFlushTimeout{timeout, fut} => {
+                        let status = hci.send(WriteAutomaticFlushTimeoutBuilder {
+                            connection_handle: handle,
+                            flush_timeout: timeout,
+                        }).await.get_status();
+                        let result = match status {
+                            ErrorCode::Success => Ok(()),
+                            status => Err(AclError::Hci(status)),
+                        };
+                        reply(fut, result);
+                    },
+                    ConnectionRequest::ReadFlushTimeout{fut} => {
+                        let response = hci.send(ReadAutomaticFlushTimeoutBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_flush_timeout()));
+                    },
+                    ConnectionRequest::SetupSco{params, fut} => {
+                        let cmd = SetupSynchronousConnectionBuilder {
+                            connection_handle: handle,
+                            transmit_bandwidth: params.transmit_bandwidth,
+                            receive_bandwidth: params.receive_bandwidth,
+                            max_latency: params.max_latency,
+                            voice_setting: params.voice_setting,
+                            retransmission_effort: params.retransmission_effort,
+                            packet_type: params.packet_type,
+                        };
+                        reply(fut, match hci.send(cmd).await.get_status() {
+                            ErrorCode::Success => Ok(()),
+                            status => Err(AclError::Hci(status)),
+                        });
+                    },
+                    // Done once its command complete is in, whatever Flush Occurred reports
+                    ConnectionRequest::Flush{fut} => {
+                        let response = hci.send(FlushBuilder { connection_handle: handle }).await;
+                        reply(fut, for_handle(handle, response.get_status(), response.get_connection_handle()).map_err(AclError::Hci));
+                    },
+                    ConnectionRequest::WriteLinkPolicy{settings, fut} => {
+                        let response = hci.send(WriteLinkPolicySettingsBuilder { connection_handle: handle, link_policy_settings: settings.bits() }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()));
+                    },
+                    ConnectionRequest::ReadLinkPolicy{fut} => {
+                        let response = hci.send(ReadLinkPolicySettingsBuilder { connection_handle: handle }).await;
+                        let _ = fut.send(for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| LinkPolicy::from_bits_truncate(response.get_link_policy_settings())));
+                    },
+                    ConnectionRequest::WriteSupervisionTimeout{slots, fut} => {
+                        if slots == 0 && !config.allow_no_supervision_timeout {
+                            let _ = fut.send(Err(ErrorCode::InvalidHciCommandParameters));
+                        } else {
+                            let response = hci.send(WriteLinkSupervisionTimeoutBuilder { connection_handle: handle, link_supervision_timeout: slots }).await;
+                            let result = for_handle(handle, response.get_status(), response.get_connection_handle());
+                            if result.is_ok() {
+                                shared.lock().await.supervision_timeout = Some(slots);
+                            }
+                            let _ = fut.send(result);
+                        }
+                    },
+                    ConnectionRequest::ReadSupervisionTimeout{fut} => {
+                        let response = hci.send(ReadLinkSupervisionTimeoutBuilder { connection_handle: handle }).await;
+                        let result = for_handle(handle, response.get_status(), response.get_connection_handle()).map(|_| response.get_link_supervision_timeout());
+                        if let Ok(slots) = result {
+                            shared.lock().await.supervision_timeout = Some(slots);
+                        }
+                        let _ = fut.send(result);
+                    },
+                    ConnectionRequest::Subscribe{mask, tx} => evt_tx.subscribe(mask, tx),
+                    ConnectionRequest::Superseded{disconnect_sent} => {
+                        if !disconnect_sent {
+                            // The controller had already lost the link
+                            core.close().await;
+                            superseded = true;
+                            break ErrorCode::ConnectionTimeout;
+                        }
+                        expecting_disconnect = true;
+                        disconnect_deadline.reset(config.disconnect_timeout);
+                    },
+                    ConnectionRequest::ControllerReset => {
+                        core.close().await;
+                        break ErrorCode::HardwareFailure;
+                    },
+                    ConnectionRequest::Standby{fut} => {
+                        lazy_events.ensure(EventCode::ReadClockOffsetComplete).await;
+                        let status = hci.send(ReadClockOffsetBuilder { connection_handle: handle }).await.get_status();
+                        if status == ErrorCode::Success {
+                            pending_standby = Some(fut);
+                        } else {
+                            enter_standby(&mut hci, handle, addr, None, &shared, &standby).await;
+                            expecting_disconnect = true;
+                            disconnect_deadline.reset(config.disconnect_timeout);
+                            reply(fut, Ok(()));
+                        }
+                    },
                 }
             },
         }
+    };
+    // The link is down, so there is nothing more to run on the connection
+    if let Some(offset) = clock_offset {
+        clock_offsets.lock().await.insert(addr, (offset, Instant::now()));
+    }
+    let (idle, replaced) = {
+        let mut connections = connections.lock().await;
+        // Unless a link the controller gave the handle to again has taken it over. After a
+        // controller reset the manager already dropped it.
+        let replaced =
+            matches!(connections.get(&handle), Some(c) if !Arc::ptr_eq(&c.shared, &shared));
+        if !replaced {
+            connections.remove(&handle);
+        }
+        (connections.is_empty(), replaced)
+    };
+    lazy_events.release(idle).await;
+    if !replaced {
+        bandwidth.release(handle);
+    }
+    slot_freed.notify_one();
+    if let Some(attempt) = pending_disconnect.take() {
+        down_waiters.extend(attempt.resolve(Ok(())));
+    }
+    for fut in down_waiters {
+        let _ = fut.send(Ok(()));
+    }
+    if let Some((_, fut)) = pending_switch.take() {
+        let _ = fut.send(Err(AclError::Disconnected));
+    }
+    if let Some(fut) = switching.take() {
+        let _ = fut.send(Err(AclError::Disconnected));
+    }
+    if let Some(fut) = pending_encryption.take() {
+        let _ = fut.send(Err(AclError::Disconnected));
+    }
+    if let Some(fut) = pending_auth.take() {
+        let _ = fut.send(Err(AclError::Disconnected));
+    }
+    if let Some(fut) = pending_mode.take() {
+        let _ = fut.send(Err(AclError::Disconnected));
+    }
+    if let Some(fut) = pending_hold.take() {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    for fut in name_waiters.drain(..) {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    for fut in features_waiters.drain(..) {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    for fut in version_waiters.drain(..) {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    if let Some((_, fut)) = pending_extended.take() {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    if let Some((fut, _)) = pending_qos.take() {
+        let _ = fut.send(Err(ErrorCode::UnknownConnection));
+    }
+    if let Some(fut) = pending_standby.take() {
+        let _ = fut.send(Ok(()));
+    }
+    if let Some(gate) = role_gate.take() {
+        let _ = gate.settled.send((addr, Err(reason))).await;
+    }
+    if let Some(settled) = security_gate.take() {
+        let _ = settled.send((addr, Err(reason))).await;
+    }
+    req_rx.close();
+    while let Ok(req) = req_rx.try_recv() {
+        cancel_request(req);
+    }
+    let (role, encrypted) = {
+        let shared = shared.lock().await;
+        (shared.role, shared.encrypted)
+    };
+    config.stats_sink.record(
+        addr,
+        ConnectionStats {
+            duration: connected_at.elapsed(),
+            reason,
+            role,
+            encrypted,
+            role_switches,
+            bytes_sent: traffic.snapshot().tx_bytes,
+        },
+    );
+    // Disconnects the manager sent on the link's behalf are only known by the controller's reason
+    let local = expecting_disconnect || status::is_local_error(reason);
+    // A disconnect this side sent comes back with the reason it gave the peer
+    let cause = if local { DisconnectCause::LocalHost } else { DisconnectCause::of(reason) };
+    let lost = reason == ErrorCode::ConnectionTimeout && !local && !superseded;
+    if lost && params.auto_reconnect_on_link_loss {
+        info!("{} was lost, connecting again", Link::connected(addr, handle, role));
+        evt_tx.send(ConnectionEvent::Reconnecting).await;
+        let params = ConnectParams { clock_offset: clock_offset.or(params.clock_offset), ..params };
+        if relink.send(Request::Connect { addr, params, fut: None }).await.is_err() {
+            warn!("could not connect {} again, the manager is gone", Link::unconnected(addr));
+        }
+    }
+    tracing::info!(?reason, local, "disconnected");
+    evt_tx.send(ConnectionEvent::Disconnected { reason, local, cause }).await;
+}
+
+/// Set what `handle` holds in the budget back to `rate`. It held that before, so this only
+/// falls short if other connections reserved the difference since.
+fn hold_rate(bandwidth: &BandwidthBudget, handle: u16, rate: Option<u32>) {
+    match rate {
+        Some(rate) => {
+            let _ = bandwidth.reserve(handle, rate);
+        }
+        None => bandwidth.release(handle),
+    }
+}
+
+/// The status of a command complete answering for `handle`. Commands are sent one at a time
+/// by the connection that answers them, so a complete for another handle means the controller
+/// mixed them up.
+fn for_handle(handle: u16, status: ErrorCode, answered: u16) -> Result<(), ErrorCode> {
+    match status {
+        ErrorCode::Success if answered != handle => {
+            error!(
+                "command complete for handle {:#x} answered a command for {:#x}",
+                answered, handle
+            );
+            Err(ErrorCode::UnspecifiedError)
+        }
+        ErrorCode::Success => Ok(()),
+        status => Err(status),
+    }
+}
+
+/// Resolve a request the link went down before it was handled. Waiters that already went away
+/// don't need to hear about it.
+fn cancel_request(req: ConnectionRequest) {
+    match req {
+        // Already done, as far as the caller is concerned
+        ConnectionRequest::Disconnect { fut, .. } => {
+            let _ = fut.send(Ok(()));
+        }
+        ConnectionRequest::Standby { fut } => {
+            let _ = fut.send(Ok(()));
+        }
+        // Dropping the sender ends the subscriber's receiver
+        ConnectionRequest::Subscribe { .. } => {}
+        ConnectionRequest::Superseded { .. } | ConnectionRequest::ControllerReset => {}
+        ConnectionRequest::SwitchRole { fut, .. } => {
+            let _ = fut.send(Err(AclError::Disconnected));
+        }
+        ConnectionRequest::DiscoverRole { fut } => {
+            let _ = fut.send(Err(AclError::Disconnected));
+        }
+        ConnectionRequest::SetFlushTimeout { fut, .. }
+        | ConnectionRequest::SetInboundOverflow { fut, .. }
+        | ConnectionRequest::Flush { fut }
+        | ConnectionRequest::SetupSco { fut, .. } => {
+            let _ = fut.send(Err(AclError::Disconnected));
+        }
+        ConnectionRequest::ReadFlushTimeout { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::WriteLinkPolicy { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadLinkPolicy { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::WriteSupervisionTimeout { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadSupervisionTimeout { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::SetEncryption { fut, .. } => {
+            let _ = fut.send(Err(AclError::Disconnected));
+        }
+        ConnectionRequest::Authenticate { fut }
+        | ConnectionRequest::EnterSniff { fut, .. }
+        | ConnectionRequest::ExitSniff { fut } => {
+            let _ = fut.send(Err(AclError::Disconnected));
+        }
+        ConnectionRequest::EnterHold { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadRemoteName { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadRemoteFeatures { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadRemoteExtendedFeatures { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadRemoteVersion { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadRssi { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadTxPower { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadClock { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadEncryptionKeySize { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadLinkQuality { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ReadFailedContactCounter { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::ResetFailedContactCounter { fut } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
+        ConnectionRequest::SetupQos { fut, .. } => {
+            let _ = fut.send(Err(ErrorCode::UnknownConnection));
+        }
     }
 }
+
+#[cfg(test)]
+mod soak;
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,82 @@
+//! Outgoing connects that failed and wait out a backoff before they page again
+
+use super::{ConnectParams, ReconnectPolicy};
+use bt_hci::Address;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Scheduled {
+    addr: Address,
+    params: ConnectParams,
+    due: Instant,
+}
+
+/// Retries waiting to be issued, and how many each address has made so far
+#[derive(Default)]
+pub(super) struct Retries {
+    scheduled: Vec<Scheduled>,
+    attempts: HashMap<Address, u32>,
+}
+
+impl Retries {
+    /// Schedule another attempt for `addr` after the policy's backoff, returning the delay. Once
+    /// the policy's attempts are used up, nothing is scheduled and `None` is returned.
+    pub fn schedule(
+        &mut self,
+        addr: Address,
+        params: ConnectParams,
+        policy: ReconnectPolicy,
+    ) -> Option<Duration> {
+        let attempts = self.attempts.entry(addr).or_default();
+        if *attempts >= policy.max_attempts {
+            self.attempts.remove(&addr);
+            return None;
+        }
+        *attempts += 1;
+        let delay = policy.delay(*attempts);
+        self.scheduled.push(Scheduled { addr, params, due: Instant::now() + delay });
+        Some(delay)
+    }
+
+    /// Take the connects whose backoff is over, earliest first
+    pub fn take_due(&mut self) -> Vec<(Address, ConnectParams)> {
+        let now = Instant::now();
+        let (mut due, later): (Vec<_>, Vec<_>) =
+            self.scheduled.drain(..).partition(|s| s.due <= now);
+        self.scheduled = later;
+        due.sort_by_key(|s| s.due);
+        due.into_iter().map(|s| (s.addr, s.params)).collect()
+    }
+
+    /// Time left until the next backoff is over, if any is running
+    pub fn next_due(&self) -> Option<Duration> {
+        let due = self.scheduled.iter().map(|s| s.due).min()?;
+        // The alarm takes a zero duration as disarming it
+        Some(due.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)))
+    }
+
+    /// How many retries are waiting out their backoff
+    pub fn len(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    /// Whether a retry for `addr` is waiting out its backoff
+    pub fn is_scheduled(&self, addr: Address) -> bool {
+        self.scheduled.iter().any(|s| s.addr == addr)
+    }
+
+    /// Forget `addr`, because it connected, was cancelled or was connected to afresh. Returns
+    /// whether a retry was waiting.
+    pub fn forget(&mut self, addr: Address) -> bool {
+        self.attempts.remove(&addr);
+        let before = self.scheduled.len();
+        self.scheduled.retain(|s| s.addr != addr);
+        self.scheduled.len() != before
+    }
+
+    /// Drop every waiting retry, returning their addresses in the order they were scheduled
+    pub fn clear(&mut self) -> Vec<Address> {
+        self.attempts.clear();
+        self.scheduled.drain(..).map(|s| s.addr).collect()
+    }
+}
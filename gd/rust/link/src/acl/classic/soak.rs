@@ -0,0 +1,80 @@
+//! Repeated connect/disconnect cycles, to catch state left behind by one cycle that breaks or
+//! bloats the next
+
+use super::*;
+use crate::acl::test_util::FakeController;
+use bt_packets::hci::{ConnectionCompleteBuilder, DisconnectionCompleteBuilder, LinkType};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const HANDLE: u16 = 0x40;
+
+/// Connect to and disconnect from `addr` `n` times, then check the manager is back to idle:
+/// no open connections, nothing pending or queued, and nothing kept for standby. Every cycle
+/// reuses the same handle, so a handle that was not released fails the next cycle.
+pub async fn run_cycles(
+    acl: &mut AclManager,
+    controller: &mut FakeController,
+    addr: Address,
+    n: usize,
+) {
+    for cycle in 0..n {
+        acl.connect(addr).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(addr, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: HANDLE,
+                bd_addr: addr,
+                link_type: LinkType::Acl,
+                encryption_enabled: Enable::Disabled,
+            })
+            .await;
+        let mut connection = match acl.evt_rx.lock().await.recv().await.unwrap() {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("cycle {}: unexpected event {:?}", cycle, e),
+        };
+
+        let reason = DisconnectReason::RemoteUserTerminatedConnection;
+        let (result, ()) = tokio::join!(connection.disconnect(reason), async {
+            controller
+                .expect_status(
+                    DisconnectBuilder { connection_handle: HANDLE, reason },
+                    ErrorCode::Success,
+                )
+                .await;
+            controller
+                .send_event(DisconnectionCompleteBuilder {
+                    status: ErrorCode::Success,
+                    connection_handle: HANDLE,
+                    reason: ErrorCode::ConnectionTerminatedByLocalHost,
+                })
+                .await;
+        });
+        assert_eq!(result, Ok(()), "cycle {}: disconnect failed", cycle);
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected {
+                local: true,
+                cause: DisconnectCause::LocalHost,
+                ..
+            }) => {}
+            e => panic!("cycle {}: unexpected connection event {:?}", cycle, e),
+        }
+    }
+
+    let open = Arc::new(AtomicUsize::new(0));
+    let counter = open.clone();
+    acl.for_each_connection(move |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        None
+    })
+    .await
+    .unwrap();
+    assert_eq!(open.load(Ordering::Relaxed), 0, "connections left open");
+    assert!(!acl.is_connecting(addr).await.unwrap(), "connect left pending");
+    assert!(!acl.is_standby(addr).await.unwrap(), "standby hints kept");
+}
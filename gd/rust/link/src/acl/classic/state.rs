@@ -0,0 +1,102 @@
+//! Bookkeeping of the connection the manager is setting up, kept out of the async loop so
+//! single events can be run against it synchronously, the way a fuzzer does
+
+use super::ConnectParams;
+use bt_hci::Address;
+use bt_packets::hci::EventChild::{ConnectionComplete, RoleChange};
+use bt_packets::hci::{ErrorCode, EventPacket, LinkType, Role};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum PendingConnect {
+    Outgoing(Address),
+    Incoming(Address),
+    None,
+}
+
+// Deriving needs `#[default]` on the variant, which is newer than the toolchains this builds on
+#[allow(clippy::derivable_impls)]
+impl Default for PendingConnect {
+    fn default() -> Self {
+        PendingConnect::None
+    }
+}
+
+impl PendingConnect {
+    pub fn take(&mut self) -> Self {
+        std::mem::replace(self, PendingConnect::None)
+    }
+}
+
+/// State of the connection the classic ACL manager is setting up
+#[derive(Default)]
+pub struct AclState {
+    pub(super) pending: PendingConnect,
+    pub(super) pending_params: ConnectParams,
+    // Role reported by a switch completing before the pending connection does
+    pub(super) pending_role: Option<Role>,
+    // Whether the pending incoming connection was accepted as peripheral to switch later
+    pub(super) pending_accept_switch: bool,
+}
+
+/// What is left to do for an event once `handle_event` ran it against the state
+#[derive(Debug)]
+pub enum EventOutcome {
+    /// A connection attempt completed, answering the pending connect
+    Completed(Completion),
+    /// A connection attempt completed that nothing was pending for
+    Unexpected,
+    /// The state took all of the event
+    Done,
+    /// The event is not about the connection being set up
+    Other,
+}
+
+/// The pending connect a connection complete answered
+#[derive(Debug)]
+pub struct Completion {
+    /// Whether the peer connected to us
+    pub incoming: bool,
+    /// Local role on the new link
+    pub role: Role,
+    /// Options the connect was issued with
+    pub params: ConnectParams,
+    /// Whether an incoming link was accepted as peripheral, to switch to central once up
+    pub switch_after_accept: bool,
+}
+
+/// Run one HCI event against the state. Doesn't panic, whatever the event.
+pub fn handle_event(state: &mut AclState, evt: &EventPacket) -> EventOutcome {
+    match evt.specialize() {
+        // Legacy SCO links complete with this event too, but only ACL links are set up here
+        ConnectionComplete(evt) if evt.get_link_type() != LinkType::Acl => EventOutcome::Other,
+        ConnectionComplete(evt) => {
+            let addr = evt.get_bd_addr();
+            let (incoming, role, params) = match state.pending {
+                PendingConnect::Outgoing(a) if a == addr => {
+                    (false, Role::Central, std::mem::take(&mut state.pending_params))
+                }
+                PendingConnect::Incoming(a) if a == addr => {
+                    (true, Role::Peripheral, ConnectParams::default())
+                }
+                // Whatever is pending still waits on its own completion
+                _ => return EventOutcome::Unexpected,
+            };
+            state.pending = PendingConnect::None;
+            let switch_after_accept = std::mem::take(&mut state.pending_accept_switch) && incoming;
+            // Incoming connections start out peripheral, and only become central once the
+            // switch requested on accept completes
+            let role = state.pending_role.take().unwrap_or(role);
+            EventOutcome::Completed(Completion { incoming, role, params, switch_after_accept })
+        }
+        RoleChange(evt) => match state.pending {
+            PendingConnect::Outgoing(a) | PendingConnect::Incoming(a) if a == evt.get_bd_addr() => {
+                if evt.get_status() == ErrorCode::Success {
+                    state.pending_role = Some(evt.get_new_role());
+                }
+                EventOutcome::Done
+            }
+            _ => EventOutcome::Other,
+        },
+        _ => EventOutcome::Other,
+    }
+}
@@ -0,0 +1,92 @@
+//! Classification of the statuses connections fail and go down with, shared by the manager
+//! and the layers above it
+
+use bt_packets::hci::ErrorCode;
+
+/// Broad kinds of connection failure, for deciding what to do next
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailureClass {
+    /// The peer could not be reached this time, and trying again later may succeed
+    Transient,
+    /// The peer or controller turned the connection down, so trying again will not help
+    Refused,
+    /// The stored link key is missing or was rejected, and the peer needs to be paired again
+    NeedsPairing,
+    /// Anything else
+    Other,
+}
+
+impl FailureClass {
+    /// Classify the status a connection failed with
+    pub fn of(reason: ErrorCode) -> Self {
+        match reason {
+            ErrorCode::PageTimeout
+            | ErrorCode::ConnectionTimeout
+            | ErrorCode::ConnectionAcceptTimeout
+            | ErrorCode::ConnectionFailedEstablishment
+            | ErrorCode::ControllerBusy
+            | ErrorCode::LinkLayerCollision => FailureClass::Transient,
+            ErrorCode::ConnectionRejectedLimitedResources
+            | ErrorCode::ConnectionRejectedSecurityReasons
+            | ErrorCode::ConnectionRejectedUnacceptableBdAddr
+            | ErrorCode::ConnectionLimitExceeded
+            | ErrorCode::ConnectionAlreadyExists
+            | ErrorCode::UnsupportedRemoteOrLmpFeature => FailureClass::Refused,
+            ErrorCode::PinOrKeyMissing | ErrorCode::AuthenticationFailure => {
+                FailureClass::NeedsPairing
+            }
+            _ => FailureClass::Other,
+        }
+    }
+}
+
+/// Broad kinds of disconnection, for telling a peer leaving apart from a lost link
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisconnectCause {
+    /// The peer ended the link, because its user asked to, it ran low on resources or it is
+    /// powering off
+    RemoteUserTerminated,
+    /// The link timed out, usually because the peer went out of range
+    LinkLoss,
+    /// This side ended the link
+    LocalHost,
+    /// Any other reason
+    Other(ErrorCode),
+}
+
+impl DisconnectCause {
+    /// Classify the reason a link went down with
+    pub fn of(reason: ErrorCode) -> Self {
+        match reason {
+            ErrorCode::RemoteUserTerminatedConnection
+            | ErrorCode::RemoteDeviceTerminatedConnectionLowResources
+            | ErrorCode::RemoteDeviceTerminatedConnectionPowerOff => {
+                DisconnectCause::RemoteUserTerminated
+            }
+            ErrorCode::ConnectionTimeout => DisconnectCause::LinkLoss,
+            ErrorCode::ConnectionTerminatedByLocalHost => DisconnectCause::LocalHost,
+            reason => DisconnectCause::Other(reason),
+        }
+    }
+}
+
+/// Whether a connect that failed with `code` may succeed if tried again later, as the
+/// reconnect policy assumes
+pub fn is_retryable(code: ErrorCode) -> bool {
+    FailureClass::of(code) == FailureClass::Transient
+}
+
+/// Whether `code` is this side's doing, the local host having ended the link or the local
+/// controller having failed the command, rather than the peer's or the link's
+pub fn is_local_error(code: ErrorCode) -> bool {
+    matches!(
+        code,
+        ErrorCode::ConnectionTerminatedByLocalHost
+            | ErrorCode::UnknownHciCommand
+            | ErrorCode::HardwareFailure
+            | ErrorCode::MemoryCapacityExceeded
+            | ErrorCode::CommandDisallowed
+            | ErrorCode::InvalidHciCommandParameters
+            | ErrorCode::UnsuportedFeatureOrParameterValue
+    )
+}
@@ -0,0 +1,3843 @@
+use super::*;
+use crate::acl::core;
+use crate::acl::test_util::{self, FakeController, Stack};
+use bt_hci::ClassOfDevice;
+use bt_packets::hci::{
+    AclBuilder, AclChild, AuthenticationCompleteBuilder, BroadcastFlag, CommandCompleteBuilder,
+    CommandStatusBuilder, CompletedPackets, ConnectionCompleteBuilder, ConnectionRequestBuilder,
+    ConnectionRequestLinkType, CreateConnectionCancelCompleteBuilder, DisconnectionCompleteBuilder,
+    EncryptionChangeBuilder, HardwareErrorBuilder, HostNumCompletedPacketsBuilder,
+    IoCapabilityRequestBuilder, IoCapabilityRequestReplyCompleteBuilder,
+    LinkKeyNotificationBuilder, LinkKeyRequestBuilder, LinkKeyRequestNegativeReplyCompleteBuilder,
+    LinkKeyRequestReplyCompleteBuilder, LinkType, ModeChangeBuilder, OpCode, PacketBoundaryFlag,
+    QosSetupCompleteBuilder, ReadConnectionAcceptTimeoutCompleteBuilder,
+    ReadEncryptionKeySizeCompleteBuilder, ReadFailedContactCounterCompleteBuilder,
+    ReadLinkPolicySettingsCompleteBuilder, ReadLinkQualityCompleteBuilder,
+    ReadLinkSupervisionTimeoutCompleteBuilder, ReadLocalNameCompleteBuilder,
+    ReadLocalSupportedCodecsV1CompleteBuilder, ReadLocalSupportedCommandsCompleteBuilder,
+    ReadLocalSupportedFeaturesCompleteBuilder, ReadRemoteExtendedFeaturesCompleteBuilder,
+    ReadRemoteSupportedFeaturesCompleteBuilder, ReadRemoteVersionInformationCompleteBuilder,
+    ReadRssiCompleteBuilder, ReadTransmitPowerLevelCompleteBuilder,
+    ResetFailedContactCounterCompleteBuilder, RoleChangeBuilder, RoleDiscoveryCompleteBuilder,
+    SynchronousConnectionCompleteBuilder, UserConfirmationRequestBuilder,
+    UserConfirmationRequestReplyCompleteBuilder, WriteConnectionAcceptTimeoutCompleteBuilder,
+    WriteDefaultLinkPolicySettingsCompleteBuilder, WriteLinkPolicySettingsCompleteBuilder,
+    WriteLinkSupervisionTimeoutCompleteBuilder,
+};
+
+const ADDR_A: Address = Address { bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06] };
+const ADDR_B: Address = Address { bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x07] };
+const ADDR_C: Address = Address { bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x08] };
+const ADDR_D: Address = Address { bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x09] };
+
+async fn start_manager(rt: Arc<Runtime>) -> (AclManager, FakeController) {
+    start_manager_with(rt, AclConfig::default()).await
+}
+
+async fn start_manager_with(rt: Arc<Runtime>, config: AclConfig) -> (AclManager, FakeController) {
+    start_manager_supporting(rt, config, [0xff; 64]).await
+}
+
+async fn start_manager_supporting(
+    rt: Arc<Runtime>,
+    config: AclConfig,
+    supported_commands: [u8; 64],
+) -> (AclManager, FakeController) {
+    let (stack, controller) = test_util::start(rt.clone()).await;
+    start_manager_on(rt, stack, controller, config, supported_commands).await
+}
+
+async fn start_manager_on(
+    rt: Arc<Runtime>,
+    stack: Stack,
+    mut controller: FakeController,
+    config: AclConfig,
+    supported_commands: [u8; 64],
+) -> (AclManager, FakeController) {
+    let acl =
+        rt.spawn(provide_acl_manager(stack.hci, stack.events, stack.dispatch, config, rt.clone()));
+    let mut local_name = [0; 248];
+    local_name[..4].copy_from_slice(b"test");
+    controller
+        .expect_complete(
+            ReadLocalNameBuilder {},
+            ReadLocalNameCompleteBuilder {
+                num_hci_command_packets: 1,
+                status: ErrorCode::Success,
+                local_name,
+            },
+        )
+        .await;
+    controller
+        .expect_complete(
+            ReadLocalSupportedCodecsV1Builder {},
+            ReadLocalSupportedCodecsV1CompleteBuilder {
+                num_hci_command_packets: 1,
+                status: ErrorCode::Success,
+                supported_codecs: vec![],
+                vendor_specific_codecs: vec![],
+            },
+        )
+        .await;
+    controller
+        .expect_complete(
+            ReadLocalSupportedFeaturesBuilder {},
+            ReadLocalSupportedFeaturesCompleteBuilder {
+                num_hci_command_packets: 1,
+                status: ErrorCode::Success,
+                lmp_features: u64::MAX,
+            },
+        )
+        .await;
+    controller
+        .expect_complete(
+            ReadLocalSupportedCommandsBuilder {},
+            ReadLocalSupportedCommandsCompleteBuilder {
+                num_hci_command_packets: 1,
+                status: ErrorCode::Success,
+                supported_commands,
+            },
+        )
+        .await;
+    (acl.await.unwrap(), controller)
+}
+
+fn connection_complete(status: ErrorCode, bd_addr: Address) -> ConnectionCompleteBuilder {
+    ConnectionCompleteBuilder {
+        status,
+        connection_handle: 0x40,
+        bd_addr,
+        link_type: LinkType::Acl,
+        encryption_enabled: Enable::Disabled,
+    }
+}
+
+async fn next_event(acl: &mut AclManager) -> Event {
+    acl.evt_rx.lock().await.recv().await.unwrap()
+}
+
+#[test]
+fn connection_complete_captures_encryption() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                bd_addr: ADDR_A,
+                link_type: LinkType::Acl,
+                encryption_enabled: Enable::Enabled,
+            })
+            .await;
+
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert!(connection.is_encrypted()),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn connect_after_cancel_pages_again() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+
+        let mut canceller = acl.clone();
+        let cancel = rt.spawn(async move { canceller.cancel_connect(ADDR_A).await.unwrap() });
+        controller
+            .expect_complete(
+                CreateConnectionCancelBuilder { bd_addr: ADDR_A },
+                CreateConnectionCancelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_A,
+                },
+            )
+            .await;
+        assert_eq!(cancel.await.unwrap(), CancelOutcome::CancelSentToController);
+
+        // Arrives while the cancelled attempt is still outstanding at the controller
+        acl.connect(ADDR_A).await.unwrap();
+        controller.send_event(connection_complete(ErrorCode::UnknownConnection, ADDR_A)).await;
+
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(_) => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert_eq!(acl.cancel_connect(ADDR_A).await.unwrap(), CancelOutcome::AlreadyConnected);
+        assert_eq!(acl.cancel_connect(ADDR_B).await.unwrap(), CancelOutcome::NotFound);
+    });
+}
+
+#[test]
+fn cancel_all_connects_clears_the_queue_and_the_page() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        acl.connect(ADDR_B).await.unwrap();
+        acl.connect(ADDR_C).await.unwrap();
+
+        let mut canceller = acl.clone();
+        let cancel = rt.spawn(async move { canceller.cancel_all_connects().await.unwrap() });
+        controller
+            .expect_complete(
+                CreateConnectionCancelBuilder { bd_addr: ADDR_A },
+                CreateConnectionCancelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_A,
+                },
+            )
+            .await;
+        assert_eq!(cancel.await.unwrap(), vec![ADDR_A, ADDR_B, ADDR_C]);
+        controller.send_event(connection_complete(ErrorCode::UnknownConnection, ADDR_A)).await;
+        controller.expect_no_command(Duration::from_millis(100)).await;
+        let snapshot = acl.snapshot().await.unwrap();
+        assert_eq!((snapshot.pending, snapshot.queued_connects), (PendingKind::None, 0));
+
+        // Nothing left behind holds up the next connect
+        acl.connect(ADDR_D).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_D, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn disconnect_retries_while_controller_is_busy() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let disconnect = rt.spawn(async move {
+            connection.disconnect_no_wait(DisconnectReason::RemoteUserTerminatedConnection).await
+        });
+        let disconnect_cmd = || DisconnectBuilder {
+            connection_handle: 0x40,
+            reason: DisconnectReason::RemoteUserTerminatedConnection,
+        };
+        controller.expect_status(disconnect_cmd(), ErrorCode::CommandDisallowed).await;
+        controller.expect_status(disconnect_cmd(), ErrorCode::Success).await;
+        assert_eq!(disconnect.await.unwrap(), Ok(()));
+    });
+}
+
+#[test]
+fn overlapping_disconnects_send_one_command() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let reason = DisconnectReason::RemoteUserTerminatedConnection;
+        let (first_tx, first) = oneshot::channel();
+        let (second_tx, second) = oneshot::channel();
+        connection
+            .requests
+            .send(ConnectionRequest::Disconnect { reason, wait: false, fut: first_tx })
+            .await
+            .unwrap();
+        connection
+            .requests
+            .send(ConnectionRequest::Disconnect { reason, wait: false, fut: second_tx })
+            .await
+            .unwrap();
+        controller
+            .expect_status(
+                DisconnectBuilder { connection_handle: 0x40, reason },
+                ErrorCode::Success,
+            )
+            .await;
+        assert_eq!(first.await.unwrap(), Ok(()));
+        assert_eq!(second.await.unwrap(), Ok(()));
+
+        // A duplicate disconnect would reach the controller ahead of this
+        let discover = rt.spawn(async move { connection.discover_role().await });
+        controller
+            .expect_complete(
+                RoleDiscoveryBuilder { connection_handle: 0x40 },
+                RoleDiscoveryCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    current_role: Role::Central,
+                },
+            )
+            .await;
+        assert_eq!(discover.await.unwrap(), Ok(Role::Central));
+    });
+}
+
+#[test]
+fn connect_disconnect_cycles_leave_nothing_behind() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+        soak::run_cycles(&mut acl, &mut controller, ADDR_A, 20).await;
+    });
+}
+
+#[test]
+fn queued_connects_page_in_order_across_cancels() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        acl.connect(ADDR_B).await.unwrap();
+        acl.connect(ADDR_C).await.unwrap();
+        acl.connect(ADDR_D).await.unwrap();
+        assert_eq!(acl.cancel_connect(ADDR_B).await.unwrap(), CancelOutcome::CancelledQueued);
+
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_C, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn queued_connects_page_as_each_completes() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        acl.connect(ADDR_B).await.unwrap();
+        acl.connect(ADDR_C).await.unwrap();
+        for (handle, addr) in [(0x40, ADDR_A), (0x41, ADDR_B), (0x42, ADDR_C)] {
+            controller
+                .expect_status(
+                    build_create_connection(addr, None, DEFAULT_PACKET_TYPES),
+                    ErrorCode::Success,
+                )
+                .await;
+            controller
+                .send_event(ConnectionCompleteBuilder {
+                    connection_handle: handle,
+                    ..connection_complete(ErrorCode::Success, addr)
+                })
+                .await;
+            match next_event(&mut acl).await {
+                Event::ConnectSuccess(connection) => assert_eq!(connection.addr, addr),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        assert!(!acl.is_connecting(ADDR_C).await.unwrap());
+    });
+}
+
+#[test]
+fn duplicate_connect_leaves_manager_running() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _a = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        acl.connect(ADDR_A).await.unwrap();
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_B)
+            })
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_B),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn unexpected_connection_complete_leaves_manager_running() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        // Nothing was paged or accepted for this one, so the link is torn down again
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_B)
+            })
+            .await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x41,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_A),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn sco_connection_completes_do_not_make_acl_connections() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                link_type: LinkType::Sco,
+                ..connection_complete(ErrorCode::Success, ADDR_A)
+            })
+            .await;
+        // Neither disconnected as unexpected, nor taken as the pending connect
+        controller.expect_no_command(Duration::from_millis(100)).await;
+        assert!(acl.active_connections().await.unwrap().is_empty());
+        assert!(acl.is_connecting(ADDR_A).await.unwrap());
+
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.handle(), 0x40),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn data_round_trips_through_the_controller() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // An L2CAP basic frame: length, channel id, then the payload
+        let pdu = Bytes::from_static(&[0x05, 0x00, 0x40, 0x00, b'h', b'e', b'l', b'l', b'o']);
+        connection.send(pdu.clone()).await.unwrap();
+        let packet = controller.recv_acl().await;
+        assert_eq!(packet.get_handle(), 0x40);
+        controller.send_acl(packet).await;
+        assert_eq!(connection.recv().await, Some(pdu));
+
+        let stats = connection.stats();
+        assert_eq!(
+            stats,
+            TrafficStats { tx_bytes: 9, rx_bytes: 9, tx_packets: 1, rx_packets: 1, rx_dropped: 0 },
+        );
+    });
+}
+
+#[test]
+fn data_is_fragmented_to_the_controller_buffer_length_and_reassembled() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // The fake controller's buffers take 1021 bytes
+        for (len, fragments) in [(1021, 1), (1022, 2), (2043, 3)] {
+            let mut pdu = vec![0; len];
+            pdu[..4].copy_from_slice(&[(len - 4) as u8, ((len - 4) >> 8) as u8, 0x40, 0x00]);
+            for (i, byte) in pdu[4..].iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            let pdu = Bytes::from(pdu);
+            connection.send(pdu.clone()).await.unwrap();
+
+            let mut sent = 0;
+            for i in 0..fragments {
+                let packet = controller.recv_acl().await;
+                assert_eq!(
+                    packet.get_packet_boundary_flag(),
+                    if i == 0 {
+                        PacketBoundaryFlag::FirstAutomaticallyFlushable
+                    } else {
+                        PacketBoundaryFlag::ContinuingFragment
+                    }
+                );
+                let payload = match packet.specialize() {
+                    AclChild::Payload(payload) => payload,
+                    AclChild::None => panic!("fragment without a payload"),
+                };
+                assert_eq!(payload[..], pdu[sent..(sent + 1021).min(len)]);
+                sent += payload.len();
+                controller.send_acl(packet).await;
+            }
+            assert_eq!(connection.recv().await, Some(pdu));
+        }
+    });
+}
+
+#[test]
+fn connect_any_keeps_the_first_address_that_answers() {
+    test_util::run(|rt| async move {
+        let (acl, mut controller) = start_manager(rt.clone()).await;
+
+        let mut group_acl = acl.clone();
+        let group = rt.spawn(async move { group_acl.connect_any(vec![ADDR_A, ADDR_B]).await });
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_A)).await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        match group.await.unwrap() {
+            Ok(connection) => assert_eq!(connection.addr, ADDR_B),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn authenticated_connects_disconnect_a_link_still_authenticating_at_the_deadline() {
+    test_util::run(|rt| async move {
+        let (acl, mut controller) = start_manager(rt.clone()).await;
+
+        let mut secure_acl = acl.clone();
+        let secure = rt.spawn(async move {
+            secure_acl.connect_authenticated(ADDR_A, Duration::from_millis(200)).await
+        });
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                AuthenticationRequestedBuilder { connection_handle: 0x40 },
+                ErrorCode::Success,
+            )
+            .await;
+        // Authentication Complete never comes
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::AuthenticationFailure,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        assert_eq!(secure.await.unwrap().err(), Some(AclError::TimedOut));
+    });
+}
+
+#[test]
+fn incoming_connections_are_rejected_while_disabled() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+        let request = || ConnectionRequestBuilder {
+            bd_addr: ADDR_B,
+            class_of_device: ClassOfDevice { bytes: [0; 3] },
+            link_type: ConnectionRequestLinkType::Acl,
+        };
+
+        // Turned away even though the allowlist would let it in
+        acl.add_to_incoming_allowlist(ADDR_B).await.unwrap();
+        acl.set_incoming_enabled(false).await.unwrap();
+        controller.send_event(request()).await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_B, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingRejected { addr: ADDR_B, reason } => {
+                assert_eq!(reason, RejectConnectionReason::LimitedResources)
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller
+            .send_event(connection_complete(ErrorCode::ConnectionRejectedLimitedResources, ADDR_B))
+            .await;
+
+        acl.set_incoming_enabled(true).await.unwrap();
+        controller.send_event(request()).await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_B, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn disabling_incoming_rejects_a_deferred_request() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::new();
+        config.set_accept_policy(Arc::new(|_| AcceptDecision::Defer));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_B,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller.expect_no_command(Duration::from_millis(100)).await;
+        let (result, ()) = tokio::join!(
+            acl.set_incoming_enabled(false),
+            controller.expect_status(
+                build_reject_connection(ADDR_B, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+        );
+        assert_eq!(result, Ok(()));
+
+        // Too late to be accepted
+        acl.resolve_incoming(ADDR_B, true).await.unwrap();
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn rejected_incoming_requests_leave_an_outgoing_page_alone() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        acl.set_incoming_enabled(false).await.unwrap();
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_B,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_B, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+        assert!(matches!(next_event(&mut acl).await, Event::IncomingRejected { addr: ADDR_B, .. }));
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::ConnectionRejectedLimitedResources, ADDR_B)
+            })
+            .await;
+
+        // Still paging A, so nothing else is paged and A's link is kept
+        controller.expect_no_command(Duration::from_millis(100)).await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_A),
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+/// Starts a manager securing incoming links, and takes an incoming link from `ADDR_B` up to
+/// its authentication
+async fn start_auto_secured_incoming(rt: Arc<Runtime>) -> (AclManager, FakeController) {
+    let mut config = AclConfig::default();
+    config.set_auto_secure_incoming(true);
+    let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+    controller
+        .send_event(ConnectionRequestBuilder {
+            bd_addr: ADDR_B,
+            class_of_device: ClassOfDevice { bytes: [0; 3] },
+            link_type: ConnectionRequestLinkType::Acl,
+        })
+        .await;
+    controller
+        .expect_status(
+            build_accept_connection(ADDR_B, AcceptConnectionRequestRole::BecomeCentral),
+            ErrorCode::Success,
+        )
+        .await;
+    match next_event(&mut acl).await {
+        Event::IncomingConnecting { addr: ADDR_B } => {}
+        e => panic!("unexpected event {:?}", e),
+    }
+    controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+    controller
+        .expect_status(
+            AuthenticationRequestedBuilder { connection_handle: 0x40 },
+            ErrorCode::Success,
+        )
+        .await;
+    (acl, controller)
+}
+
+#[test]
+fn auto_secured_incoming_links_are_reported_once_encrypted() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_auto_secured_incoming(rt).await;
+
+        controller
+            .send_event(AuthenticationCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+            })
+            .await;
+        controller
+            .expect_status(
+                SetConnectionEncryptionBuilder {
+                    connection_handle: 0x40,
+                    encryption_enable: Enable::Enabled,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        assert!(acl.evt_rx.lock().await.try_recv().is_err());
+        controller
+            .send_event(EncryptionChangeBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                encryption_enabled: EncryptionEnabled::On,
+            })
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert!(connection.is_encrypted()),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn auto_secured_incoming_links_failing_authentication_are_failed() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_auto_secured_incoming(rt).await;
+
+        controller
+            .send_event(AuthenticationCompleteBuilder {
+                status: ErrorCode::AuthenticationFailure,
+                connection_handle: 0x40,
+            })
+            .await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::AuthenticationFailure,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason, .. } => {
+                assert_eq!(addr, ADDR_B);
+                assert_eq!(reason, ErrorCode::AuthenticationFailure);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        // Already on its way down, so it isn't disconnected twice
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn auto_secured_incoming_links_failing_encryption_are_failed() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_auto_secured_incoming(rt).await;
+
+        controller
+            .send_event(AuthenticationCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+            })
+            .await;
+        controller
+            .expect_status(
+                SetConnectionEncryptionBuilder {
+                    connection_handle: 0x40,
+                    encryption_enable: Enable::Enabled,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(EncryptionChangeBuilder {
+                status: ErrorCode::PinOrKeyMissing,
+                connection_handle: 0x40,
+                encryption_enabled: EncryptionEnabled::Off,
+            })
+            .await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::AuthenticationFailure,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason, .. } => {
+                assert_eq!(addr, ADDR_B);
+                assert_eq!(reason, ErrorCode::PinOrKeyMissing);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn waits_on_the_same_address_share_one_connect() {
+    test_util::run(|rt| async move {
+        let (acl, mut controller) = start_manager(rt.clone()).await;
+
+        let mut first_acl = acl.clone();
+        let first = rt.spawn(async move { first_acl.connect_and_wait(ADDR_A).await });
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        let mut second_acl = acl.clone();
+        let mut second = Box::pin(second_acl.connect_and_wait(ADDR_A));
+        assert!(futures::poll!(&mut second).is_pending());
+        // Requests are handled in order, so the second wait is taken once this is answered
+        assert!(acl.is_connecting(ADDR_A).await.unwrap());
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+
+        assert_eq!(first.await.unwrap().unwrap().addr, ADDR_A);
+        assert_eq!(second.await.err(), Some(AclError::Hci(ErrorCode::ConnectionAlreadyExists)));
+        // Nothing else went out for the second wait ahead of the disconnect this provokes
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn connection_info_only_covers_open_links() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        assert!(acl.connection_info(ADDR_A).await.unwrap().is_none());
+        assert!(acl.is_connecting(ADDR_A).await.unwrap());
+
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        let info = acl.connection_info(ADDR_A).await.unwrap().unwrap();
+        assert_eq!((info.addr, info.handle, info.role), (ADDR_A, 0x40, Role::Central));
+        assert!(acl.connection_info(ADDR_B).await.unwrap().is_none());
+    });
+}
+
+#[test]
+fn active_connections_follow_links_up_and_down() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let mut open = Vec::new();
+        for (handle, addr) in [(0x40, ADDR_A), (0x41, ADDR_B)] {
+            acl.connect(addr).await.unwrap();
+            controller
+                .expect_status(
+                    build_create_connection(addr, None, DEFAULT_PACKET_TYPES),
+                    ErrorCode::Success,
+                )
+                .await;
+            controller
+                .send_event(ConnectionCompleteBuilder {
+                    connection_handle: handle,
+                    ..connection_complete(ErrorCode::Success, addr)
+                })
+                .await;
+            match next_event(&mut acl).await {
+                Event::ConnectSuccess(connection) => open.push(connection),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        let mut active: Vec<(Address, u16)> =
+            acl.active_connections().await.unwrap().iter().map(|c| (c.addr, c.handle)).collect();
+        active.sort_by_key(|(_, handle)| *handle);
+        assert_eq!(active, vec![(ADDR_A, 0x40), (ADDR_B, 0x41)]);
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        match open[0].channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected {
+                local: false,
+                cause: DisconnectCause::RemoteUserTerminated,
+                ..
+            }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        let active = acl.active_connections().await.unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].addr, ADDR_B);
+    });
+}
+
+#[test]
+fn connection_timers_stop_with_the_link() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        let interval = Duration::from_millis(100);
+        config.set_link_risk(Some(LinkRiskThresholds {
+            interval,
+            failed_contacts: 100,
+            rssi: -100,
+        }));
+        let (mut acl, mut controller) = start_manager_with(rt.clone(), config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // The link quality check is running
+        controller
+            .expect_complete(
+                ReadFailedContactCounterBuilder { connection_handle: 0x40 },
+                ReadFailedContactCounterCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    failed_contact_counter: 0,
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadRssiBuilder { connection_handle: 0x40 },
+                ReadRssiCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    rssi: 0,
+                },
+            )
+            .await;
+
+        // And so is the disconnect retry
+        let reason = DisconnectReason::RemoteUserTerminatedConnection;
+        let disconnect = rt.spawn(async move { connection.disconnect(reason).await });
+        controller
+            .expect_status(
+                DisconnectBuilder { connection_handle: 0x40, reason },
+                ErrorCode::CommandDisallowed,
+            )
+            .await;
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        assert_eq!(disconnect.await.unwrap(), Ok(()));
+
+        controller.expect_no_command(interval * 3).await;
+    });
+}
+
+#[test]
+fn links_without_data_are_disconnected_once_idle() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        let timeout = Duration::from_millis(300);
+        config.set_idle_timeout(Some(timeout));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // Received data keeps the link up for another timeout
+        tokio::time::sleep(timeout / 2).await;
+        let pdu = Bytes::from_static(&[0x01, 0x00, 0x40, 0x00, 0x00]);
+        connection.send(pdu.clone()).await.unwrap();
+        let packet = controller.recv_acl().await;
+        controller.send_acl(packet).await;
+        assert_eq!(connection.recv().await, Some(pdu));
+        controller.expect_no_command(timeout * 2 / 3).await;
+
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTerminatedByLocalHost,
+            })
+            .await;
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected { local: true, .. }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn links_not_down_in_time_after_a_disconnect_are_dropped() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_disconnect_timeout(Duration::from_millis(200));
+        let (mut acl, mut controller) = start_manager_with(rt.clone(), config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let reason = DisconnectReason::RemoteUserTerminatedConnection;
+        let (result, ()) = tokio::join!(
+            connection.disconnect(reason),
+            controller.expect_status(
+                DisconnectBuilder { connection_handle: 0x40, reason },
+                ErrorCode::Success
+            )
+        );
+        assert_eq!(result, Ok(()));
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected {
+                reason: ErrorCode::ConnectionTimeout,
+                local: true,
+                cause: DisconnectCause::LocalHost,
+            }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        assert!(acl.active_connections().await.unwrap().is_empty());
+
+        // The handle was let go of, so the controller can give it out again
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.handle(), 0x40),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn links_timing_out_on_their_own_are_reported_as_lost() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTimeout,
+            })
+            .await;
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected {
+                local: false,
+                cause: DisconnectCause::LinkLoss,
+                ..
+            }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn links_lost_with_auto_reconnect_are_connected_again() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams {
+            clock_offset: Some(0x1234),
+            auto_reconnect_on_link_loss: true,
+            ..Default::default()
+        };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, Some(0x1234), DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTimeout,
+            })
+            .await;
+        let evt_rx = &mut connection.channels.as_mut().unwrap().evt_rx;
+        assert!(matches!(evt_rx.recv().await, Some(ConnectionEvent::Reconnecting)));
+        assert!(matches!(
+            evt_rx.recv().await,
+            Some(ConnectionEvent::Disconnected { cause: DisconnectCause::LinkLoss, .. })
+        ));
+
+        // Paged again as it was first, and with no reconnect policy failed for good at once
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, Some(0x1234), DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_A, reason: ErrorCode::PageTimeout, .. } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn links_suspended_are_connected_again_as_they_were_on_resume() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams { clock_offset: Some(0x1234), ..Default::default() };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, Some(0x1234), DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let (suspended, _) = tokio::join!(acl.on_system_suspend(), async {
+            controller
+                .expect_status(
+                    ReadClockOffsetBuilder { connection_handle: 0x40 },
+                    ErrorCode::UnknownConnection,
+                )
+                .await;
+            controller
+                .expect_status(
+                    DisconnectBuilder {
+                        connection_handle: 0x40,
+                        reason: DisconnectReason::RemoteUserTerminatedConnection,
+                    },
+                    ErrorCode::Success,
+                )
+                .await;
+        });
+        suspended.unwrap();
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTerminatedByLocalHost,
+            })
+            .await;
+
+        // Paged with the parameters it was first connected with
+        acl.on_system_resume().await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, Some(0x1234), DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn dropped_links_lost_with_auto_reconnect_are_connected_again() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams { auto_reconnect_on_link_loss: true, ..Default::default() };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => drop(connection),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // Nobody is left to tell, which is no reason not to page it again
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTimeout,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn hardware_errors_drop_every_link() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let mut connections = Vec::new();
+        for (addr, handle) in [(ADDR_A, 0x40), (ADDR_B, 0x41)] {
+            acl.connect(addr).await.unwrap();
+            controller
+                .expect_status(
+                    build_create_connection(addr, None, DEFAULT_PACKET_TYPES),
+                    ErrorCode::Success,
+                )
+                .await;
+            controller
+                .send_event(ConnectionCompleteBuilder {
+                    connection_handle: handle,
+                    ..connection_complete(ErrorCode::Success, addr)
+                })
+                .await;
+            match next_event(&mut acl).await {
+                Event::ConnectSuccess(connection) => connections.push(connection),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+
+        controller.send_event(HardwareErrorBuilder { hardware_code: 0x01 }).await;
+        for connection in &mut connections {
+            match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+                Some(ConnectionEvent::Disconnected {
+                    reason: ErrorCode::HardwareFailure, ..
+                }) => {}
+                e => panic!("unexpected connection event {:?}", e),
+            }
+        }
+        assert!(matches!(next_event(&mut acl).await, Event::ControllerReset));
+        assert!(acl.active_connections().await.unwrap().is_empty());
+
+        // The controller is free to give the handles out again
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.handle(), 0x40),
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn floods_of_events_through_full_channels_are_delayed_but_not_lost() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::new();
+        config.set_channel_capacities(ChannelCapacities {
+            requests: 1,
+            manager_events: 1,
+            hci_events: 1,
+            connection_requests: 1,
+            connection_events: 1,
+        });
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        const FLOOD: u16 = 64;
+        let evt_rx = &mut connection.channels.as_mut().unwrap().evt_rx;
+        let ((), intervals) = tokio::join!(
+            async {
+                for interval in 1..=FLOOD {
+                    controller
+                        .send_event(ModeChangeBuilder {
+                            status: ErrorCode::Success,
+                            connection_handle: 0x40,
+                            current_mode: Mode::Sniff,
+                            interval,
+                        })
+                        .await;
+                }
+            },
+            async {
+                // Falls behind, so the flood backs up
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let mut intervals = Vec::new();
+                while intervals.len() < FLOOD as usize {
+                    match evt_rx.recv().await {
+                        Some(ConnectionEvent::ModeChanged { interval, .. }) => {
+                            intervals.push(interval)
+                        }
+                        e => panic!("unexpected connection event {:?}", e),
+                    }
+                }
+                intervals
+            }
+        );
+        assert_eq!(intervals, (1..=FLOOD).collect::<Vec<_>>());
+        assert!(acl.metrics().connection_events.full > 0);
+    });
+}
+
+#[test]
+fn a_reused_handle_replaces_the_link_that_had_it() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut stale = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // The controller hands 0x40 out again without reporting the first link down
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        match stale.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected { cause: DisconnectCause::LinkLoss, .. }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        let active: Vec<_> =
+            acl.active_connections().await.unwrap().iter().map(|c| (c.addr, c.handle)).collect();
+        assert_eq!(active, vec![(ADDR_B, 0x40)]);
+
+        // The stale link winding down left the new one registered
+        let pdu = Bytes::from_static(&[0x01, 0x00, 0x40, 0x00, b'b']);
+        connection.send(pdu.clone()).await.unwrap();
+        let packet = controller.recv_acl().await;
+        assert_eq!(packet.get_handle(), 0x40);
+        controller.send_acl(packet).await;
+        assert_eq!(connection.recv().await, Some(pdu));
+    });
+}
+
+#[test]
+fn subscribers_only_get_the_events_they_selected() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        let mut disconnects = connection.subscribe(ConnectionEventMask::DISCONNECTED).await;
+
+        controller
+            .send_event(ModeChangeBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                current_mode: Mode::Sniff,
+                interval: 0x0320,
+            })
+            .await;
+        // The connection's own events still get everything
+        let events = &mut connection.channels.as_mut().unwrap().evt_rx;
+        assert!(matches!(events.recv().await, Some(ConnectionEvent::ModeChanged { .. })));
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        assert!(matches!(events.recv().await, Some(ConnectionEvent::Disconnected { .. })));
+        assert!(matches!(disconnects.recv().await, Some(ConnectionEvent::Disconnected { .. })));
+        assert!(disconnects.recv().await.is_none());
+    });
+}
+
+#[test]
+fn disconnect_resolves_outstanding_requests() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // Waits on the remote features, which never arrive
+        let switch = rt.spawn(async move { connection.switch_role(Role::Peripheral).await });
+        controller
+            .expect_status(
+                ReadRemoteSupportedFeaturesBuilder { connection_handle: 0x40 },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        assert_eq!(switch.await.unwrap(), Err(AclError::Disconnected));
+    });
+}
+
+#[test]
+fn failed_authentication_reports_its_status() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        controller
+            .send_event(AuthenticationCompleteBuilder {
+                status: ErrorCode::AuthenticationFailure,
+                connection_handle: 0x40,
+            })
+            .await;
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::AuthenticationComplete(ErrorCode::AuthenticationFailure)) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn remote_features_and_version_are_read() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let read = rt.spawn(async move {
+            let features = connection.read_remote_features().await;
+            (connection, features)
+        });
+        controller
+            .expect_status(
+                ReadRemoteSupportedFeaturesBuilder { connection_handle: 0x40 },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ReadRemoteSupportedFeaturesCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                lmp_features: 0x8765_4321,
+            })
+            .await;
+        let (mut connection, features) = read.await.unwrap();
+        assert_eq!(features, Ok(0x8765_4321));
+        assert_eq!(connection.view().remote_features().await, Some(0x8765_4321));
+
+        let read = rt.spawn(async move { connection.read_remote_version().await });
+        controller
+            .expect_status(
+                ReadRemoteVersionInformationBuilder { connection_handle: 0x40 },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ReadRemoteVersionInformationCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                version: 0x0b,
+                manufacturer_name: 0x001d,
+                sub_version: 0x1234,
+            })
+            .await;
+        let version =
+            RemoteVersion { lmp_version: 0x0b, manufacturer_name: 0x001d, lmp_subversion: 0x1234 };
+        assert_eq!(read.await.unwrap(), Ok(version));
+    });
+}
+
+#[test]
+fn local_codecs_read_failure_is_kept() {
+    test_util::run(|rt| async move {
+        let (stack, mut controller) = test_util::start(rt.clone()).await;
+        let acl = rt.spawn(provide_acl_manager(
+            stack.hci,
+            stack.events,
+            stack.dispatch,
+            AclConfig::default(),
+            rt.clone(),
+        ));
+        controller
+            .expect_complete(
+                ReadLocalNameBuilder {},
+                ReadLocalNameCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    local_name: [0; 248],
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedCodecsV1Builder {},
+                ReadLocalSupportedCodecsV1CompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::UnknownHciCommand,
+                    supported_codecs: vec![],
+                    vendor_specific_codecs: vec![],
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedFeaturesBuilder {},
+                ReadLocalSupportedFeaturesCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    lmp_features: u64::MAX,
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedCommandsBuilder {},
+                ReadLocalSupportedCommandsCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    supported_commands: [0xff; 64],
+                },
+            )
+            .await;
+        let acl = acl.await.unwrap();
+
+        assert_eq!(acl.local_codecs(), Err(ErrorCode::UnknownHciCommand));
+        assert_eq!(acl.local_vendor_codecs(), Err(ErrorCode::UnknownHciCommand));
+    });
+}
+
+#[test]
+fn an_event_claimed_elsewhere_fails_the_manager_by_name() {
+    test_util::run(|rt| async move {
+        let (stack, mut controller) = test_util::start(rt.clone()).await;
+        // Some other module got to Connection Complete first
+        let mut events = stack.events.clone();
+        let (claim_tx, _claim_rx) = channel(1);
+        events.register(EventCode::ConnectionComplete, claim_tx).await;
+
+        let acl = rt.spawn(provide_acl_manager(
+            stack.hci,
+            stack.events,
+            stack.dispatch,
+            AclConfig::default(),
+            rt.clone(),
+        ));
+        controller
+            .expect_complete(
+                ReadLocalNameBuilder {},
+                ReadLocalNameCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    local_name: [0; 248],
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedCodecsV1Builder {},
+                ReadLocalSupportedCodecsV1CompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    supported_codecs: vec![],
+                    vendor_specific_codecs: vec![],
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedFeaturesBuilder {},
+                ReadLocalSupportedFeaturesCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    lmp_features: u64::MAX,
+                },
+            )
+            .await;
+        controller
+            .expect_complete(
+                ReadLocalSupportedCommandsBuilder {},
+                ReadLocalSupportedCommandsCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    supported_commands: [0xff; 64],
+                },
+            )
+            .await;
+
+        // Rather than a manager that would never hear of a connection
+        let panic = match acl.await {
+            Ok(_) => panic!("manager started without Connection Complete"),
+            Err(e) => e.into_panic(),
+        };
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains("ConnectionComplete"), "{}", message);
+    });
+}
+
+#[test]
+fn shutdown_drops_connect_completing_during_teardown() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_B)
+            })
+            .await;
+        let _open = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        acl.connect(ADDR_C).await.unwrap();
+
+        acl.stop();
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_C, .. } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        // The pending connect is cancelled before any open link is disconnected
+        controller
+            .expect_complete(
+                CreateConnectionCancelBuilder { bd_addr: ADDR_A },
+                CreateConnectionCancelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_A,
+                },
+            )
+            .await;
+        let power_off = DisconnectReason::RemoteDeviceTerminatedConnectionPowerOff;
+        controller
+            .expect_status(
+                DisconnectBuilder { connection_handle: 0x41, reason: power_off },
+                ErrorCode::Success,
+            )
+            .await;
+
+        // Completed at the controller before the cancel reached it
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                DisconnectBuilder { connection_handle: 0x40, reason: power_off },
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_A, reason, .. } => {
+                assert_eq!(reason, ErrorCode::ConnectionTerminatedByLocalHost)
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // Nothing new is let in either
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_D,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_D, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn disconnect_all_holds_new_connects_back_until_every_link_is_down() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _open = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let reason = DisconnectReason::RemoteUserTerminatedConnection;
+        let mut bulk = acl.clone();
+        let all_down = rt.spawn(async move { bulk.disconnect_all(reason).await });
+        controller
+            .expect_status(
+                DisconnectBuilder { connection_handle: 0x40, reason },
+                ErrorCode::Success,
+            )
+            .await;
+
+        // Outgoing connects wait their turn, while incoming ones are turned away
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_C,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_C, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(connection_complete(ErrorCode::ConnectionRejectedLimitedResources, ADDR_C))
+            .await;
+        controller.expect_no_command(Duration::from_millis(100)).await;
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::ConnectionTerminatedByLocalHost,
+            })
+            .await;
+        assert_eq!(all_down.await.unwrap(), Ok(()));
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+fn limited_to_one(when_full: WhenFull) -> AclConfig {
+    let mut config = AclConfig::default();
+    config.set_connection_limit(Some(ConnectionLimit { max: 1, when_full }));
+    config
+}
+
+#[test]
+fn dropped_connections_dont_stall_on_events_nobody_reads() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) =
+            start_manager_with(rt, limited_to_one(WhenFull::Queue)).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => drop(connection),
+            e => panic!("unexpected event {:?}", e),
+        }
+        acl.connect(ADDR_B).await.unwrap();
+
+        // More than fit in the parked event receiver
+        for i in 0..3 * ChannelCapacities::default().connection_events {
+            let current_mode = if i % 2 == 0 { Mode::Sniff } else { Mode::Active };
+            controller
+                .send_event(ModeChangeBuilder {
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    current_mode,
+                    interval: 0x20,
+                })
+                .await;
+        }
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+
+        // Torn down, making room for the queued connect
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        assert!(acl.active_connections().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) =
+            start_manager_with(rt, limited_to_one(WhenFull::Fail)).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _a = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        acl.connect(ADDR_B).await.unwrap();
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_B, reason, .. } => {
+                assert_eq!(reason, ErrorCode::ConnectionLimitExceeded)
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_C,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_C, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingRejected { addr: ADDR_C, reason } => {
+                assert_eq!(reason, RejectConnectionReason::LimitedResources)
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn connects_over_the_limit_wait_for_a_free_slot() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) =
+            start_manager_with(rt, limited_to_one(WhenFull::Queue)).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let _a = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        acl.connect(ADDR_B).await.unwrap();
+        assert!(acl.is_connecting(ADDR_B).await.unwrap());
+        controller.expect_no_command(Duration::from_millis(50)).await;
+        let snapshot = ManagerSnapshot {
+            active_connections: 1,
+            queued_connects: 1,
+            retrying_connects: 0,
+            pending: PendingKind::None,
+        };
+        assert_eq!(acl.snapshot().await.unwrap(), snapshot);
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn rssi_is_read_from_the_command_complete() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let read = rt.spawn(async move { connection.read_rssi().await });
+        controller
+            .expect_complete(
+                ReadRssiBuilder { connection_handle: 0x40 },
+                ReadRssiCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    rssi: -6i8 as u8,
+                },
+            )
+            .await;
+        assert_eq!(read.await.unwrap(), Ok(-6));
+    });
+}
+
+#[test]
+fn link_health_is_read_for_its_own_handle() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let read = rt.spawn(async move {
+            let quality = connection.read_link_quality().await;
+            (connection, quality)
+        });
+        controller
+            .expect_complete(
+                ReadLinkQualityBuilder { connection_handle: 0x40 },
+                ReadLinkQualityCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    link_quality: 0xf0,
+                },
+            )
+            .await;
+        let (mut connection, quality) = read.await.unwrap();
+        assert_eq!(quality, Ok(0xf0));
+
+        let read = rt.spawn(async move {
+            let failed = connection.read_failed_contact_counter().await;
+            (connection, failed)
+        });
+        // Answered for another link
+        controller
+            .expect_complete(
+                ReadFailedContactCounterBuilder { connection_handle: 0x40 },
+                ReadFailedContactCounterCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x41,
+                    failed_contact_counter: 3,
+                },
+            )
+            .await;
+        let (mut connection, failed) = read.await.unwrap();
+        assert_eq!(failed, Err(ErrorCode::UnspecifiedError));
+
+        let reset = rt.spawn(async move { connection.reset_failed_contact_counter().await });
+        controller
+            .expect_complete(
+                ResetFailedContactCounterBuilder { connection_handle: 0x40 },
+                ResetFailedContactCounterCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                },
+            )
+            .await;
+        assert_eq!(reset.await.unwrap(), Ok(()));
+    });
+}
+
+#[test]
+fn subscribers_each_hear_of_every_connect() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+        let mut first = acl.subscribe();
+        let mut second = acl.subscribe();
+
+        let mut waiter = acl.clone();
+        let wait = rt.spawn(async move { waiter.connect_and_wait(ADDR_A).await });
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        assert!(wait.await.unwrap().is_ok());
+
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_B)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_B, .. } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        for rx in [&mut first, &mut second] {
+            assert_eq!(rx.recv().await, Ok(EventInfo::Connected { addr: ADDR_A, handle: 0x40 }));
+            assert_eq!(
+                rx.recv().await,
+                Ok(EventInfo::ConnectFailed {
+                    addr: ADDR_B,
+                    reason: ErrorCode::PageTimeout,
+                    class: FailureClass::Transient,
+                })
+            );
+        }
+    });
+}
+
+#[test]
+fn qos_setup_reports_and_reserves_what_was_granted() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let qos = QosParams {
+            service_type: ServiceType::Guaranteed,
+            token_rate: 40_000,
+            peak_bandwidth: 50_000,
+            latency: 20_000,
+            delay_variation: 0xffff_ffff,
+        };
+        let setup = rt.spawn(async move { connection.setup_qos(qos).await });
+        controller
+            .expect_status(
+                QosSetupBuilder {
+                    connection_handle: 0x40,
+                    service_type: ServiceType::Guaranteed,
+                    token_rate: 40_000,
+                    peak_bandwidth: 50_000,
+                    latency: 20_000,
+                    delay_variation: 0xffff_ffff,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(QosSetupCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                service_type: ServiceType::Guaranteed,
+                token_rate: 30_000,
+                peak_bandwidth: 50_000,
+                latency: 25_000,
+                delay_variation: 0xffff_ffff,
+            })
+            .await;
+        let granted = QosResult {
+            service_type: ServiceType::Guaranteed,
+            token_rate: 30_000,
+            peak_bandwidth: 50_000,
+            latency: 25_000,
+            delay_variation: 0xffff_ffff,
+        };
+        assert_eq!(setup.await.unwrap(), Ok(granted));
+        assert_eq!(acl.reserved_bandwidth(), 30_000);
+    });
+}
+
+fn retrying_once(base_delay: Duration) -> AclConfig {
+    let mut config = AclConfig::default();
+    config.set_reconnect_policy(Some(ReconnectPolicy {
+        max_attempts: 1,
+        base_delay,
+        max_delay: base_delay,
+    }));
+    config
+}
+
+#[test]
+fn transient_failures_are_retried_until_attempts_run_out() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) =
+            start_manager_with(rt, retrying_once(Duration::from_millis(20))).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        for _ in 0..2 {
+            controller
+                .expect_status(
+                    build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                    ErrorCode::Success,
+                )
+                .await;
+            controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_A)).await;
+        }
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr: ADDR_A, reason, .. } => {
+                assert_eq!(reason, ErrorCode::PageTimeout)
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(!acl.is_connecting(ADDR_A).await.unwrap());
+    });
+}
+
+#[test]
+fn cancel_connect_drops_a_waiting_retry() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) =
+            start_manager_with(rt, retrying_once(Duration::from_millis(500))).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_A)).await;
+        assert!(acl.is_connecting(ADDR_A).await.unwrap());
+
+        assert_eq!(acl.cancel_connect(ADDR_A).await.unwrap(), CancelOutcome::CancelledQueued);
+        assert!(!acl.is_connecting(ADDR_A).await.unwrap());
+        controller.expect_no_command(Duration::from_millis(800)).await;
+    });
+}
+
+#[test]
+fn repeated_connects_to_one_address_page_it_once() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_B).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        // Queued once behind the connect to B
+        for _ in 0..3 {
+            acl.connect(ADDR_A).await.unwrap();
+        }
+        assert_eq!(acl.snapshot().await.unwrap().queued_connects, 1);
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_B)).await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        // And not queued again while it pages
+        acl.connect(ADDR_A).await.unwrap();
+        assert_eq!(acl.snapshot().await.unwrap().queued_connects, 0);
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn accepted_incoming_connections_are_announced() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_B,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_B, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingConnecting { addr: ADDR_B } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(acl.is_connecting(ADDR_B).await.unwrap());
+        assert_eq!(acl.cancel_connect(ADDR_B).await.unwrap(), CancelOutcome::IncomingInProgress);
+
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_B),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(!acl.is_connecting(ADDR_B).await.unwrap());
+    });
+}
+
+#[test]
+fn requests_from_connected_peers_are_rejected_with_the_configured_reason() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_duplicate_incoming(DuplicateIncoming::Reject(
+            RejectConnectionReason::LimitedResources,
+        ));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(_) => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_A,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_A, RejectConnectionReason::LimitedResources),
+                ErrorCode::Success,
+            )
+            .await;
+        assert_eq!(acl.active_connections().await.unwrap().len(), 1);
+    });
+}
+
+struct OutOfRange;
+
+impl NextConnectionStrategy for OutOfRange {
+    fn pick(&self, candidates: &[QueuedConnect]) -> usize {
+        candidates.len()
+    }
+}
+
+#[test]
+fn strategies_picking_past_the_queue_page_the_first_connect() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::new();
+        config.set_next_connection_strategy(Arc::new(OutOfRange));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        acl.connect(ADDR_B).await.unwrap();
+        acl.connect(ADDR_C).await.unwrap();
+        controller.send_event(connection_complete(ErrorCode::PageTimeout, ADDR_A)).await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn deferred_requests_from_connected_peers_only_replace_the_link_once_accepted() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_duplicate_incoming(DuplicateIncoming::ReplaceExisting);
+        config.set_accept_policy(Arc::new(|_| AcceptDecision::Defer));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        assert!(matches!(next_event(&mut acl).await, Event::ConnectSuccess(_)));
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_A,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller.expect_no_command(Duration::from_millis(100)).await;
+
+        // Turned down, so the link it would have replaced stays
+        let (result, ()) = tokio::join!(
+            acl.resolve_incoming(ADDR_A, false),
+            controller.expect_status(
+                build_reject_connection(ADDR_A, RejectConnectionReason::SecurityReasons),
+                ErrorCode::Success,
+            )
+        );
+        assert_eq!(result, Ok(()));
+        controller.expect_no_command(Duration::from_millis(100)).await;
+        assert_eq!(acl.active_connections().await.unwrap().len(), 1);
+    });
+}
+
+#[test]
+fn requests_from_connected_peers_can_replace_the_stale_link() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_duplicate_incoming(DuplicateIncoming::ReplaceExisting);
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut stale = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_A,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        // The controller had already dropped the old link, so the manager does too
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::UnknownConnection,
+            )
+            .await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_A, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+        match stale.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected {
+                reason: ErrorCode::ConnectionTimeout, ..
+            }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        match next_event(&mut acl).await {
+            Event::IncomingConnecting { addr: ADDR_A } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_A)
+            })
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.handle(), 0x41),
+            e => panic!("unexpected event {:?}", e),
+        }
+        let active: Vec<_> =
+            acl.active_connections().await.unwrap().iter().map(|c| (c.addr, c.handle)).collect();
+        assert_eq!(active, vec![(ADDR_A, 0x41)]);
+    });
+}
+
+#[test]
+fn only_allowlisted_peers_can_connect_in() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+        acl.add_to_incoming_allowlist(ADDR_B).await.unwrap();
+        let request = |bd_addr| ConnectionRequestBuilder {
+            bd_addr,
+            class_of_device: ClassOfDevice { bytes: [0; 3] },
+            link_type: ConnectionRequestLinkType::Acl,
+        };
+
+        controller.send_event(request(ADDR_C)).await;
+        controller
+            .expect_status(
+                build_reject_connection(ADDR_C, RejectConnectionReason::UnacceptableBdAddr),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingRejected { addr: ADDR_C, .. } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        controller.send_event(request(ADDR_B)).await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_B, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingConnecting { addr: ADDR_B } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_B)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_B),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // An empty allowlist lets everyone in again
+        acl.remove_from_incoming_allowlist(ADDR_B).await.unwrap();
+        controller.send_event(request(ADDR_C)).await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_C, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn link_policy_is_written_and_read_back() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // Role switches left out
+        let policy = LinkPolicy::HOLD_MODE | LinkPolicy::SNIFF_MODE;
+        let (result, ()) = tokio::join!(
+            connection.write_link_policy(policy),
+            controller.expect_complete(
+                WriteLinkPolicySettingsBuilder {
+                    connection_handle: 0x40,
+                    link_policy_settings: 0x06
+                },
+                WriteLinkPolicySettingsCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                },
+            )
+        );
+        assert_eq!(result, Ok(()));
+
+        let (result, ()) = tokio::join!(
+            connection.read_link_policy(),
+            controller.expect_complete(
+                ReadLinkPolicySettingsBuilder { connection_handle: 0x40 },
+                ReadLinkPolicySettingsCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    link_policy_settings: 0x8006,
+                },
+            )
+        );
+        let read = result.unwrap();
+        assert_eq!(read, policy);
+        assert!(!read.contains(LinkPolicy::ROLE_SWITCH));
+    });
+}
+
+#[test]
+fn supervision_timeout_is_written_and_read_back() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // Turning it off isn't allowed by default, and isn't sent
+        assert_eq!(
+            connection.write_link_supervision_timeout(0).await,
+            Err(ErrorCode::InvalidHciCommandParameters)
+        );
+
+        let (result, ()) = tokio::join!(
+            connection.write_link_supervision_timeout(0x0c80),
+            controller.expect_complete(
+                WriteLinkSupervisionTimeoutBuilder {
+                    connection_handle: 0x40,
+                    link_supervision_timeout: 0x0c80,
+                },
+                WriteLinkSupervisionTimeoutCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                },
+            )
+        );
+        assert_eq!(result, Ok(()));
+
+        let (result, ()) = tokio::join!(
+            connection.read_link_supervision_timeout(),
+            controller.expect_complete(
+                ReadLinkSupervisionTimeoutBuilder { connection_handle: 0x40 },
+                ReadLinkSupervisionTimeoutCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    link_supervision_timeout: 0x0c80,
+                },
+            )
+        );
+        assert_eq!(result, Ok(0x0c80));
+    });
+}
+
+#[test]
+fn tx_power_is_read_for_the_requested_kind() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let (result, ()) = tokio::join!(
+            connection.read_tx_power(TxPowerKind::Current),
+            controller.expect_complete(
+                ReadTransmitPowerLevelBuilder {
+                    connection_handle: 0x40,
+                    transmit_power_level_type: TransmitPowerLevelType::Current,
+                },
+                ReadTransmitPowerLevelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    transmit_power_level: 0xfc,
+                },
+            )
+        );
+        assert_eq!(result, Ok(-4));
+
+        let (result, ()) = tokio::join!(
+            connection.read_tx_power(TxPowerKind::Maximum),
+            controller.expect_complete(
+                ReadTransmitPowerLevelBuilder {
+                    connection_handle: 0x40,
+                    transmit_power_level_type: TransmitPowerLevelType::Maximum,
+                },
+                ReadTransmitPowerLevelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    transmit_power_level: 20,
+                },
+            )
+        );
+        assert_eq!(result, Ok(20));
+    });
+}
+
+#[test]
+fn tx_power_reads_the_controller_lacks_are_not_sent() {
+    test_util::run(|rt| async move {
+        let mut supported_commands = [0xff; 64];
+        // Read Transmit Power Level is octet 10, bit 2
+        supported_commands[10] &= !(1 << 2);
+        let (mut acl, mut controller) =
+            start_manager_supporting(rt, AclConfig::default(), supported_commands).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        assert_eq!(
+            connection.read_tx_power(TxPowerKind::Current).await,
+            Err(ErrorCode::UnknownHciCommand)
+        );
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn clock_reads_the_controller_lacks_are_not_sent() {
+    test_util::run(|rt| async move {
+        let mut supported_commands = [0xff; 64];
+        // Read Clock is octet 15, bit 7
+        supported_commands[15] &= !(1 << 7);
+        let (mut acl, mut controller) =
+            start_manager_supporting(rt, AclConfig::default(), supported_commands).await;
+        assert!(!acl.supports(ControllerFeature::ReadClock));
+        assert!(acl.supports(ControllerFeature::ReadTxPower));
+        assert!(acl.supports(ControllerFeature::SniffMode));
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        assert_eq!(
+            connection.read_clock(ClockKind::Local).await,
+            Err(ErrorCode::UnknownHciCommand)
+        );
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn links_already_in_the_required_role_are_reported_right_away() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams { require_role: Some(Role::Central), ..Default::default() };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        // The peer isn't given the chance to take central away
+        controller
+            .expect_status(
+                CreateConnectionBuilder {
+                    allow_role_switch: CreateConnectionRoleSwitch::RemainCentral,
+                    ..build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES)
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        assert!(matches!(next_event(&mut acl).await, Event::ConnectSuccess(_)));
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn links_in_the_wrong_role_are_reported_once_switched() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams { require_role: Some(Role::Peripheral), ..Default::default() };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                SwitchRoleBuilder { bd_addr: ADDR_A, role: Role::Peripheral },
+                ErrorCode::Success,
+            )
+            .await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), next_event(&mut acl))
+            .await
+            .is_err());
+
+        controller
+            .send_event(RoleChangeBuilder {
+                status: ErrorCode::Success,
+                bd_addr: ADDR_A,
+                new_role: Role::Peripheral,
+            })
+            .await;
+        let connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(connection.role().await, Role::Peripheral);
+    });
+}
+
+#[test]
+fn links_that_fail_to_switch_to_the_required_role_are_dropped() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectParams { require_role: Some(Role::Peripheral), ..Default::default() };
+        acl.connect_with_params(ADDR_A, params).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                SwitchRoleBuilder { bd_addr: ADDR_A, role: Role::Peripheral },
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(RoleChangeBuilder {
+                status: ErrorCode::RoleChangeNotAllowed,
+                bd_addr: ADDR_A,
+                new_role: Role::Central,
+            })
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason, .. } => {
+                assert_eq!(addr, ADDR_A);
+                assert_eq!(reason, ErrorCode::RoleChangeNotAllowed);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
+
+#[test]
+fn encryption_key_size_is_read_from_the_controller() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                encryption_enabled: Enable::Enabled,
+                ..connection_complete(ErrorCode::Success, ADDR_A)
+            })
+            .await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let (result, ()) = tokio::join!(
+            connection.read_encryption_key_size(),
+            controller.expect_complete(
+                ReadEncryptionKeySizeBuilder { connection_handle: 0x40 },
+                ReadEncryptionKeySizeCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    key_size: 7,
+                },
+            )
+        );
+        assert_eq!(result, Ok(7));
+    });
+}
+
+#[test]
+fn links_down_as_soon_as_they_come_up_report_it() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        // Not waiting on the manager in between, so the disconnect can overtake it
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::Disconnected { local: false, .. }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        assert!(acl.active_connections().await.unwrap().is_empty());
+    });
+}
+
+#[test]
+fn pairing_defaults_to_just_works() {
+    test_util::run(|rt| async move {
+        let (_acl, mut controller) = start_manager(rt).await;
+
+        controller.send_event(IoCapabilityRequestBuilder { bd_addr: ADDR_A }).await;
+        controller
+            .expect_complete(
+                IoCapabilityRequestReplyBuilder {
+                    bd_addr: ADDR_A,
+                    io_capability: IoCapability::DisplayYesNo,
+                    oob_present: OobDataPresent::NotPresent,
+                    authentication_requirements:
+                        AuthenticationRequirements::GeneralBondingMitmProtection,
+                },
+                IoCapabilityRequestReplyCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_A,
+                },
+            )
+            .await;
+
+        controller
+            .send_event(UserConfirmationRequestBuilder { bd_addr: ADDR_A, numeric_value: 123456 })
+            .await;
+        controller
+            .expect_complete(
+                UserConfirmationRequestReplyBuilder { bd_addr: ADDR_A },
+                UserConfirmationRequestReplyCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_A,
+                },
+            )
+            .await;
+    });
+}
+
+#[derive(Default)]
+struct MemoryKeys(std::sync::Mutex<HashMap<Address, LinkKey>>);
+
+impl KeyStore for MemoryKeys {
+    fn get(&self, addr: Address) -> Option<LinkKey> {
+        self.0.lock().unwrap().get(&addr).copied()
+    }
+
+    fn store(&self, addr: Address, key: LinkKey, _key_type: KeyType) {
+        self.0.lock().unwrap().insert(addr, key);
+    }
+}
+
+#[test]
+fn link_keys_are_kept_and_handed_back_on_request() {
+    test_util::run(|rt| async move {
+        let keys = Arc::new(MemoryKeys::default());
+        let mut config = AclConfig::new();
+        config.set_key_store(keys.clone());
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+        let mut link_keys = acl.link_key_events().await.unwrap();
+        assert!(acl.clone().link_key_events().await.is_none());
+
+        controller
+            .send_event(LinkKeyNotificationBuilder {
+                bd_addr: ADDR_A,
+                link_key: [0x11; 16],
+                key_type: KeyType::AuthenticatedP256,
+            })
+            .await;
+        assert_eq!(link_keys.recv().await.unwrap().1, [0x11; 16]);
+        assert_eq!(keys.get(ADDR_A), Some([0x11; 16]));
+
+        controller.send_event(LinkKeyRequestBuilder { bd_addr: ADDR_A }).await;
+        controller
+            .expect_complete(
+                LinkKeyRequestReplyBuilder { bd_addr: ADDR_A, link_key: [0x11; 16] },
+                LinkKeyRequestReplyCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                },
+            )
+            .await;
+
+        controller.send_event(LinkKeyRequestBuilder { bd_addr: ADDR_B }).await;
+        controller
+            .expect_complete(
+                LinkKeyRequestNegativeReplyBuilder { bd_addr: ADDR_B },
+                LinkKeyRequestNegativeReplyCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    bd_addr: ADDR_B,
+                },
+            )
+            .await;
+    });
+}
+
+#[derive(Default)]
+struct EstablishedLinks(std::sync::Mutex<Vec<ConnectionEstablished>>);
+
+impl ConnectionStatsSink for EstablishedLinks {
+    fn record(&self, _addr: Address, _stats: ConnectionStats) {}
+
+    fn established(&self, established: ConnectionEstablished) {
+        self.0.lock().unwrap().push(established);
+    }
+}
+
+#[test]
+fn established_links_are_reported_before_they_are_handed_out() {
+    test_util::run(|rt| async move {
+        let links = Arc::new(EstablishedLinks::default());
+        let mut config = AclConfig::new();
+        config.set_stats_sink(links.clone());
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                encryption_enabled: Enable::Enabled,
+                ..connection_complete(ErrorCode::Success, ADDR_A)
+            })
+            .await;
+        assert!(matches!(next_event(&mut acl).await, Event::ConnectSuccess(_)));
+        assert_eq!(
+            *links.0.lock().unwrap(),
+            [ConnectionEstablished {
+                addr: ADDR_A,
+                handle: 0x40,
+                role: Role::Central,
+                link_type: LinkType::Acl,
+                encryption_enabled: true,
+            }]
+        );
+    });
+}
+
+#[test]
+fn extended_feature_pages_are_checked_against_the_last_page() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let extended_features =
+            |page_number, extended_lmp_features| ReadRemoteExtendedFeaturesCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                page_number,
+                maximum_page_number: 1,
+                extended_lmp_features,
+            };
+        // How many pages the peer has is learned from page 0 first
+        let (result, ()) = tokio::join!(connection.read_remote_extended_features(1), async {
+            for page_number in 0..2 {
+                controller
+                    .expect_status(
+                        ReadRemoteExtendedFeaturesBuilder { connection_handle: 0x40, page_number },
+                        ErrorCode::Success,
+                    )
+                    .await;
+                controller.send_event(extended_features(page_number, 0x0f)).await;
+            }
+        });
+        assert_eq!(result, Ok(0x0f));
+
+        assert_eq!(
+            connection.read_remote_extended_features(2).await,
+            Err(ErrorCode::InvalidHciCommandParameters)
+        );
+        controller.expect_no_command(Duration::from_millis(100)).await;
+    });
+}
+
+#[test]
+fn statuses_are_classified() {
+    // Status, retryable, local error
+    let table = [
+        (ErrorCode::PageTimeout, true, false),
+        (ErrorCode::ConnectionTimeout, true, false),
+        (ErrorCode::AuthenticationFailure, false, false),
+        (ErrorCode::ConnectionLimitExceeded, false, false),
+        (ErrorCode::ConnectionTerminatedByLocalHost, false, true),
+        (ErrorCode::CommandDisallowed, false, true),
+        (ErrorCode::RemoteUserTerminatedConnection, false, false),
+    ];
+    for (code, retryable, local) in table {
+        assert_eq!(status::is_retryable(code), retryable, "{:?}", code);
+        assert_eq!(status::is_local_error(code), local, "{:?}", code);
+    }
+}
+
+#[test]
+fn connection_accept_timeouts_out_of_range_are_not_sent() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        for slots in [0, 0xb541] {
+            assert_eq!(
+                acl.write_connection_accept_timeout(slots).await,
+                Err(AclError::Hci(ErrorCode::InvalidHciCommandParameters))
+            );
+        }
+        controller.expect_no_command(Duration::from_millis(50)).await;
+
+        let (result, ()) = tokio::join!(
+            acl.write_connection_accept_timeout(0x7d00),
+            controller.expect_complete(
+                WriteConnectionAcceptTimeoutBuilder { conn_accept_timeout: 0x7d00 },
+                WriteConnectionAcceptTimeoutCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                }
+            )
+        );
+        assert_eq!(result, Ok(()));
+        let (timeout, ()) = tokio::join!(
+            acl.connection_accept_timeout(),
+            controller.expect_complete(
+                ReadConnectionAcceptTimeoutBuilder {},
+                ReadConnectionAcceptTimeoutCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    conn_accept_timeout: 0x7d00,
+                }
+            )
+        );
+        assert_eq!(timeout, Ok(0x7d00));
+    });
+}
+
+#[test]
+fn default_link_policy_is_written_to_the_controller() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let settings = LinkPolicy::ROLE_SWITCH | LinkPolicy::SNIFF_MODE;
+        let (result, ()) = tokio::join!(
+            acl.write_default_link_policy(settings),
+            controller.expect_complete(
+                WriteDefaultLinkPolicySettingsBuilder { default_link_policy_settings: 0x05 },
+                WriteDefaultLinkPolicySettingsCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                }
+            )
+        );
+        result.unwrap();
+    });
+}
+
+#[test]
+fn rssi_streams_skip_failed_reads_and_end_with_the_link() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let mut samples = connection.rssi_stream(Duration::from_millis(20));
+        for (status, rssi) in [(ErrorCode::ControllerBusy, 0), (ErrorCode::Success, -6i8)] {
+            controller
+                .expect_complete(
+                    ReadRssiBuilder { connection_handle: 0x40 },
+                    ReadRssiCompleteBuilder {
+                        num_hci_command_packets: 1,
+                        status,
+                        connection_handle: 0x40,
+                        rssi: rssi as u8,
+                    },
+                )
+                .await;
+        }
+        assert_eq!(samples.recv().await, Some(-6));
+
+        controller
+            .send_event(DisconnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                reason: ErrorCode::RemoteUserTerminatedConnection,
+            })
+            .await;
+        assert_eq!(samples.recv().await, None);
+    });
+}
+
+#[test]
+fn requested_sco_links_are_announced_on_their_acl_connection() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        let params = ScoParams {
+            transmit_bandwidth: 8000,
+            receive_bandwidth: 8000,
+            max_latency: 0x0d,
+            voice_setting: 0x0060,
+            retransmission_effort: 0x02,
+            packet_type: 0x0380,
+        };
+        let (result, ()) = tokio::join!(
+            connection.request_sco(params),
+            controller.expect_status(
+                SetupSynchronousConnectionBuilder {
+                    connection_handle: 0x40,
+                    transmit_bandwidth: 8000,
+                    receive_bandwidth: 8000,
+                    max_latency: 0x0d,
+                    voice_setting: 0x0060,
+                    retransmission_effort: 0x02,
+                    packet_type: 0x0380,
+                },
+                ErrorCode::Success
+            )
+        );
+        assert_eq!(result, Ok(()));
+        controller
+            .send_event(SynchronousConnectionCompleteBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x41,
+                bd_addr: ADDR_A,
+                link_type: ScoLinkType::Esco,
+                transmission_interval_slots: 12,
+                retransmission_window_slots: 2,
+                rx_packet_length: 60,
+                tx_packet_length: 60,
+                air_mode: ScoAirMode::Cvsd,
+            })
+            .await;
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::ScoConnected(sco)) => {
+                assert_eq!((sco.handle(), sco.link_type()), (0x41, ScoLinkType::Esco))
+            }
+            e => panic!("unexpected connection event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn inbound_data_past_a_slow_reader_is_dropped_when_asked() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        connection.set_inbound_overflow(InboundOverflow::DropNewest).await.unwrap();
+
+        // Two past what the connection's receive queue holds
+        for i in 0..12u8 {
+            let pdu = Bytes::from(vec![0x01, 0x00, 0x40, 0x00, i]);
+            controller
+                .send_acl(
+                    AclBuilder {
+                        handle: 0x40,
+                        packet_boundary_flag: PacketBoundaryFlag::FirstAutomaticallyFlushable,
+                        broadcast_flag: BroadcastFlag::PointToPoint,
+                        payload: Some(pdu),
+                    }
+                    .build(),
+                )
+                .await;
+        }
+        while connection.stats().rx_packets < 12 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(connection.stats().rx_dropped, 2);
+        for i in 0..10u8 {
+            assert_eq!(connection.recv().await.unwrap()[4], i);
+        }
+    });
+}
+
+#[test]
+fn host_flow_control_only_reports_packets_for_known_links() {
+    test_util::run(|rt| async move {
+        let flow =
+            core::HostFlowControl { acl_buffers: 2, report_interval: Duration::from_secs(5) };
+        let (stack, controller) = test_util::start_with_host_flow_control(rt.clone(), flow).await;
+        let (mut acl, mut controller) =
+            start_manager_on(rt, stack, controller, AclConfig::new(), [0xff; 64]).await;
+        let acl_on = |handle| {
+            AclBuilder {
+                handle,
+                packet_boundary_flag: PacketBoundaryFlag::FirstAutomaticallyFlushable,
+                broadcast_flag: BroadcastFlag::PointToPoint,
+                payload: Some(Bytes::from(vec![0x01, 0x00, 0x40, 0x00, 0x00])),
+            }
+            .build()
+        };
+        // How a controller answers a report naming a handle it doesn't know
+        let refused = || CommandCompleteBuilder {
+            num_hci_command_packets: 1,
+            command_op_code: OpCode::HostNumCompletedPackets,
+            payload: Some(Bytes::from(vec![ErrorCode::InvalidHciCommandParameters as u8])),
+        };
+
+        controller.send_acl(acl_on(0x99)).await;
+        controller.expect_no_command(Duration::from_millis(100)).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        assert!(matches!(next_event(&mut acl).await, Event::ConnectSuccess(_)));
+        controller.send_acl(acl_on(0x40)).await;
+        controller
+            .expect(HostNumCompletedPacketsBuilder {
+                completed_packets: vec![CompletedPackets {
+                    connection_handle: 0x40,
+                    host_num_of_completed_packets: 1,
+                }],
+            })
+            .await;
+
+        // Answers to the report are dropped, idle or with another command waiting
+        controller.send_event(refused()).await;
+        acl.connect(ADDR_B).await.unwrap();
+        controller.expect(build_create_connection(ADDR_B, None, DEFAULT_PACKET_TYPES)).await;
+        controller.send_event(refused()).await;
+        controller
+            .send_event(CommandStatusBuilder {
+                status: ErrorCode::Success,
+                num_hci_command_packets: 1,
+                command_op_code: OpCode::CreateConnection,
+                payload: None,
+            })
+            .await;
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_B)
+            })
+            .await;
+        assert!(matches!(next_event(&mut acl).await, Event::ConnectSuccess(_)));
+    });
+}
+
+#[test]
+fn links_staying_at_low_quality_are_reported_degraded_once() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_link_degradation(Some(LinkDegradationThresholds {
+            interval: Duration::from_millis(20),
+            quality: 100,
+            samples: 2,
+        }));
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+
+        // A good sample in between starts the count over, and a failed read doesn't count
+        for (status, link_quality) in [
+            (ErrorCode::Success, 90),
+            (ErrorCode::Success, 120),
+            (ErrorCode::Success, 90),
+            (ErrorCode::ControllerBusy, 0),
+            (ErrorCode::Success, 80),
+            (ErrorCode::Success, 70),
+        ] {
+            controller
+                .expect_complete(
+                    ReadLinkQualityBuilder { connection_handle: 0x40 },
+                    ReadLinkQualityCompleteBuilder {
+                        num_hci_command_packets: 1,
+                        status,
+                        connection_handle: 0x40,
+                        link_quality,
+                    },
+                )
+                .await;
+        }
+        let events = connection.channels.as_mut().unwrap();
+        match events.evt_rx.recv().await {
+            Some(ConnectionEvent::LinkDegraded { quality: 80 }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        assert!(events.evt_rx.try_recv().is_err());
+    });
+}
+
+#[test]
+fn connection_state_follows_events_and_reads() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        let mut connection = match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => connection,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(
+            connection.state().await,
+            ConnectionState {
+                role: Role::Central,
+                encrypted: false,
+                mode: Mode::Active,
+                rssi: None,
+                supervision_timeout: None,
+            }
+        );
+
+        controller
+            .send_event(ModeChangeBuilder {
+                status: ErrorCode::Success,
+                connection_handle: 0x40,
+                current_mode: Mode::Sniff,
+                interval: 800,
+            })
+            .await;
+        match connection.channels.as_mut().unwrap().evt_rx.recv().await {
+            Some(ConnectionEvent::ModeChanged { mode: Mode::Sniff, .. }) => {}
+            e => panic!("unexpected connection event {:?}", e),
+        }
+        let (rssi, ()) = tokio::join!(
+            connection.read_rssi(),
+            controller.expect_complete(
+                ReadRssiBuilder { connection_handle: 0x40 },
+                ReadRssiCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                    connection_handle: 0x40,
+                    rssi: -6i8 as u8,
+                }
+            )
+        );
+        assert_eq!(rssi, Ok(-6));
+        let state = connection.state().await;
+        assert_eq!((state.mode, state.rssi), (Mode::Sniff, Some(-6)));
+    });
+}
+
+#[test]
+fn links_the_dispatch_cannot_carry_are_disconnected_and_failed() {
+    test_util::run(|rt| async move {
+        let (mut stack, controller) = test_util::start(rt.clone()).await;
+        stack.dispatch = core::start_dataless_acl_dispatch(&rt);
+        let (mut acl, mut controller) =
+            start_manager_on(rt, stack, controller, AclConfig::default(), [0xff; 64]).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason, .. } => {
+                assert_eq!(addr, ADDR_A);
+                assert_eq!(reason, ErrorCode::ConnectionFailedEstablishment);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert_eq!(acl.snapshot().await.unwrap().active_connections, 0);
+    });
+}
+
+#[test]
+fn queue_events_leave_incoming_connections_out_unless_asked() {
+    test_util::run(|rt| async move {
+        let mut config = AclConfig::default();
+        config.set_queue_events(QueueEvents::Outgoing);
+        let (mut acl, mut controller) = start_manager_with(rt, config).await;
+
+        acl.connect(ADDR_A).await.unwrap();
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, None, DEFAULT_PACKET_TYPES),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::QueueActive => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_A),
+            e => panic!("unexpected event {:?}", e),
+        }
+        match next_event(&mut acl).await {
+            Event::QueueIdle => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // Incoming connections come and go without either
+        controller
+            .send_event(ConnectionRequestBuilder {
+                bd_addr: ADDR_B,
+                class_of_device: ClassOfDevice { bytes: [0; 3] },
+                link_type: ConnectionRequestLinkType::Acl,
+            })
+            .await;
+        controller
+            .expect_status(
+                build_accept_connection(ADDR_B, AcceptConnectionRequestRole::BecomeCentral),
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::IncomingConnecting { addr: ADDR_B } => {}
+            e => panic!("unexpected event {:?}", e),
+        }
+        controller
+            .send_event(ConnectionCompleteBuilder {
+                connection_handle: 0x41,
+                ..connection_complete(ErrorCode::Success, ADDR_B)
+            })
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectSuccess(connection) => assert_eq!(connection.addr, ADDR_B),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(acl.evt_rx.lock().await.try_recv().is_err());
+    });
+}
+
+#[test]
+fn events_for_nothing_pending_leave_state_alone() {
+    let mut state = AclState::default();
+    let complete = connection_complete(ErrorCode::Success, ADDR_A).build().into();
+    assert!(matches!(handle_event(&mut state, &complete), EventOutcome::Unexpected));
+
+    let role_change =
+        RoleChangeBuilder { status: ErrorCode::Success, bd_addr: ADDR_A, new_role: Role::Central };
+    assert!(matches!(handle_event(&mut state, &role_change.build().into()), EventOutcome::Other));
+    assert_eq!(state.pending, PendingConnect::None);
+    assert_eq!(state.pending_role, None);
+}
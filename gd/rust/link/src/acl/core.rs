@@ -1,17 +1,23 @@
 //! ACL core dispatch shared between LE and classic
 
 use crate::acl::fragment::{fragmenting_stream, Reassembler};
+use bt_common::time::interval;
 use bt_common::Bluetooth::{self, Classic, Le};
 use bt_hal::AclHal;
-use bt_hci::{ControllerExports, EventRegistry};
+use bt_hci::{CommandSender, ControllerExports, EventRegistry};
 use bt_packets::hci::EventChild::{DisconnectionComplete, NumberOfCompletedPackets};
-use bt_packets::hci::{AclPacket, EventCode, EventPacket};
+use bt_packets::hci::{
+    AclPacket, CompletedPackets, ErrorCode, EventCode, EventPacket, HostBufferSizeBuilder,
+    HostNumCompletedPacketsBuilder, SetControllerToHostFlowControlBuilder,
+};
 use bytes::Bytes;
 use futures::stream::{SelectAll, StreamExt};
 use gddi::{module, provides, Stoppable};
-use log::info;
-use std::collections::HashMap;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 use tokio::select;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
@@ -22,9 +28,45 @@ module! {
     core_module,
     providers {
         AclDispatch => provide_acl_dispatch,
+        AclDispatchConfig => provide_acl_dispatch_config,
     },
 }
 
+/// ACL dispatch config. Inject into the registry to override the defaults.
+#[derive(Clone, Copy, Default, Stoppable)]
+pub struct AclDispatchConfig {
+    host_flow_control: Option<HostFlowControl>,
+}
+
+impl AclDispatchConfig {
+    /// Overwrites whether the controller has to wait for the host to consume received ACL data
+    /// before sending more. `None`, the default, leaves it off.
+    pub fn set_host_flow_control(&mut self, value: Option<HostFlowControl>) {
+        self.host_flow_control = value;
+    }
+}
+
+#[provides]
+async fn provide_acl_dispatch_config() -> AclDispatchConfig {
+    AclDispatchConfig::default()
+}
+
+/// Host side of controller to host flow control for ACL data
+#[derive(Clone, Copy, Debug)]
+pub struct HostFlowControl {
+    /// Number of received ACL packets the host can hold at a time
+    pub acl_buffers: u16,
+    /// Longest consumed packets go unreported to the controller
+    pub report_interval: Duration,
+}
+
+/// Reports consumed ACL packets back to the controller, under host flow control
+#[derive(Clone)]
+pub struct HostCompletions {
+    hci: CommandSender,
+    config: HostFlowControl,
+}
+
 /// A basic ACL connection
 #[derive(Debug)]
 pub struct Connection {
@@ -34,6 +76,35 @@ pub struct Connection {
     requests: Sender<Request>,
     pub evt_rx: Receiver<EventPacket>,
     pub evt_tx: Sender<EventPacket>,
+    pub tx_limit: TxPduLimit,
+}
+
+impl Connection {
+    /// Drop the connection from the dispatch without waiting on its Disconnection Complete, for
+    /// a link the controller won't report down. Data not yet sent on it is dropped.
+    pub async fn close(&self) {
+        let _ = self.requests.send(Request::Unregister { handle: self.handle }).await;
+    }
+}
+
+/// Caps the size of outbound ACL packets on a connection
+#[derive(Clone, Debug)]
+pub struct TxPduLimit {
+    current: Arc<AtomicUsize>,
+    controller: usize,
+}
+
+impl TxPduLimit {
+    fn new(controller: usize) -> Self {
+        Self { current: Arc::new(AtomicUsize::new(controller)), controller }
+    }
+
+    /// Fragment data not yet sent into packets of at most `size` bytes. Sizes above what the
+    /// controller accepts are capped to its buffer length, and 0 is taken as 1, as packets have
+    /// to carry data.
+    pub fn set(&self, size: usize) {
+        self.current.store(size.max(1).min(self.controller), Ordering::Relaxed);
+    }
 }
 
 struct ConnectionInternal {
@@ -50,28 +121,112 @@ pub struct AclDispatch {
 }
 
 impl AclDispatch {
-    /// Register the provided connection with the ACL dispatch
+    /// Register the provided connection with the ACL dispatch. Returns `None` if the dispatch
+    /// has stopped.
     #[allow(dead_code)]
-    pub async fn register(&mut self, handle: u16, bt: Bluetooth) -> Connection {
+    pub async fn register(&mut self, handle: u16, bt: Bluetooth) -> Option<Connection> {
         let (tx, rx) = oneshot::channel();
-        self.requests.send(Request::Register { handle, bt, fut: tx }).await.unwrap();
-        rx.await.unwrap()
+        self.requests.send(Request::Register { handle, bt, fut: tx }).await.ok()?;
+        rx.await.ok()
     }
 }
 
 #[derive(Debug)]
 enum Request {
     Register { handle: u16, bt: Bluetooth, fut: oneshot::Sender<Connection> },
+    Unregister { handle: u16 },
 }
 
 const QCOM_DEBUG_HANDLE: u16 = 0xedc;
 
+// How long a Disconnection Complete for a handle nobody registered yet waits for its link
+const EARLY_DISCONNECT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Controller buffer sizes used to pace outbound ACL data
+#[derive(Clone, Copy, Debug)]
+pub struct BufferSizes {
+    pub acl_buffer_length: u16,
+    pub acl_buffers: u16,
+    pub le_buffer_length: u16,
+    pub le_buffers: u16,
+}
+
+impl From<&ControllerExports> for BufferSizes {
+    fn from(controller: &ControllerExports) -> Self {
+        Self {
+            acl_buffer_length: controller.acl_buffer_length,
+            acl_buffers: controller.acl_buffers,
+            le_buffer_length: controller.le_buffer_length,
+            le_buffers: controller.le_buffers.into(),
+        }
+    }
+}
+
 #[provides]
 async fn provide_acl_dispatch(
     acl: AclHal,
     controller: Arc<ControllerExports>,
-    mut events: EventRegistry,
+    mut hci: CommandSender,
+    events: EventRegistry,
+    config: AclDispatchConfig,
     rt: Arc<Runtime>,
+) -> AclDispatch {
+    let buffers = BufferSizes::from(controller.as_ref());
+    let completions = match config.host_flow_control {
+        Some(flow) => enable_host_flow_control(&mut hci, flow, &buffers)
+            .await
+            .map(|()| HostCompletions { hci, config: flow }),
+        None => None,
+    };
+    start_acl_dispatch(acl, buffers, events, completions, &rt)
+}
+
+/// Tell the controller how much ACL data the host can hold, and to wait for it to be consumed.
+/// Returns `None` if the controller turned it down, in which case it stays off.
+async fn enable_host_flow_control(
+    hci: &mut CommandSender,
+    flow: HostFlowControl,
+    buffers: &BufferSizes,
+) -> Option<()> {
+    let status = hci
+        .send(HostBufferSizeBuilder {
+            host_acl_data_packet_length: buffers.acl_buffer_length.max(buffers.le_buffer_length),
+            host_synchronous_data_packet_length: 0,
+            host_total_num_acl_data_packets: flow.acl_buffers,
+            host_total_num_synchronous_data_packets: 0,
+        })
+        .await
+        .get_status();
+    if status != ErrorCode::Success {
+        warn!("could not set host buffer size: {:?}", status);
+        return None;
+    }
+    let status = hci.send(SetControllerToHostFlowControlBuilder { acl: 1, synchronous: 0 }).await;
+    match status.get_status() {
+        ErrorCode::Success => Some(()),
+        status => {
+            warn!("could not enable host flow control: {:?}", status);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+impl HostCompletions {
+    pub fn new(hci: CommandSender, config: HostFlowControl) -> Self {
+        Self { hci, config }
+    }
+}
+
+/// Starts the dispatch loop, pacing outbound data against the provided buffer sizes. If
+/// `completions` is set, the controller is under host flow control and consumed packets are
+/// reported back through it.
+pub fn start_acl_dispatch(
+    acl: AclHal,
+    buffers: BufferSizes,
+    mut events: EventRegistry,
+    mut completions: Option<HostCompletions>,
+    rt: &Runtime,
 ) -> AclDispatch {
     let (req_tx, mut req_rx) = channel::<Request>(10);
     let req_tx_clone = req_tx.clone();
@@ -79,9 +234,19 @@ async fn provide_acl_dispatch(
     rt.spawn(async move {
         let mut connections: HashMap<u16, ConnectionInternal> = HashMap::new();
         let mut classic_outbound = SelectAll::new();
-        let mut classic_credits = controller.acl_buffers;
+        let mut classic_credits = buffers.acl_buffers;
         let mut le_outbound = SelectAll::new();
-        let mut le_credits: u16 = controller.le_buffers.into();
+        let mut le_credits = buffers.le_buffers;
+        // Received packets the host consumed but has not reported yet, by handle
+        let mut consumed: HashMap<u16, u16> = HashMap::new();
+        // The link a Disconnection Complete is for is registered as its Connection Complete is
+        // handled, which is routed apart from here and can be overtaken. Kept for a moment, so
+        // a link that went down as soon as it came up still learns of it.
+        let mut early_disconnects: HashMap<u16, (EventPacket, Instant)> = HashMap::new();
+        // Links closed before the controller reported them down, whose Disconnection Complete
+        // may still come late
+        let mut closed: HashSet<u16> = HashSet::new();
+        let mut report = interval(completions.as_ref().map(|c| c.config.report_interval).unwrap_or(Duration::from_secs(1)));
 
         let (evt_tx, mut evt_rx) = channel(3);
         events.register(EventCode::NumberOfCompletedPackets, evt_tx.clone()).await;
@@ -96,25 +261,38 @@ async fn provide_acl_dispatch(
                             let (in_tx, in_rx) = channel(10);
                             let (evt_tx, evt_rx) = channel(3);
                             let (close_tx, close_rx) = oneshot::channel();
+                            closed.remove(&handle);
 
-                            assert!(connections.insert(
+                            let stale = connections.insert(
                                 handle,
                                 ConnectionInternal {
                                     reassembler: Reassembler::new(out_tx),
                                     bt,
                                     close_tx,
                                     evt_tx: evt_tx.clone(),
-                                }).is_none());
+                                });
+                            // The controller reused the handle of a link it never reported down
+                            if let Some(stale) = stale {
+                                warn!("handle {} registered again, dropping its previous link", handle);
+                                let _ = stale.close_tx.send(());
+                            }
+                            if let Some((evt, at)) = early_disconnects.remove(&handle) {
+                                if at.elapsed() < EARLY_DISCONNECT_WINDOW {
+                                    let c = connections.remove(&handle).unwrap();
+                                    let _ = c.close_tx.send(());
+                                    c.evt_tx.send(evt).await.unwrap();
+                                }
+                            }
 
+                            let tx_limit = match bt {
+                                Classic => TxPduLimit::new(buffers.acl_buffer_length.into()),
+                                Le => TxPduLimit::new(buffers.le_buffer_length.into()),
+                            };
+                            let fragments = fragmenting_stream(
+                                ReceiverStream::new(in_rx), tx_limit.current.clone(), handle, bt, close_rx);
                             match bt {
-                                Classic => {
-                                    classic_outbound.push(fragmenting_stream(
-                                        ReceiverStream::new(in_rx), controller.acl_buffer_length.into(), handle, bt, close_rx));
-                                },
-                                Le => {
-                                    le_outbound.push(fragmenting_stream(
-                                        ReceiverStream::new(in_rx), controller.le_buffer_length.into(), handle, bt, close_rx));
-                                },
+                                Classic => classic_outbound.push(fragments),
+                                Le => le_outbound.push(fragments),
                             }
 
                             fut.send(Connection {
@@ -124,17 +302,45 @@ async fn provide_acl_dispatch(
                                 requests: req_tx_clone.clone(),
                                 evt_rx,
                                 evt_tx,
+                                tx_limit,
                             }).unwrap();
                         },
+                        Request::Unregister { handle } => {
+                            consumed.remove(&handle);
+                            if let Some(c) = connections.remove(&handle) {
+                                let _ = c.close_tx.send(());
+                                closed.insert(handle);
+                            }
+                        },
                     }
                 },
                 Some(p) = consume(&acl.rx) => {
-                    match connections.get_mut(&p.get_handle()) {
-                        Some(c) => c.reassembler.on_packet(p).await,
-                        None if p.get_handle() == QCOM_DEBUG_HANDLE => {},
-                        None => info!("no acl for {}", p.get_handle()),
+                    let handle = p.get_handle();
+                    let delivered = match connections.get_mut(&handle) {
+                        Some(c) => {
+                            c.reassembler.on_packet(p).await;
+                            true
+                        },
+                        None if handle == QCOM_DEBUG_HANDLE => false,
+                        None => {
+                            info!("no acl for {}", handle);
+                            false
+                        },
+                    };
+                    // Only reported for handles the controller knows, as it fails the whole
+                    // report for one it doesn't
+                    if let (true, Some(completions)) = (delivered, completions.as_mut()) {
+                        // Consumed once handed on, so the controller's buffer for it is free
+                        *consumed.entry(handle).or_default() += 1;
+                        let unreported: u16 = consumed.values().sum();
+                        if unreported >= completions.config.acl_buffers / 2 {
+                            report_consumed(completions, &mut consumed).await;
+                        }
                     }
                 },
+                _ = report.tick(), if completions.is_some() && !consumed.is_empty() => {
+                    report_consumed(completions.as_mut().unwrap(), &mut consumed).await;
+                },
                 Some(p) = classic_outbound.next(), if classic_credits > 0 => {
                     acl.tx.send(p).await.unwrap();
                     classic_credits -= 1;
@@ -154,17 +360,27 @@ async fn provide_acl_dispatch(
                                             Classic => classic_credits += credits,
                                             Le => le_credits += credits,
                                         }
-                                        assert!(classic_credits <= controller.acl_buffers);
-                                        assert!(le_credits <= controller.le_buffers.into());
+                                        assert!(classic_credits <= buffers.acl_buffers);
+                                        assert!(le_credits <= buffers.le_buffers);
                                     },
                                     None => info!("dropping credits for unknown connection {}", entry.connection_handle),
                                 }
                             }
                         },
                         DisconnectionComplete(evt) => {
-                            if let Some(c) = connections.remove(&evt.get_connection_handle()) {
-                                c.close_tx.send(()).unwrap();
-                                c.evt_tx.send(evt.into()).await.unwrap();
+                            let handle = evt.get_connection_handle();
+                            // The controller drops what it counted against the handle
+                            consumed.remove(&handle);
+                            match connections.remove(&handle) {
+                                Some(c) => {
+                                    c.close_tx.send(()).unwrap();
+                                    c.evt_tx.send(evt.into()).await.unwrap();
+                                },
+                                None if closed.remove(&handle) => {},
+                                None => {
+                                    early_disconnects.retain(|_, (_, at)| at.elapsed() < EARLY_DISCONNECT_WINDOW);
+                                    early_disconnects.insert(handle, (evt.into(), Instant::now()));
+                                },
                             }
                         },
                         _ => unimplemented!(),
@@ -177,6 +393,42 @@ async fn provide_acl_dispatch(
     AclDispatch { requests: req_tx }
 }
 
+/// Starts a dispatch that registers connections without their data channels, as a broken
+/// dispatch would
+#[cfg(test)]
+pub fn start_dataless_acl_dispatch(rt: &Runtime) -> AclDispatch {
+    let (req_tx, mut req_rx) = channel::<Request>(10);
+    let requests = req_tx.clone();
+    rt.spawn(async move {
+        while let Some(req) = req_rx.recv().await {
+            if let Request::Register { handle, fut, .. } = req {
+                let (evt_tx, evt_rx) = channel(3);
+                let _ = fut.send(Connection {
+                    rx: None,
+                    tx: None,
+                    handle,
+                    requests: requests.clone(),
+                    evt_rx,
+                    evt_tx,
+                    tx_limit: TxPduLimit::new(1021),
+                });
+            }
+        }
+    });
+    AclDispatch { requests: req_tx }
+}
+
+async fn report_consumed(completions: &mut HostCompletions, consumed: &mut HashMap<u16, u16>) {
+    let completed_packets = consumed
+        .drain()
+        .map(|(connection_handle, host_num_of_completed_packets)| CompletedPackets {
+            connection_handle,
+            host_num_of_completed_packets,
+        })
+        .collect();
+    completions.hci.send_unacknowledged(HostNumCompletedPacketsBuilder { completed_packets }).await;
+}
+
 async fn consume(rx: &Arc<Mutex<Receiver<AclPacket>>>) -> Option<AclPacket> {
     rx.lock().await.recv().await
 }
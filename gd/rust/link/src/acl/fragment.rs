@@ -8,6 +8,8 @@ use bt_packets::hci::{AclBuilder, AclChild, AclPacket, BroadcastFlag};
 use bytes::{Buf, Bytes, BytesMut};
 use futures::stream::{self, StreamExt};
 use log::{error, info, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
 use tokio_stream::wrappers::ReceiverStream;
@@ -89,7 +91,7 @@ fn get_l2cap_pdu_size(first_packet: &Bytes) -> usize {
 
 pub fn fragmenting_stream(
     rx: ReceiverStream<Bytes>,
-    mtu: usize,
+    mtu: Arc<AtomicUsize>,
     handle: u16,
     bt: Bluetooth,
     close_rx: oneshot::Receiver<()>,
@@ -98,7 +100,7 @@ pub fn fragmenting_stream(
 > {
     rx.flat_map(move |data| {
         stream::iter(
-            data.chunks(mtu)
+            data.chunks(mtu.load(Ordering::Relaxed))
                 .enumerate()
                 .map(move |(i, chunk)| {
                     AclBuilder {
@@ -0,0 +1,475 @@
+//! LE ACL manager
+
+use crate::acl::core;
+use bt_common::Bluetooth;
+use bt_hci::{Address, CommandSender, EventRegistry};
+use bt_packets::hci::EventChild::DisconnectionComplete;
+use bt_packets::hci::LeMetaEventChild::{LeConnectionComplete, LeEnhancedConnectionComplete};
+use bt_packets::hci::{
+    AddressType, DisconnectBuilder, DisconnectReason, ErrorCode, InitiatorFilterPolicy,
+    LeCreateConnPhyScanParameters, LeCreateConnectionBuilder, LeCreateConnectionCancelBuilder,
+    LeExtendedCreateConnectionBuilder, LeReadPhyBuilder, OwnAddressType, PhyType, Role,
+    SubeventCode,
+};
+use bytes::Bytes;
+use gddi::{module, provides, Stoppable};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::select;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{oneshot, Mutex};
+
+module! {
+    le_acl_module,
+    providers {
+        LeAclManager => provide_le_acl_manager,
+    },
+}
+
+/// LE ACL manager
+#[derive(Clone, Stoppable)]
+pub struct LeAclManager {
+    req_tx: Sender<Request>,
+    /// High level events from LeAclManager
+    pub evt_rx: Arc<Mutex<Receiver<Event>>>,
+    default_conn_params: Arc<std::sync::Mutex<ConnectionParameters>>,
+}
+
+/// Events generated by LeAclManager
+#[derive(Debug)]
+pub enum Event {
+    /// Connection was successful - provides the newly created connection
+    ConnectSuccess(Connection),
+    /// Locally initiated connection was not successful - indicates address & reason
+    ConnectFail {
+        /// Address of the failed connection
+        addr: Address,
+        /// Reason of the failed connection
+        reason: ErrorCode,
+    },
+}
+
+/// A set of LE PHYs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PhySet {
+    /// LE 1M
+    pub le_1m: bool,
+    /// LE 2M
+    pub le_2m: bool,
+    /// LE Coded, for long range
+    pub le_coded: bool,
+}
+
+impl PhySet {
+    /// Only the LE 1M PHY, which every controller supports
+    pub const LE_1M: PhySet = PhySet { le_1m: true, le_2m: false, le_coded: false };
+
+    fn phys(&self) -> impl Iterator<Item = u8> {
+        let set = [self.le_1m, self.le_2m, self.le_coded];
+        (0..3).filter(move |i| set[*i as usize]).map(|i| 1 << i)
+    }
+}
+
+impl Default for PhySet {
+    fn default() -> Self {
+        Self::LE_1M
+    }
+}
+
+/// Options for an outgoing connection
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectParams {
+    /// PHYs to initiate on. Anything other than 1M alone needs extended connection support.
+    pub initiating_phys: PhySet,
+}
+
+/// Connection parameters new connections are created with
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionParameters {
+    /// Shortest connection interval, in units of 1.25ms
+    pub interval_min: u16,
+    /// Longest connection interval, in units of 1.25ms
+    pub interval_max: u16,
+    /// Connection events the peripheral may skip
+    pub latency: u16,
+    /// Supervision timeout, in units of 10ms
+    pub supervision_timeout: u16,
+}
+
+impl Default for ConnectionParameters {
+    fn default() -> Self {
+        Self { interval_min: 0x0018, interval_max: 0x0028, latency: 0, supervision_timeout: 0x01f4 }
+    }
+}
+
+/// PHYs in use on a connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectionPhy {
+    /// Transmitter PHY
+    pub tx: PhyType,
+    /// Receiver PHY
+    pub rx: PhyType,
+}
+
+/// An LE ACL connection
+#[derive(Debug)]
+pub struct Connection {
+    addr: Address,
+    role: Role,
+    phy: Option<ConnectionPhy>,
+    rx: Receiver<Bytes>,
+    tx: Sender<Bytes>,
+    requests: Sender<ConnectionRequest>,
+    evt_rx: Receiver<ConnectionEvent>,
+}
+
+/// Events generated by Connection
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    /// Connection was disconnected with the specified code.
+    Disconnected(ErrorCode),
+}
+
+impl Connection {
+    /// The local role on the connection
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// PHYs the connection came up on. Only known for connections initiated on a PHY set
+    /// other than 1M alone.
+    pub fn phy(&self) -> Option<ConnectionPhy> {
+        self.phy
+    }
+
+    /// Disconnect the connection with the specified reason.
+    pub async fn disconnect(&mut self, reason: DisconnectReason) {
+        let (tx, rx) = oneshot::channel();
+        self.requests.send(ConnectionRequest::Disconnect { reason, fut: tx }).await.unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Queue data to be sent on the connection
+    pub async fn send_data(&mut self, data: Bytes) {
+        self.tx.send(data).await.unwrap();
+    }
+}
+
+#[derive(Debug)]
+enum ConnectionRequest {
+    Disconnect { reason: DisconnectReason, fut: oneshot::Sender<()> },
+}
+
+impl LeAclManager {
+    /// Connect to the specified address, or queue it if a connection is already pending
+    pub async fn connect(&mut self, addr: Address, addr_type: AddressType) {
+        self.connect_with_params(addr, addr_type, ConnectParams::default()).await;
+    }
+
+    /// Connect to the specified address with the specified options, or queue it if a
+    /// connection is already pending
+    pub async fn connect_with_params(
+        &mut self,
+        addr: Address,
+        addr_type: AddressType,
+        params: ConnectParams,
+    ) {
+        self.req_tx.send(Request::Connect { addr, addr_type, params }).await.unwrap();
+    }
+
+    /// Set the connection parameters connections are created with, instead of updating each
+    /// once it is up. Kept by the host, since the controller has no default of its own, and
+    /// applied to every connect sent from now on, including those already queued.
+    pub fn set_default_conn_params(&mut self, params: ConnectionParameters) {
+        *self.default_conn_params.lock().unwrap() = params;
+    }
+
+    /// The connection parameters connections are currently created with
+    pub fn default_conn_params(&self) -> ConnectionParameters {
+        *self.default_conn_params.lock().unwrap()
+    }
+
+    /// Cancel the connection to the specified address, if it is pending. A cancelled attempt
+    /// is reported as `ConnectFail` with `ConnectionTerminatedByLocalHost`.
+    pub async fn cancel_connect(&mut self, addr: Address) {
+        let (tx, rx) = oneshot::channel();
+        self.req_tx.send(Request::CancelConnect { addr, fut: tx }).await.unwrap();
+        rx.await.unwrap();
+    }
+}
+
+#[derive(Debug)]
+enum Request {
+    Connect { addr: Address, addr_type: AddressType, params: ConnectParams },
+    CancelConnect { addr: Address, fut: oneshot::Sender<()> },
+}
+
+#[provides]
+async fn provide_le_acl_manager(
+    mut hci: CommandSender,
+    mut events: EventRegistry,
+    mut dispatch: core::AclDispatch,
+    rt: Arc<Runtime>,
+) -> LeAclManager {
+    let (req_tx, mut req_rx) = channel::<Request>(10);
+    let (conn_evt_tx, conn_evt_rx) = channel::<Event>(10);
+    let local_rt = rt.clone();
+    let default_conn_params = Arc::new(std::sync::Mutex::new(ConnectionParameters::default()));
+    let loop_conn_params = default_conn_params.clone();
+
+    local_rt.spawn(async move {
+        let connections: Arc<Mutex<HashMap<u16, Address>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut connect_queue: Vec<(Address, AddressType, ConnectParams)> = Vec::new();
+        let mut pending: Option<Address> = None;
+        let mut pending_params = ConnectParams::default();
+
+        let (evt_tx, mut evt_rx) = channel(3);
+        events.register_le(SubeventCode::ConnectionComplete, evt_tx.clone()).await;
+        events.register_le(SubeventCode::EnhancedConnectionComplete, evt_tx).await;
+
+        loop {
+            select! {
+                Some(req) = req_rx.recv() => {
+                    match req {
+                        Request::Connect { addr, addr_type, params } => {
+                            if connections.lock().await.values().any(|a| *a == addr) {
+                                warn!("already connected: {}", addr);
+                            } else if pending.is_none() {
+                                pending = Some(addr);
+                                pending_params = params;
+                                let conn_params = *loop_conn_params.lock().unwrap();
+                                send_create_connection(&mut hci, addr, addr_type, params, conn_params).await;
+                            } else {
+                                connect_queue.insert(0, (addr, addr_type, params));
+                            }
+                        },
+                        Request::CancelConnect { addr, fut } => {
+                            connect_queue.retain(|(p, _, _)| *p != addr);
+                            if pending == Some(addr) {
+                                let status = hci.send(LeCreateConnectionCancelBuilder {}).await.get_status();
+                                if status != ErrorCode::Success {
+                                    // Most likely the connection completed first
+                                    warn!("could not cancel LE connection to {}: {:?}", addr, status);
+                                }
+                            }
+                            fut.send(()).unwrap();
+                        },
+                    }
+                }
+                Some(evt) = evt_rx.recv() => {
+                    let completion = match evt.specialize() {
+                        LeConnectionComplete(evt) => Completion {
+                            status: evt.get_status(),
+                            handle: evt.get_connection_handle(),
+                            role: evt.get_role(),
+                            peer_address: evt.get_peer_address(),
+                        },
+                        LeEnhancedConnectionComplete(evt) => Completion {
+                            status: evt.get_status(),
+                            handle: evt.get_connection_handle(),
+                            role: evt.get_role(),
+                            peer_address: evt.get_peer_address(),
+                        },
+                        _ => {
+                            warn!("unexpected event {:?}", evt.get_subevent_code());
+                            continue;
+                        }
+                    };
+                    let status = completion.status;
+                    if status == ErrorCode::Success {
+                        let addr = completion.peer_address;
+                        let handle = completion.handle;
+                        let mut phy = None;
+                        if pending == Some(addr) {
+                            pending = None;
+                            let params = std::mem::take(&mut pending_params);
+                            // Controllers that can initiate on other PHYs can also report them
+                            if params.initiating_phys != PhySet::LE_1M {
+                                phy = read_phy(&mut hci, handle).await;
+                            }
+                        }
+
+                        // A link the dispatch can't carry data for is of no use, so it is taken
+                        // down rather than handed out
+                        match dispatch.register(handle, Bluetooth::Le).await {
+                            Some(mut core_conn) if core_conn.rx.is_some() && core_conn.tx.is_some() => {
+                                let (evt_tx, evt_rx) = channel(10);
+                                let (req_tx, req_rx) = channel(10);
+                                let connection = Connection {
+                                    addr,
+                                    role: completion.role,
+                                    phy,
+                                    rx: core_conn.rx.take().unwrap(),
+                                    tx: core_conn.tx.take().unwrap(),
+                                    requests: req_tx,
+                                    evt_rx,
+                                };
+
+                                assert!(connections.lock().await.insert(handle, addr).is_none());
+                                rt.spawn(run_connection(handle, evt_tx, req_rx, core_conn, connections.clone(), hci.clone()));
+                                conn_evt_tx.send(Event::ConnectSuccess(connection)).await.unwrap();
+                            },
+                            registered => {
+                                warn!("LE connection to {} could not be registered for data, disconnecting", addr);
+                                if let Some(core_conn) = registered {
+                                    core_conn.close().await;
+                                }
+                                hci.send(DisconnectBuilder {
+                                    connection_handle: handle,
+                                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                                }).await;
+                                let reason = ErrorCode::ConnectionFailedEstablishment;
+                                conn_evt_tx.send(Event::ConnectFail { addr, reason }).await.unwrap();
+                            },
+                        }
+                    } else if let Some(addr) = pending.take() {
+                        // Only the status is meaningful in a failed completion, which always
+                        // concludes the outstanding create connection
+                        let reason = match status {
+                            // The controller confirms a cancellation this way
+                            ErrorCode::UnknownConnection => ErrorCode::ConnectionTerminatedByLocalHost,
+                            _ => status,
+                        };
+                        conn_evt_tx.send(Event::ConnectFail { addr, reason }).await.unwrap();
+                    } else {
+                        warn!("LE connection failed with nothing pending: {:?}", status);
+                    }
+
+                    if pending.is_none() {
+                        if let Some((next, next_type, params)) = connect_queue.pop() {
+                            pending = Some(next);
+                            pending_params = params;
+                            let conn_params = *loop_conn_params.lock().unwrap();
+                            send_create_connection(&mut hci, next, next_type, params, conn_params).await;
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    LeAclManager { req_tx, evt_rx: Arc::new(Mutex::new(conn_evt_rx)), default_conn_params }
+}
+
+/// The fields shared by the legacy and enhanced connection complete events
+struct Completion {
+    status: ErrorCode,
+    handle: u16,
+    role: Role,
+    peer_address: Address,
+}
+
+async fn send_create_connection(
+    hci: &mut CommandSender,
+    peer_address: Address,
+    peer_address_type: AddressType,
+    params: ConnectParams,
+    conn_params: ConnectionParameters,
+) {
+    if params.initiating_phys == PhySet::LE_1M {
+        hci.send(build_create_connection(peer_address, peer_address_type, conn_params)).await;
+    } else {
+        let cmd =
+            build_extended_create_connection(peer_address, peer_address_type, params, conn_params);
+        hci.send(cmd).await;
+    }
+}
+
+fn build_create_connection(
+    peer_address: Address,
+    peer_address_type: AddressType,
+    conn_params: ConnectionParameters,
+) -> LeCreateConnectionBuilder {
+    LeCreateConnectionBuilder {
+        le_scan_interval: 0x0060,
+        le_scan_window: 0x0030,
+        initiator_filter_policy: InitiatorFilterPolicy::UsePeerAddress,
+        peer_address_type,
+        peer_address,
+        own_address_type: OwnAddressType::PublicDeviceAddress,
+        conn_interval_min: conn_params.interval_min,
+        conn_interval_max: conn_params.interval_max,
+        conn_latency: conn_params.latency,
+        supervision_timeout: conn_params.supervision_timeout,
+        minimum_ce_length: 0,
+        maximum_ce_length: 0,
+    }
+}
+
+fn build_extended_create_connection(
+    peer_address: Address,
+    peer_address_type: AddressType,
+    params: ConnectParams,
+    conn_params: ConnectionParameters,
+) -> LeExtendedCreateConnectionBuilder {
+    let phys: Vec<u8> = params.initiating_phys.phys().collect();
+    LeExtendedCreateConnectionBuilder {
+        initiator_filter_policy: InitiatorFilterPolicy::UsePeerAddress,
+        own_address_type: OwnAddressType::PublicDeviceAddress,
+        peer_address_type,
+        peer_address,
+        initiating_phys: phys.iter().fold(0, |bits, phy| bits | phy),
+        // One set of parameters per initiating PHY, in bit order
+        phy_scan_parameters: phys
+            .iter()
+            .map(|_| LeCreateConnPhyScanParameters {
+                scan_interval: 0x0060,
+                scan_window: 0x0030,
+                conn_interval_min: conn_params.interval_min,
+                conn_interval_max: conn_params.interval_max,
+                conn_latency: conn_params.latency,
+                supervision_timeout: conn_params.supervision_timeout,
+                min_ce_length: 0,
+                max_ce_length: 0,
+            })
+            .collect(),
+    }
+}
+
+async fn read_phy(hci: &mut CommandSender, handle: u16) -> Option<ConnectionPhy> {
+    let evt = hci.send(LeReadPhyBuilder { connection_handle: handle }).await;
+    match evt.get_status() {
+        ErrorCode::Success => Some(ConnectionPhy { tx: evt.get_tx_phy(), rx: evt.get_rx_phy() }),
+        status => {
+            warn!("could not read PHY of {}: {:?}", handle, status);
+            None
+        }
+    }
+}
+
+async fn run_connection(
+    handle: u16,
+    evt_tx: Sender<ConnectionEvent>,
+    mut req_rx: Receiver<ConnectionRequest>,
+    mut core: core::Connection,
+    connections: Arc<Mutex<HashMap<u16, Address>>>,
+    mut hci: CommandSender,
+) {
+    loop {
+        select! {
+            Some(evt) = core.evt_rx.recv() => {
+                match evt.specialize() {
+                    DisconnectionComplete(evt) => {
+                        connections.lock().await.remove(&handle);
+                        evt_tx.send(ConnectionEvent::Disconnected(evt.get_reason())).await.unwrap();
+                        return; // At this point, there is nothing more to run on the connection.
+                    },
+                    _ => warn!("unexpected event {:?}", evt.get_event_code()),
+                }
+            },
+            Some(req) = req_rx.recv() => {
+                match req {
+                    ConnectionRequest::Disconnect{reason, fut} => {
+                        hci.send(DisconnectBuilder { connection_handle: handle, reason }).await;
+                        fut.send(()).unwrap();
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
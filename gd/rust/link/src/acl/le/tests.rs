@@ -0,0 +1,140 @@
+use super::*;
+use crate::acl::test_util::{self, FakeController};
+use bt_packets::hci::{
+    ClockAccuracy, LeConnectionCompleteBuilder, LeCreateConnectionCancelCompleteBuilder,
+};
+
+const ADDR_A: Address = Address { bytes: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06] };
+
+async fn start_manager(rt: Arc<Runtime>) -> (LeAclManager, FakeController) {
+    let (stack, controller) = test_util::start(rt.clone()).await;
+    let acl = provide_le_acl_manager(stack.hci, stack.events, stack.dispatch, rt).await;
+    (acl, controller)
+}
+
+fn connection_complete(status: ErrorCode, peer_address: Address) -> LeConnectionCompleteBuilder {
+    LeConnectionCompleteBuilder {
+        status,
+        connection_handle: 0x40,
+        role: Role::Central,
+        peer_address_type: AddressType::PublicDeviceAddress,
+        peer_address,
+        conn_interval: 0x0028,
+        conn_latency: 0,
+        supervision_timeout: 0x01f4,
+        central_clock_accuracy: ClockAccuracy::Ppm500,
+    }
+}
+
+async fn next_event(acl: &mut LeAclManager) -> Event {
+    acl.evt_rx.lock().await.recv().await.unwrap()
+}
+
+#[test]
+fn links_the_dispatch_cannot_carry_are_disconnected_and_failed() {
+    test_util::run(|rt| async move {
+        let (mut stack, mut controller) = test_util::start(rt.clone()).await;
+        stack.dispatch = core::start_dataless_acl_dispatch(&rt);
+        let mut acl = provide_le_acl_manager(stack.hci, stack.events, stack.dispatch, rt).await;
+
+        acl.connect(ADDR_A, AddressType::PublicDeviceAddress).await;
+        controller
+            .expect_status(
+                build_create_connection(
+                    ADDR_A,
+                    AddressType::PublicDeviceAddress,
+                    ConnectionParameters::default(),
+                ),
+                ErrorCode::Success,
+            )
+            .await;
+        controller.send_event(connection_complete(ErrorCode::Success, ADDR_A)).await;
+        controller
+            .expect_status(
+                DisconnectBuilder {
+                    connection_handle: 0x40,
+                    reason: DisconnectReason::RemoteUserTerminatedConnection,
+                },
+                ErrorCode::Success,
+            )
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason } => {
+                assert_eq!(addr, ADDR_A);
+                assert_eq!(reason, ErrorCode::ConnectionFailedEstablishment);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn cancel_connect_reports_local_termination() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt.clone()).await;
+
+        acl.connect(ADDR_A, AddressType::PublicDeviceAddress).await;
+        controller
+            .expect_status(
+                build_create_connection(
+                    ADDR_A,
+                    AddressType::PublicDeviceAddress,
+                    ConnectionParameters::default(),
+                ),
+                ErrorCode::Success,
+            )
+            .await;
+
+        let mut canceller = acl.clone();
+        let cancel = rt.spawn(async move { canceller.cancel_connect(ADDR_A).await });
+        controller
+            .expect_complete(
+                LeCreateConnectionCancelBuilder {},
+                LeCreateConnectionCancelCompleteBuilder {
+                    num_hci_command_packets: 1,
+                    status: ErrorCode::Success,
+                },
+            )
+            .await;
+        cancel.await.unwrap();
+
+        // The peer address is not meaningful in the cancellation confirmation
+        controller
+            .send_event(connection_complete(
+                ErrorCode::UnknownConnection,
+                Address { bytes: [0; 6] },
+            ))
+            .await;
+        match next_event(&mut acl).await {
+            Event::ConnectFail { addr, reason } => {
+                assert_eq!(addr, ADDR_A);
+                assert_eq!(reason, ErrorCode::ConnectionTerminatedByLocalHost);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+    });
+}
+
+#[test]
+fn connects_use_default_conn_params() {
+    test_util::run(|rt| async move {
+        let (mut acl, mut controller) = start_manager(rt).await;
+
+        let params = ConnectionParameters {
+            interval_min: 0x0006,
+            interval_max: 0x000c,
+            latency: 4,
+            supervision_timeout: 0x0064,
+        };
+        acl.set_default_conn_params(params);
+        assert_eq!(acl.default_conn_params(), params);
+
+        acl.connect(ADDR_A, AddressType::PublicDeviceAddress).await;
+        controller
+            .expect_status(
+                build_create_connection(ADDR_A, AddressType::PublicDeviceAddress, params),
+                ErrorCode::Success,
+            )
+            .await;
+    });
+}
@@ -4,6 +4,10 @@
 pub mod classic;
 mod core;
 mod fragment;
+/// Exposes LE ACL functionality
+pub mod le;
+#[cfg(test)]
+mod test_util;
 
 use gddi::module;
 
@@ -12,5 +16,6 @@ module! {
     submodules {
         classic::classic_acl_module,
         core::core_module,
+        le::le_acl_module,
     },
 }
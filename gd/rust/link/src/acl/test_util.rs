@@ -0,0 +1,171 @@
+//! Fake controller plumbing for exercising the ACL managers in tests
+
+use crate::acl::core::{self, AclDispatch, BufferSizes, HostCompletions, HostFlowControl};
+use bt_hal::{AclHal, ControlHal};
+use bt_hci::{CommandSender, EventRegistry};
+use bt_packets::hci::{
+    AclPacket, CommandPacket, CommandStatusBuilder, ErrorCode, EventPacket, Packet, ResetBuilder,
+    ResetCompleteBuilder,
+};
+use gddi::RegistryBuilder;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs an async test body on a fresh runtime
+pub fn run<F, Fut>(test: F)
+where
+    F: FnOnce(Arc<Runtime>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let rt = Arc::new(Runtime::new().unwrap());
+    rt.block_on(test(rt.clone()));
+}
+
+/// Host side dependencies of the ACL managers, wired up to a fake controller
+pub struct Stack {
+    pub hci: CommandSender,
+    pub events: EventRegistry,
+    pub dispatch: AclDispatch,
+}
+
+/// Starts the HCI layer and ACL dispatch on top of a fake controller
+pub async fn start(rt: Arc<Runtime>) -> (Stack, FakeController) {
+    start_with(rt, None).await
+}
+
+/// Like `start`, with the controller under host flow control as the dispatch would have
+/// enabled it
+pub async fn start_with_host_flow_control(
+    rt: Arc<Runtime>,
+    flow: HostFlowControl,
+) -> (Stack, FakeController) {
+    start_with(rt, Some(flow)).await
+}
+
+async fn start_with(rt: Arc<Runtime>, flow: Option<HostFlowControl>) -> (Stack, FakeController) {
+    let (cmd_tx, cmd_rx) = channel(10);
+    let (evt_tx, evt_rx) = channel(10);
+    let (acl_out_tx, acl_out_rx) = channel(10);
+    let (acl_in_tx, acl_in_rx) = channel(10);
+
+    let registry = Arc::new(RegistryBuilder::new().register_module(bt_hci::hci_module).build());
+    registry.inject(rt.clone()).await;
+    registry.inject(ControlHal { tx: cmd_tx, rx: Arc::new(Mutex::new(evt_rx)) }).await;
+
+    let mut controller = FakeController { cmd_rx, evt_tx, acl_tx: acl_in_tx, acl_rx: acl_out_rx };
+
+    let hci = {
+        let registry = registry.clone();
+        rt.spawn(async move { registry.get::<CommandSender>().await })
+    };
+    controller
+        .expect_complete(
+            ResetBuilder {},
+            ResetCompleteBuilder { num_hci_command_packets: 1, status: ErrorCode::Success },
+        )
+        .await;
+    let hci = hci.await.unwrap();
+    let events = registry.get::<EventRegistry>().await;
+
+    let dispatch = core::start_acl_dispatch(
+        AclHal { tx: acl_out_tx, rx: Arc::new(Mutex::new(acl_in_rx)) },
+        BufferSizes {
+            acl_buffer_length: 1021,
+            acl_buffers: 8,
+            le_buffer_length: 251,
+            le_buffers: 8,
+        },
+        events.clone(),
+        flow.map(|flow| HostCompletions::new(hci.clone(), flow)),
+        &rt,
+    );
+
+    (Stack { hci, events, dispatch }, controller)
+}
+
+/// The controller end of the HAL, driven by the test
+pub struct FakeController {
+    cmd_rx: Receiver<CommandPacket>,
+    evt_tx: Sender<EventPacket>,
+    acl_tx: Sender<AclPacket>,
+    acl_rx: Receiver<AclPacket>,
+}
+
+impl FakeController {
+    /// Wait for the host to send a command, failing the test if it doesn't
+    pub async fn next_command(&mut self) -> CommandPacket {
+        timeout(COMMAND_TIMEOUT, self.cmd_rx.recv())
+            .await
+            .expect("timed out waiting for a command")
+            .expect("command channel closed")
+    }
+
+    /// Fail the test if the host sends a command within `period`
+    pub async fn expect_no_command(&mut self, period: Duration) {
+        if let Ok(command) = timeout(period, self.cmd_rx.recv()).await {
+            panic!("unexpected command {:?}", command);
+        }
+    }
+
+    /// Expect the next command to match `expected`, and return it unanswered
+    pub async fn expect<T: Into<CommandPacket>>(&mut self, expected: T) -> CommandPacket {
+        let expected = expected.into();
+        let actual = self.next_command().await;
+        assert_eq!(
+            actual.clone().to_vec(),
+            expected.clone().to_vec(),
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+        actual
+    }
+
+    /// Expect the next command to match `expected`, and acknowledge it with a command status
+    pub async fn expect_status<T: Into<CommandPacket>>(&mut self, expected: T, status: ErrorCode) {
+        let command = self.expect(expected).await;
+        self.send_event(CommandStatusBuilder {
+            status,
+            num_hci_command_packets: 1,
+            command_op_code: command.get_op_code(),
+            payload: None,
+        })
+        .await;
+    }
+
+    /// Expect the next command to match `expected`, and answer it with `response`
+    pub async fn expect_complete<T: Into<CommandPacket>, R: Into<EventPacket>>(
+        &mut self,
+        expected: T,
+        response: R,
+    ) {
+        self.expect(expected).await;
+        self.send_event(response).await;
+    }
+
+    /// Deliver an event to the host, round tripped through its wire format like a real HAL
+    pub async fn send_event<T: Into<EventPacket>>(&mut self, event: T) {
+        let event = EventPacket::parse(&event.into().to_vec()).unwrap();
+        self.evt_tx.send(event).await.unwrap();
+    }
+
+    /// Wait for the host to send an ACL packet, failing the test if it doesn't
+    pub async fn recv_acl(&mut self) -> AclPacket {
+        timeout(COMMAND_TIMEOUT, self.acl_rx.recv())
+            .await
+            .expect("timed out waiting for ACL data")
+            .expect("ACL channel closed")
+    }
+
+    /// Deliver an ACL packet to the host
+    pub async fn send_acl(&mut self, packet: AclPacket) {
+        self.acl_tx.send(packet).await.unwrap();
+    }
+}